@@ -101,6 +101,7 @@
 pub const TRUNCATE_EXISTING: DWORD = 5;
 
 pub const FILE_LIST_DIRECTORY: DWORD = 0x1;
+pub const FILE_READ_DATA: DWORD = 0x00000001;
 pub const FILE_WRITE_DATA: DWORD = 0x00000002;
 pub const FILE_APPEND_DATA: DWORD = 0x00000004;
 pub const FILE_WRITE_EA: DWORD = 0x00000010;
@@ -108,6 +109,7 @@
 pub const DELETE: DWORD = 0x10000;
 pub const READ_CONTROL: DWORD = 0x00020000;
 pub const SYNCHRONIZE: DWORD = 0x00100000;
+pub const EVENT_MODIFY_STATE: DWORD = 0x0002;
 pub const GENERIC_READ: DWORD = 0x80000000;
 pub const GENERIC_WRITE: DWORD = 0x40000000;
 pub const STANDARD_RIGHTS_WRITE: DWORD = READ_CONTROL;
@@ -186,8 +188,17 @@ fn clone(&self) -> Self {
 pub const DLL_THREAD_DETACH: DWORD = 3;
 pub const DLL_PROCESS_DETACH: DWORD = 0;
 
+pub const THREAD_PRIORITY_IDLE: c_int = -15;
+pub const THREAD_PRIORITY_LOWEST: c_int = -2;
+pub const THREAD_PRIORITY_BELOW_NORMAL: c_int = -1;
+pub const THREAD_PRIORITY_NORMAL: c_int = 0;
+pub const THREAD_PRIORITY_ABOVE_NORMAL: c_int = 1;
+pub const THREAD_PRIORITY_HIGHEST: c_int = 2;
+pub const THREAD_PRIORITY_TIME_CRITICAL: c_int = 15;
+
 pub const INFINITE: DWORD = !0;
 
+pub const DUPLICATE_CLOSE_SOURCE: DWORD = 0x00000001;
 pub const DUPLICATE_SAME_ACCESS: DWORD = 0x00000002;
 
 pub const CONDITION_VARIABLE_INIT: CONDITION_VARIABLE = CONDITION_VARIABLE { ptr: ptr::null_mut() };
@@ -256,15 +267,23 @@ pub struct ipv6_mreq {
 pub const WAIT_OBJECT_0: DWORD = 0x00000000;
 pub const WAIT_TIMEOUT: DWORD = 258;
 pub const WAIT_FAILED: DWORD = 0xFFFFFFFF;
+pub const MAXIMUM_WAIT_OBJECTS: DWORD = 64;
 
 pub const PIPE_ACCESS_INBOUND: DWORD = 0x00000001;
 pub const PIPE_ACCESS_OUTBOUND: DWORD = 0x00000002;
+pub const PIPE_ACCESS_DUPLEX: DWORD = 0x00000003;
 pub const FILE_FLAG_FIRST_PIPE_INSTANCE: DWORD = 0x00080000;
 pub const FILE_FLAG_OVERLAPPED: DWORD = 0x40000000;
+pub const FILE_FLAG_WRITE_THROUGH: DWORD = 0x80000000;
 pub const PIPE_WAIT: DWORD = 0x00000000;
+pub const PIPE_NOWAIT: DWORD = 0x00000001;
 pub const PIPE_TYPE_BYTE: DWORD = 0x00000000;
 pub const PIPE_REJECT_REMOTE_CLIENTS: DWORD = 0x00000008;
 pub const PIPE_READMODE_BYTE: DWORD = 0x00000000;
+pub const PIPE_TYPE_MESSAGE: DWORD = 0x00000004;
+pub const PIPE_READMODE_MESSAGE: DWORD = 0x00000002;
+pub const PIPE_UNLIMITED_INSTANCES: DWORD = 255;
+pub const NMPWAIT_WAIT_FOREVER: DWORD = 0xffffffff;
 
 pub const FD_SETSIZE: usize = 64;
 
@@ -335,6 +354,14 @@ pub struct IO_STATUS_BLOCK {
     pub Information: usize,
 }
 
+/// `FileAccessInformation` in `FILE_INFORMATION_CLASS`.
+pub const FileAccessInformation: ULONG = 8;
+
+#[repr(C)]
+pub struct FILE_ACCESS_INFORMATION {
+    pub AccessFlags: ACCESS_MASK,
+}
+
 pub type LPOVERLAPPED_COMPLETION_ROUTINE = unsafe extern "system" fn(
     dwErrorCode: DWORD,
     dwNumberOfBytesTransfered: DWORD,
@@ -569,6 +596,43 @@ pub struct SECURITY_ATTRIBUTES {
     pub bInheritHandle: BOOL,
 }
 
+pub const SECURITY_DESCRIPTOR_REVISION: DWORD = 1;
+pub const ACL_REVISION: DWORD = 2;
+
+#[repr(C)]
+pub struct SECURITY_DESCRIPTOR {
+    pub Revision: BYTE,
+    pub Sbz1: BYTE,
+    pub Control: WORD,
+    pub Owner: *mut SID,
+    pub Group: *mut SID,
+    pub Sacl: *mut ACL,
+    pub Dacl: *mut ACL,
+}
+
+#[repr(C)]
+pub struct ACL {
+    pub AclRevision: BYTE,
+    pub Sbz1: BYTE,
+    pub AclSize: WORD,
+    pub AceCount: WORD,
+    pub Sbz2: WORD,
+}
+
+#[repr(C)]
+pub struct SID_IDENTIFIER_AUTHORITY {
+    pub Value: [BYTE; 6],
+}
+
+/// The start of a SID, which really has `SubAuthorityCount` sub-authorities.
+#[repr(C)]
+pub struct SID {
+    pub Revision: BYTE,
+    pub SubAuthorityCount: BYTE,
+    pub IdentifierAuthority: SID_IDENTIFIER_AUTHORITY,
+    pub SubAuthority: [DWORD; 1],
+}
+
 #[repr(C)]
 pub struct PROCESS_INFORMATION {
     pub hProcess: HANDLE,
@@ -636,6 +700,16 @@ pub struct OVERLAPPED {
     pub hEvent: HANDLE,
 }
 
+/// One page of a buffer passed to `WriteFileGather`.
+///
+/// This is always 64 bits wide, so on 32-bit targets the pointer should be
+/// stored through `Alignment` to make sure the upper half is zeroed.
+#[repr(C)]
+pub union FILE_SEGMENT_ELEMENT {
+    pub Buffer: *mut c_void,
+    pub Alignment: u64,
+}
+
 #[repr(C)]
 #[allow(dead_code)] // we only use some variants
 pub enum ADDRESS_MODE {
@@ -783,6 +857,7 @@ pub struct BY_HANDLE_FILE_INFORMATION {
     pub type LPCVOID = *const c_void;
 
     pub const HANDLE_FLAG_INHERIT: DWORD = 0x00000001;
+    pub const HANDLE_FLAG_PROTECT_FROM_CLOSE: DWORD = 0x00000002;
 
     pub const TOKEN_READ: DWORD = 0x20008;
 
@@ -794,6 +869,9 @@ pub fn OpenProcessToken(
             DesiredAccess: DWORD,
             TokenHandle: *mut HANDLE,
         ) -> BOOL;
+        // Forbidden when targeting UWP
+        pub fn ImpersonateNamedPipeClient(hNamedPipe: HANDLE) -> BOOL;
+        pub fn RevertToSelf() -> BOOL;
     }
 
     #[link(name = "userenv")]
@@ -832,6 +910,7 @@ pub fn GetFileInformationByHandle(
             lpFileInformation: LPBY_HANDLE_FILE_INFORMATION,
         ) -> BOOL;
         pub fn SetHandleInformation(hObject: HANDLE, dwMask: DWORD, dwFlags: DWORD) -> BOOL;
+        pub fn GetHandleInformation(hObject: HANDLE, lpdwFlags: LPDWORD) -> BOOL;
         pub fn AddVectoredExceptionHandler(
             FirstHandler: ULONG,
             VectoredHandler: PVECTORED_EXCEPTION_HANDLER,
@@ -866,6 +945,7 @@ pub struct FILE_STANDARD_INFO {
 #[link(name = "kernel32")]
 extern "system" {
     pub fn GetCurrentProcessId() -> DWORD;
+    pub fn ProcessIdToSessionId(dwProcessId: DWORD, pSessionId: *mut DWORD) -> BOOL;
 
     pub fn GetSystemDirectoryW(lpBuffer: LPWSTR, uSize: UINT) -> UINT;
     pub fn RemoveDirectoryW(lpPathName: LPCWSTR) -> BOOL;
@@ -875,6 +955,8 @@ pub struct FILE_STANDARD_INFO {
     pub fn GetTempPathW(nBufferLength: DWORD, lpBuffer: LPCWSTR) -> DWORD;
     pub fn GetCurrentProcess() -> HANDLE;
     pub fn GetCurrentThread() -> HANDLE;
+    pub fn SetThreadPriority(hThread: HANDLE, nPriority: c_int) -> BOOL;
+    pub fn GetThreadPriority(hThread: HANDLE) -> c_int;
     pub fn GetStdHandle(which: DWORD) -> HANDLE;
     pub fn ExitProcess(uExitCode: c_uint) -> !;
     pub fn DeviceIoControl(
@@ -972,6 +1054,13 @@ pub fn ReadFileEx(
         lpOverlapped: LPOVERLAPPED,
         lpCompletionRoutine: LPOVERLAPPED_COMPLETION_ROUTINE,
     ) -> BOOL;
+    pub fn WriteFile(
+        hFile: BorrowedHandle<'_>,
+        lpBuffer: LPVOID,
+        nNumberOfBytesToWrite: DWORD,
+        lpNumberOfBytesWritten: LPDWORD,
+        lpOverlapped: LPOVERLAPPED,
+    ) -> BOOL;
     pub fn WriteFileEx(
         hFile: BorrowedHandle<'_>,
         lpBuffer: LPVOID,
@@ -979,6 +1068,13 @@ pub fn WriteFileEx(
         lpOverlapped: LPOVERLAPPED,
         lpCompletionRoutine: LPOVERLAPPED_COMPLETION_ROUTINE,
     ) -> BOOL;
+    pub fn WriteFileGather(
+        hFile: HANDLE,
+        aSegmentArray: *mut FILE_SEGMENT_ELEMENT,
+        nNumberOfBytesToWrite: DWORD,
+        lpReserved: LPDWORD,
+        lpOverlapped: LPOVERLAPPED,
+    ) -> BOOL;
     pub fn CloseHandle(hObject: HANDLE) -> BOOL;
     pub fn MoveFileExW(lpExistingFileName: LPCWSTR, lpNewFileName: LPCWSTR, dwFlags: DWORD)
     -> BOOL;
@@ -998,6 +1094,12 @@ pub fn CreateFileW(
         dwFlagsAndAttributes: DWORD,
         hTemplateFile: HANDLE,
     ) -> HandleOrInvalid;
+    pub fn ReOpenFile(
+        hOriginalFile: HANDLE,
+        dwDesiredAccess: DWORD,
+        dwShareMode: DWORD,
+        dwFlagsAndAttributes: DWORD,
+    ) -> HANDLE;
 
     pub fn FindFirstFileW(fileName: LPCWSTR, findFileData: LPWIN32_FIND_DATAW) -> HANDLE;
     pub fn FindNextFileW(findFile: HANDLE, findFileData: LPWIN32_FIND_DATAW) -> BOOL;
@@ -1016,6 +1118,8 @@ pub fn CreateEventW(
         bInitialState: BOOL,
         lpName: LPCWSTR,
     ) -> HANDLE;
+    pub fn OpenEventW(dwDesiredAccess: DWORD, bInheritHandle: BOOL, lpName: LPCWSTR) -> HANDLE;
+    pub fn SetEvent(hEvent: HANDLE) -> BOOL;
     pub fn WaitForMultipleObjects(
         nCount: DWORD,
         lpHandles: *const HANDLE,
@@ -1038,6 +1142,59 @@ pub fn CreateNamedPipeW(
         nDefaultTimeOut: DWORD,
         lpSecurityAttributes: LPSECURITY_ATTRIBUTES,
     ) -> HANDLE;
+    pub fn SetNamedPipeHandleState(
+        hNamedPipe: HANDLE,
+        lpMode: LPDWORD,
+        lpMaxCollectionCount: LPDWORD,
+        lpCollectDataTimeout: LPDWORD,
+    ) -> BOOL;
+    pub fn PeekNamedPipe(
+        hNamedPipe: HANDLE,
+        lpBuffer: LPVOID,
+        nBufferSize: DWORD,
+        lpBytesRead: LPDWORD,
+        lpTotalBytesAvail: LPDWORD,
+        lpBytesLeftThisMessage: LPDWORD,
+    ) -> BOOL;
+    pub fn GetNamedPipeInfo(
+        hNamedPipe: HANDLE,
+        lpFlags: LPDWORD,
+        lpOutBufferSize: LPDWORD,
+        lpInBufferSize: LPDWORD,
+        lpMaxInstances: LPDWORD,
+    ) -> BOOL;
+    pub fn GetNamedPipeHandleStateW(
+        hNamedPipe: HANDLE,
+        lpState: LPDWORD,
+        lpCurInstances: LPDWORD,
+        lpMaxCollectionCount: LPDWORD,
+        lpCollectDataTimeout: LPDWORD,
+        lpUserName: LPWSTR,
+        nMaxUserNameSize: DWORD,
+    ) -> BOOL;
+    pub fn WaitNamedPipeW(lpNamedPipeName: LPCWSTR, nTimeOut: DWORD) -> BOOL;
+    pub fn CallNamedPipeW(
+        lpNamedPipeName: LPCWSTR,
+        lpInBuffer: LPVOID,
+        nInBufferSize: DWORD,
+        lpOutBuffer: LPVOID,
+        nOutBufferSize: DWORD,
+        lpBytesRead: LPDWORD,
+        nTimeOut: DWORD,
+    ) -> BOOL;
+    pub fn TransactNamedPipe(
+        hNamedPipe: HANDLE,
+        lpInBuffer: LPVOID,
+        nInBufferSize: DWORD,
+        lpOutBuffer: LPVOID,
+        nOutBufferSize: DWORD,
+        lpBytesRead: LPDWORD,
+        lpOverlapped: LPOVERLAPPED,
+    ) -> BOOL;
+    pub fn ConnectNamedPipe(hNamedPipe: HANDLE, lpOverlapped: LPOVERLAPPED) -> BOOL;
+    pub fn DisconnectNamedPipe(hNamedPipe: HANDLE) -> BOOL;
+    pub fn GetNamedPipeClientProcessId(Pipe: HANDLE, ClientProcessId: *mut ULONG) -> BOOL;
+    pub fn GetNamedPipeServerProcessId(Pipe: HANDLE, ServerProcessId: *mut ULONG) -> BOOL;
     pub fn CancelIo(handle: HANDLE) -> BOOL;
     pub fn GetOverlappedResult(
         hFile: HANDLE,
@@ -1045,6 +1202,19 @@ pub fn GetOverlappedResult(
         lpNumberOfBytesTransferred: LPDWORD,
         bWait: BOOL,
     ) -> BOOL;
+    pub fn CreateIoCompletionPort(
+        FileHandle: HANDLE,
+        ExistingCompletionPort: HANDLE,
+        CompletionKey: ULONG_PTR,
+        NumberOfConcurrentThreads: DWORD,
+    ) -> HANDLE;
+    pub fn GetQueuedCompletionStatus(
+        CompletionPort: HANDLE,
+        lpNumberOfBytesTransferred: LPDWORD,
+        lpCompletionKey: *mut ULONG_PTR,
+        lpOverlapped: *mut LPOVERLAPPED,
+        dwMilliseconds: DWORD,
+    ) -> BOOL;
     pub fn CreateSymbolicLinkW(
         lpSymlinkFileName: LPCWSTR,
         lpTargetFileName: LPCWSTR,
@@ -1206,6 +1376,35 @@ pub fn BCryptGenRandom(
     ) -> NTSTATUS;
 }
 
+#[link(name = "advapi32")]
+extern "system" {
+    pub fn InitializeSecurityDescriptor(
+        pSecurityDescriptor: *mut SECURITY_DESCRIPTOR,
+        dwRevision: DWORD,
+    ) -> BOOL;
+    pub fn SetSecurityDescriptorDacl(
+        pSecurityDescriptor: *mut SECURITY_DESCRIPTOR,
+        bDaclPresent: BOOL,
+        pDacl: *mut ACL,
+        bDaclDefaulted: BOOL,
+    ) -> BOOL;
+    pub fn InitializeAcl(pAcl: *mut ACL, nAclLength: DWORD, dwAclRevision: DWORD) -> BOOL;
+    pub fn AddAccessAllowedAce(
+        pAcl: *mut ACL,
+        dwAceRevision: DWORD,
+        AccessMask: DWORD,
+        pSid: *mut SID,
+    ) -> BOOL;
+    pub fn AddAccessDeniedAce(
+        pAcl: *mut ACL,
+        dwAceRevision: DWORD,
+        AccessMask: DWORD,
+        pSid: *mut SID,
+    ) -> BOOL;
+    pub fn IsValidSid(pSid: *mut SID) -> BOOL;
+    pub fn GetLengthSid(pSid: *mut SID) -> DWORD;
+}
+
 // Functions that aren't available on every version of Windows that we support,
 // but we still use them and just provide some form of a fallback implementation.
 compat_fn! {
@@ -1230,6 +1429,23 @@ pub fn GetSystemTimePreciseAsFileTime(lpSystemTimeAsFileTime: LPFILETIME)
     pub fn GetTempPath2W(nBufferLength: DWORD, lpBuffer: LPCWSTR) -> DWORD {
         GetTempPathW(nBufferLength, lpBuffer)
     }
+
+    // >= Vista / Server 2008
+    // https://docs.microsoft.com/en-us/windows/win32/fileio/cancelioex-func
+    pub fn CancelIoEx(hFile: HANDLE, lpOverlapped: LPOVERLAPPED) -> BOOL {
+        // This cancels all of the calling thread's I/O on the handle, but
+        // it's the best we can do.
+        CancelIo(hFile)
+    }
+
+    // >= Vista / Server 2008
+    // https://docs.microsoft.com/en-us/windows/win32/api/winbase/nf-winbase-getnamedpipeserversessionid
+    pub fn GetNamedPipeServerSessionId(Pipe: HANDLE, ServerSessionId: *mut ULONG) -> BOOL {
+        SetLastError(ERROR_CALL_NOT_IMPLEMENTED as DWORD); FALSE
+    }
+    pub fn GetNamedPipeClientSessionId(Pipe: HANDLE, ClientSessionId: *mut ULONG) -> BOOL {
+        SetLastError(ERROR_CALL_NOT_IMPLEMENTED as DWORD); FALSE
+    }
 }
 
 compat_fn! {
@@ -1251,6 +1467,18 @@ pub fn WakeByAddressSingle(Address: LPVOID) -> () {
     }
 }
 
+compat_fn! {
+    "kernelbase":
+
+    // >= Win10 1607 / Server 2016
+    // https://docs.microsoft.com/en-us/windows/win32/api/handleapi/nf-handleapi-compareobjecthandles
+    pub fn CompareObjectHandles(hFirstObjectHandle: HANDLE, hSecondObjectHandle: HANDLE) -> BOOL {
+        // Equal handles are certainly the same object, but different ones
+        // could be too and there's no telling.
+        (hFirstObjectHandle == hSecondObjectHandle) as BOOL
+    }
+}
+
 compat_fn! {
     "ntdll":
     pub fn NtCreateFile(
@@ -1294,6 +1522,15 @@ pub fn NtWriteFile(
     ) -> NTSTATUS {
         panic!("`NtWriteFile` not available");
     }
+    pub fn NtQueryInformationFile(
+        FileHandle: HANDLE,
+        IoStatusBlock: &mut IO_STATUS_BLOCK,
+        FileInformation: *mut c_void,
+        Length: ULONG,
+        FileInformationClass: ULONG
+    ) -> NTSTATUS {
+        panic!("`NtQueryInformationFile` not available");
+    }
     pub fn RtlNtStatusToDosError(
         Status: NTSTATUS
     ) -> ULONG {
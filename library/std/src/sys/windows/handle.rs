@@ -1,6 +1,7 @@
 #![unstable(issue = "none", feature = "windows_handle")]
 
 use crate::cmp;
+use crate::ffi::OsStr;
 use crate::io::{self, ErrorKind, IoSlice, IoSliceMut, Read, ReadBuf};
 use crate::mem;
 use crate::os::windows::io::{
@@ -8,8 +9,9 @@
 };
 use crate::ptr;
 use crate::sys::c;
-use crate::sys::cvt;
+use crate::sys::{cvt, dur2timeout, to_u16s};
 use crate::sys_common::{AsInner, FromInner, IntoInner};
+use crate::time::Duration;
 
 /// An owned container for `HANDLE` object, closing them on Drop.
 ///
@@ -28,6 +30,104 @@ pub fn new_event(manual: bool, init: bool) -> io::Result<Handle> {
             }
         }
     }
+
+    /// Creates an event called `name` that other processes can open with
+    /// `open_named_event`, or opens it if it already exists.
+    #[allow(dead_code)]
+    pub fn new_named_event(name: &OsStr, manual: bool, init: bool) -> io::Result<Handle> {
+        let name = to_u16s(name)?;
+        unsafe {
+            let event =
+                c::CreateEventW(ptr::null_mut(), manual as c::BOOL, init as c::BOOL, name.as_ptr());
+            if event.is_null() {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(Handle::from_raw_handle(event))
+            }
+        }
+    }
+
+    /// Opens the existing event called `name` with the given access rights,
+    /// such as `SYNCHRONIZE` to wait for it or `EVENT_MODIFY_STATE` to set it.
+    #[allow(dead_code)]
+    pub fn open_named_event(name: &OsStr, access: c::DWORD, inherit: bool) -> io::Result<Handle> {
+        let name = to_u16s(name)?;
+        unsafe {
+            let event = c::OpenEventW(access, inherit as c::BOOL, name.as_ptr());
+            if event.is_null() {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(Handle::from_raw_handle(event))
+            }
+        }
+    }
+}
+
+/// Information about a pipe returned by `GetNamedPipeInfo`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NamedPipeInfo {
+    /// The type of the pipe and whether this is the server or client end, as
+    /// `PIPE_SERVER_END` and `PIPE_TYPE_MESSAGE` flags.
+    pub flags: u32,
+    pub out_buffer_size: u32,
+    pub in_buffer_size: u32,
+    /// The maximum number of instances of the pipe that can be created, or
+    /// `PIPE_UNLIMITED_INSTANCES`.
+    pub max_instances: u32,
+}
+
+/// How data is read from a pipe.
+#[allow(dead_code)] // we only use some variants
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PipeReadMode {
+    /// Data is read as a stream of bytes.
+    Byte,
+    /// Data is read one message at a time.
+    Message,
+}
+
+/// Whether operations on a pipe block.
+#[allow(dead_code)] // we only use some variants
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PipeWaitMode {
+    Wait,
+    NoWait,
+}
+
+/// The state of a pipe handle returned by `GetNamedPipeHandleStateW`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PipeHandleState {
+    pub read_mode: PipeReadMode,
+    pub wait_mode: PipeWaitMode,
+    /// How many bytes are collected before being sent to the server, and how
+    /// many milliseconds may pass before they are sent anyway.
+    ///
+    /// These only apply to the client end of a pipe whose server is on
+    /// another machine, and are zero otherwise.
+    pub max_collection_count: u32,
+    pub collect_data_timeout_ms: u32,
+}
+
+/// Reverts the current thread to its own security context when dropped.
+///
+/// Returned by `Handle::impersonate_named_pipe_client`.
+#[cfg(not(target_vendor = "uwp"))]
+#[must_use = "the impersonation ends as soon as the guard is dropped"]
+pub struct ImpersonationGuard(());
+
+// Impersonation only applies to the thread that started it.
+#[cfg(not(target_vendor = "uwp"))]
+impl !Send for ImpersonationGuard {}
+
+#[cfg(not(target_vendor = "uwp"))]
+impl Drop for ImpersonationGuard {
+    fn drop(&mut self) {
+        // If this fails the thread would keep running as the client, which
+        // isn't safe to continue from.
+        if unsafe { c::RevertToSelf() } == 0 {
+            rtabort!("failed to revert impersonation: {}", io::Error::last_os_error());
+        }
+    }
 }
 
 impl AsInner<OwnedHandle> for Handle {
@@ -162,6 +262,162 @@ pub unsafe fn read_overlapped(
         }
     }
 
+    /// Starts a read into `buf` with `ReadFileEx`, which calls `callback`
+    /// rather than signaling an event once the read is done.
+    ///
+    /// The callback runs on this thread, and only once it's in an alertable
+    /// state (for example in `SleepEx` with `bAlertable` set), even if the read
+    /// finishes straight away. Until then `buf` and `overlapped` must stay
+    /// valid. `hEvent` isn't used by `ReadFileEx`, so it can carry a pointer
+    /// for the callback.
+    ///
+    /// Returns `None` once the read has started, or `Some(0)` if the pipe
+    /// was already at EOF, in which case `callback` is never called.
+    pub unsafe fn read_overlapped_ex(
+        &self,
+        buf: &mut [u8],
+        overlapped: &mut c::OVERLAPPED,
+        callback: c::LPOVERLAPPED_COMPLETION_ROUTINE,
+    ) -> io::Result<Option<usize>> {
+        let len = cmp::min(buf.len(), <c::DWORD>::MAX as usize) as c::DWORD;
+        let res = cvt(c::ReadFileEx(
+            self.as_handle(),
+            buf.as_mut_ptr() as c::LPVOID,
+            len,
+            overlapped,
+            callback,
+        ));
+        match res {
+            Ok(_) => Ok(None),
+            Err(ref e) if e.raw_os_error() == Some(c::ERROR_BROKEN_PIPE as i32) => Ok(Some(0)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads into `buf` with an overlapped `ReadFile`, waiting at most
+    /// `timeout` for the read to complete.
+    ///
+    /// Returns `None` if the read timed out, in which case it has been
+    /// cancelled. If it was cancelled after reading some data, that is
+    /// returned instead. This handle must have been opened for overlapped I/O, and
+    /// `overlapped.hEvent` is replaced with an event that's closed again on
+    /// return. (`ReadFileEx` can't be used for this as it doesn't signal the
+    /// event when the read completes.)
+    #[allow(dead_code)]
+    pub fn read_overlapped_timeout(
+        &self,
+        buf: &mut [u8],
+        overlapped: &mut c::OVERLAPPED,
+        timeout: Duration,
+    ) -> io::Result<Option<usize>> {
+        let event = Handle::new_event(true, false)?;
+        overlapped.hEvent = event.as_raw_handle();
+        if let Some(amt) = unsafe { self.read_overlapped(buf, overlapped)? } {
+            return Ok(Some(amt));
+        }
+        self.wait_overlapped_timeout(&event, overlapped, timeout)
+    }
+
+    /// Starts an overlapped write of `buf`, returning `None` if the write is
+    /// still in progress.
+    ///
+    /// Note that a pipe closed by the reader fails with `ERROR_NO_DATA`
+    /// (`BrokenPipe`) rather than being treated as EOF.
+    pub unsafe fn write_overlapped(
+        &self,
+        buf: &[u8],
+        overlapped: *mut c::OVERLAPPED,
+    ) -> io::Result<Option<usize>> {
+        let len = cmp::min(buf.len(), <c::DWORD>::MAX as usize) as c::DWORD;
+        let mut amt = 0;
+        let res = cvt(c::WriteFile(
+            self.as_handle(),
+            buf.as_ptr() as c::LPVOID,
+            len,
+            &mut amt,
+            overlapped,
+        ));
+        match res {
+            Ok(_) => Ok(Some(amt as usize)),
+            Err(ref e) if e.raw_os_error() == Some(c::ERROR_IO_PENDING as i32) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like `read_overlapped_ex`, but starts a write of `buf` with
+    /// `WriteFileEx`.
+    ///
+    /// Once this returns `Ok` the callback is always called, on this thread
+    /// once it's alertable, and `buf` and `overlapped` must stay valid until
+    /// then.
+    #[allow(dead_code)]
+    pub unsafe fn write_overlapped_ex(
+        &self,
+        buf: &[u8],
+        overlapped: &mut c::OVERLAPPED,
+        callback: c::LPOVERLAPPED_COMPLETION_ROUTINE,
+    ) -> io::Result<()> {
+        let len = cmp::min(buf.len(), <c::DWORD>::MAX as usize) as c::DWORD;
+        cvt(c::WriteFileEx(self.as_handle(), buf.as_ptr() as c::LPVOID, len, overlapped, callback))
+            .map(drop)
+    }
+
+    /// Like `read_overlapped_timeout`, but writes `buf` with an overlapped
+    /// `WriteFile`.
+    #[allow(dead_code)]
+    pub fn write_overlapped_timeout(
+        &self,
+        buf: &[u8],
+        overlapped: &mut c::OVERLAPPED,
+        timeout: Duration,
+    ) -> io::Result<Option<usize>> {
+        let event = Handle::new_event(true, false)?;
+        overlapped.hEvent = event.as_raw_handle();
+        if let Some(amt) = unsafe { self.write_overlapped(buf, overlapped)? } {
+            return Ok(Some(amt));
+        }
+        self.wait_overlapped_timeout(&event, overlapped, timeout)
+    }
+
+    /// Waits up to `timeout` for the overlapped operation using `overlapped`
+    /// to signal `event`, cancelling it if it doesn't.
+    fn wait_overlapped_timeout(
+        &self,
+        event: &Handle,
+        overlapped: &mut c::OVERLAPPED,
+        timeout: Duration,
+    ) -> io::Result<Option<usize>> {
+        let res = unsafe { c::WaitForSingleObject(event.as_raw_handle(), dur2timeout(timeout)) };
+        if res == c::WAIT_OBJECT_0 {
+            return self.overlapped_result(overlapped, true).map(Some);
+        }
+
+        // The operation has to be finished before its buffer and `overlapped`
+        // can be given back, whatever happens.
+        if res != c::WAIT_TIMEOUT {
+            let err = io::Error::last_os_error();
+            let _ = self.cancel_io_ex(overlapped);
+            let _ = self.overlapped_result(overlapped, true);
+            return Err(err);
+        }
+
+        // The operation may have completed just before being cancelled, in
+        // which case its result isn't thrown away. A cancelled pipe write
+        // can also have been partly taken by the reader already, and then
+        // that part mustn't be written again.
+        let _ = self.cancel_io_ex(overlapped);
+        match self.overlapped_result(overlapped, true) {
+            Ok(amt) => Ok(Some(amt)),
+            Err(e) if e.raw_os_error() == Some(c::ERROR_OPERATION_ABORTED as i32) => {
+                match overlapped.InternalHigh as usize {
+                    0 => Ok(None),
+                    amt => Ok(Some(amt)),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn overlapped_result(
         &self,
         overlapped: *mut c::OVERLAPPED,
@@ -187,8 +443,264 @@ pub fn overlapped_result(
         }
     }
 
-    pub fn cancel_io(&self) -> io::Result<()> {
-        unsafe { cvt(c::CancelIo(self.as_raw_handle())).map(drop) }
+    /// Returns the access rights that were granted when this handle was
+    /// opened, such as `FILE_READ_DATA` and `FILE_WRITE_DATA`.
+    pub fn granted_access(&self) -> io::Result<c::ACCESS_MASK> {
+        let mut info = c::FILE_ACCESS_INFORMATION { AccessFlags: 0 };
+        let mut io_status = c::IO_STATUS_BLOCK::default();
+        let status = unsafe {
+            c::NtQueryInformationFile(
+                self.as_raw_handle(),
+                &mut io_status,
+                &mut info as *mut _ as c::LPVOID,
+                mem::size_of::<c::FILE_ACCESS_INFORMATION>() as c::ULONG,
+                c::FileAccessInformation,
+            )
+        };
+        if c::nt_success(status) {
+            Ok(info.AccessFlags)
+        } else {
+            let error = unsafe { c::RtlNtStatusToDosError(status) };
+            Err(io::Error::from_raw_os_error(error as _))
+        }
+    }
+
+    /// Opens whatever this handle refers to again with `ReOpenFile`, with
+    /// the same access rights but with `flags` as its `FILE_FLAG_*` flags.
+    pub fn reopen(&self, flags: c::DWORD) -> io::Result<Handle> {
+        let access = self.granted_access()?;
+        let share = c::FILE_SHARE_READ | c::FILE_SHARE_WRITE;
+        let handle = unsafe { c::ReOpenFile(self.as_raw_handle(), access, share, flags) };
+        if handle == c::INVALID_HANDLE_VALUE {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(unsafe { Handle::from_raw_handle(handle) })
+        }
+    }
+
+    /// Cancels the overlapped operation on this handle that was started with
+    /// `overlapped`, or all of this process's I/O on the handle if
+    /// `overlapped` is null.
+    ///
+    /// The operation still has to be waited for afterwards, as it may have
+    /// completed before it could be cancelled. Where `CancelIoEx` isn't
+    /// available this falls back to `CancelIo`, which cancels all of the
+    /// calling thread's I/O on the handle whatever `overlapped` is.
+    pub fn cancel_io_ex(&self, overlapped: *mut c::OVERLAPPED) -> io::Result<()> {
+        cvt(unsafe { c::CancelIoEx(self.as_raw_handle(), overlapped) }).map(drop)
+    }
+
+    /// Queries the type and buffer sizes of the pipe this handle refers to.
+    pub fn pipe_info(&self) -> io::Result<NamedPipeInfo> {
+        let mut info =
+            NamedPipeInfo { flags: 0, out_buffer_size: 0, in_buffer_size: 0, max_instances: 0 };
+        cvt(unsafe {
+            c::GetNamedPipeInfo(
+                self.as_raw_handle(),
+                &mut info.flags,
+                &mut info.out_buffer_size,
+                &mut info.in_buffer_size,
+                &mut info.max_instances,
+            )
+        })?;
+        Ok(info)
+    }
+
+    /// Returns the process ID of the client connected to this named pipe.
+    #[allow(dead_code)]
+    pub fn pipe_client_process_id(&self) -> io::Result<u32> {
+        let mut pid = 0;
+        cvt(unsafe { c::GetNamedPipeClientProcessId(self.as_raw_handle(), &mut pid) })?;
+        Ok(pid)
+    }
+
+    /// Returns the process ID of the server end of this named pipe.
+    #[allow(dead_code)]
+    pub fn pipe_server_process_id(&self) -> io::Result<u32> {
+        let mut pid = 0;
+        cvt(unsafe { c::GetNamedPipeServerProcessId(self.as_raw_handle(), &mut pid) })?;
+        Ok(pid)
+    }
+
+    /// Returns the Terminal Services session ID of the server end of this
+    /// named pipe.
+    ///
+    /// Fails with `Unsupported` if `GetNamedPipeServerSessionId` can't be
+    /// loaded.
+    #[allow(dead_code)]
+    pub fn pipe_server_session_id(&self) -> io::Result<u32> {
+        if c::GetNamedPipeServerSessionId::option().is_none() {
+            return Err(io::const_io_error!(
+                io::ErrorKind::Unsupported,
+                "GetNamedPipeServerSessionId is not available",
+            ));
+        }
+        let mut id = 0;
+        cvt(unsafe { c::GetNamedPipeServerSessionId(self.as_raw_handle(), &mut id) })?;
+        Ok(id)
+    }
+
+    /// Returns the Terminal Services session ID of the client connected to
+    /// this named pipe.
+    ///
+    /// Fails with `Unsupported` if `GetNamedPipeClientSessionId` can't be
+    /// loaded.
+    #[allow(dead_code)]
+    pub fn pipe_client_session_id(&self) -> io::Result<u32> {
+        if c::GetNamedPipeClientSessionId::option().is_none() {
+            return Err(io::const_io_error!(
+                io::ErrorKind::Unsupported,
+                "GetNamedPipeClientSessionId is not available",
+            ));
+        }
+        let mut id = 0;
+        cvt(unsafe { c::GetNamedPipeClientSessionId(self.as_raw_handle(), &mut id) })?;
+        Ok(id)
+    }
+
+    /// Makes the current thread impersonate the client of this named pipe
+    /// server until the returned guard is dropped.
+    ///
+    /// Data must have been read from the pipe first. Impersonating with more
+    /// than identification level also requires `SeImpersonatePrivilege`,
+    /// without which this fails with `ERROR_ACCESS_DENIED`.
+    #[cfg(not(target_vendor = "uwp"))]
+    #[allow(dead_code)]
+    pub fn impersonate_named_pipe_client(&self) -> io::Result<ImpersonationGuard> {
+        cvt(unsafe { c::ImpersonateNamedPipeClient(self.as_raw_handle()) })?;
+        Ok(ImpersonationGuard(()))
+    }
+
+    /// Waits for a client to connect to this named pipe, which must have been
+    /// created with `FILE_FLAG_OVERLAPPED`.
+    ///
+    /// This succeeds straight away if a client is already connected.
+    #[allow(dead_code)]
+    pub fn connect_named_pipe_overlapped(&self) -> io::Result<()> {
+        let event = Handle::new_event(true, false)?;
+        let mut overlapped: c::OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.hEvent = event.as_raw_handle();
+        let res = cvt(unsafe { c::ConnectNamedPipe(self.as_raw_handle(), &mut overlapped) });
+        match res {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(c::ERROR_PIPE_CONNECTED as i32) => Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(c::ERROR_IO_PENDING as i32) => {
+                let wait = unsafe { c::WaitForSingleObject(event.as_raw_handle(), c::INFINITE) };
+                // `overlapped` must stay alive until the connect completes, so
+                // if waiting on the event failed block on the result instead.
+                self.overlapped_result(&mut overlapped, wait != c::WAIT_OBJECT_0).map(drop)
+            }
+            // A client that connected and went away again gives `ERROR_NO_DATA`,
+            // which is reported as `BrokenPipe`.
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Changes the read mode of a pipe and, if `wait_mode` is `Some`, whether
+    /// it's blocking. Otherwise the current wait mode is kept.
+    ///
+    /// A pipe can only be switched to `PipeReadMode::Message` if it was
+    /// created with `PIPE_TYPE_MESSAGE`.
+    pub fn set_pipe_mode(
+        &self,
+        read_mode: PipeReadMode,
+        wait_mode: Option<PipeWaitMode>,
+    ) -> io::Result<()> {
+        let wait_mode = match wait_mode {
+            Some(PipeWaitMode::Wait) => c::PIPE_WAIT,
+            Some(PipeWaitMode::NoWait) => c::PIPE_NOWAIT,
+            None => self.pipe_mode()? & c::PIPE_NOWAIT,
+        };
+        let read_mode = match read_mode {
+            PipeReadMode::Byte => c::PIPE_READMODE_BYTE,
+            PipeReadMode::Message => c::PIPE_READMODE_MESSAGE,
+        };
+        self.set_raw_pipe_mode(read_mode | wait_mode)
+    }
+
+    /// Changes whether operations on a pipe block, keeping its read mode.
+    pub fn set_pipe_wait_mode(&self, wait_mode: PipeWaitMode) -> io::Result<()> {
+        let read_mode = self.pipe_mode()? & c::PIPE_READMODE_MESSAGE;
+        let wait_mode = match wait_mode {
+            PipeWaitMode::Wait => c::PIPE_WAIT,
+            PipeWaitMode::NoWait => c::PIPE_NOWAIT,
+        };
+        self.set_raw_pipe_mode(read_mode | wait_mode)
+    }
+
+    /// Returns the `PIPE_READMODE_*` and `PIPE_NOWAIT` bits of a pipe.
+    pub fn pipe_mode(&self) -> io::Result<c::DWORD> {
+        let mut state = 0;
+        cvt(unsafe {
+            c::GetNamedPipeHandleStateW(
+                self.as_raw_handle(),
+                &mut state,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                0,
+            )
+        })?;
+        Ok(state)
+    }
+
+    /// Queries the read mode, wait mode, and collection settings of a pipe.
+    ///
+    /// Fails if this handle isn't a pipe.
+    #[allow(dead_code)]
+    pub fn pipe_handle_state(&self) -> io::Result<PipeHandleState> {
+        let mode = self.pipe_mode()?;
+        let mut state = PipeHandleState {
+            read_mode: if mode & c::PIPE_READMODE_MESSAGE != 0 {
+                PipeReadMode::Message
+            } else {
+                PipeReadMode::Byte
+            },
+            wait_mode: if mode & c::PIPE_NOWAIT != 0 {
+                PipeWaitMode::NoWait
+            } else {
+                PipeWaitMode::Wait
+            },
+            max_collection_count: 0,
+            collect_data_timeout_ms: 0,
+        };
+
+        // Asking for the collection settings of a local pipe or of the server
+        // end fails with `ERROR_INVALID_PARAMETER`, in which case they don't
+        // apply.
+        let res = cvt(unsafe {
+            c::GetNamedPipeHandleStateW(
+                self.as_raw_handle(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut state.max_collection_count,
+                &mut state.collect_data_timeout_ms,
+                ptr::null_mut(),
+                0,
+            )
+        });
+        match res {
+            Ok(_) => Ok(state),
+            Err(e) if e.raw_os_error() == Some(c::ERROR_INVALID_PARAMETER as i32) => {
+                state.max_collection_count = 0;
+                state.collect_data_timeout_ms = 0;
+                Ok(state)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn set_raw_pipe_mode(&self, mut mode: c::DWORD) -> io::Result<()> {
+        cvt(unsafe {
+            c::SetNamedPipeHandleState(
+                self.as_raw_handle(),
+                &mut mode,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        })?;
+        Ok(())
     }
 
     pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
@@ -226,6 +738,23 @@ pub(crate) fn set_inheritable(&self) -> io::Result<()> {
         self.0.set_inheritable()
     }
 
+    /// Allows child processes to inherit this handle.
+    ///
+    /// UWP can't change the flags of an existing handle, so there the handle
+    /// is replaced with an inheritable duplicate instead.
+    pub fn make_inheritable(&mut self) -> io::Result<()> {
+        #[cfg(not(target_vendor = "uwp"))]
+        {
+            self.set_inheritable()
+        }
+
+        #[cfg(target_vendor = "uwp")]
+        {
+            *self = self.duplicate(0, true, c::DUPLICATE_SAME_ACCESS)?;
+            Ok(())
+        }
+    }
+
     /// Performs a synchronous read.
     ///
     /// If the handle is opened for asynchronous I/O then this abort the process.
@@ -1,18 +1,36 @@
+#[cfg(test)]
+mod tests;
+
 use crate::os::windows::prelude::*;
 
+use crate::alloc::Allocator;
+use crate::borrow::Cow;
+use crate::cell::Cell;
 use crate::ffi::OsStr;
+use crate::fmt;
 use crate::io::{self, IoSlice, IoSliceMut};
 use crate::mem;
+use crate::panic;
 use crate::path::Path;
 use crate::ptr;
 use crate::slice;
-use crate::sync::atomic::AtomicUsize;
-use crate::sync::atomic::Ordering::SeqCst;
+use crate::str;
+use crate::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use crate::sync::atomic::Ordering::Relaxed;
+use crate::sync::mpsc;
+use crate::sync::{Arc, Condvar, Mutex, Once};
 use crate::sys::c;
+use crate::sys::cvt;
 use crate::sys::fs::{File, OpenOptions};
-use crate::sys::handle::Handle;
-use crate::sys::hashmap_random_keys;
-use crate::sys_common::IntoInner;
+#[cfg(not(target_vendor = "uwp"))]
+use crate::sys::handle::ImpersonationGuard;
+use crate::sys::handle::{Handle, NamedPipeInfo, PipeReadMode, PipeWaitMode};
+use crate::sys::memchr;
+use crate::sys::process::Stdio;
+use crate::sys::{dur2timeout, to_u16s};
+use crate::sys_common::{AsInner, IntoInner};
+use crate::thread::{self, JoinHandle};
+use crate::time::{Duration, Instant};
 
 ////////////////////////////////////////////////////////////////////////////////
 // Anonymous pipes
@@ -21,20 +39,151 @@
 // A 64kb pipe capacity is the same as a typical Linux default.
 const PIPE_BUFFER_CAPACITY: u32 = 64 * 1024;
 
+// What the names of anonymous pipes start with by default, after `\\.\pipe\`.
+const DEFAULT_NAME_PREFIX: &str = "__rust_anonymous_pipe1__";
+
+// How many names to try before giving up on creating an anonymous pipe.
+const MAX_NAME_TRIES: u32 = 64;
+
 pub enum AnonPipe {
-    Sync(Handle),
-    Async(Handle),
+    Sync(Handle, PipeState),
+    Async(Handle, PipeState),
+}
+
+/// The state of an `AnonPipe` that isn't part of its handle.
+pub struct PipeState {
+    /// The name of the named pipe behind the pipe, if it has one.
+    ///
+    /// This is only kept in builds with debug assertions, to help with
+    /// debugging.
+    #[cfg(debug_assertions)]
+    name: Option<Box<str>>,
+    /// The read and write timeouts in nanoseconds, or zero for none.
+    read_timeout: AtomicU64,
+    write_timeout: AtomicU64,
+}
+
+impl PipeState {
+    fn unnamed() -> PipeState {
+        PipeState {
+            #[cfg(debug_assertions)]
+            name: None,
+            read_timeout: AtomicU64::new(0),
+            write_timeout: AtomicU64::new(0),
+        }
+    }
+
+    #[cfg_attr(not(debug_assertions), allow(unused_variables))]
+    fn named(name: &str) -> PipeState {
+        PipeState {
+            #[cfg(debug_assertions)]
+            name: Some(name.into()),
+            ..PipeState::unnamed()
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        #[cfg(debug_assertions)]
+        return self.name.as_deref();
+        #[cfg(not(debug_assertions))]
+        return None;
+    }
+}
+
+fn load_timeout(timeout: &AtomicU64) -> Option<Duration> {
+    match timeout.load(Relaxed) {
+        0 => None,
+        nanos => Some(Duration::from_nanos(nanos)),
+    }
+}
+
+fn store_timeout(timeout: &AtomicU64, dur: Option<Duration>) -> io::Result<()> {
+    let nanos = match dur {
+        Some(dur) if dur.is_zero() => {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "cannot set a 0 duration timeout",
+            ));
+        }
+        Some(dur) => dur.as_nanos().min(u64::MAX as u128) as u64,
+        None => 0,
+    };
+    timeout.store(nanos, Relaxed);
+    Ok(())
+}
+
+impl Clone for PipeState {
+    fn clone(&self) -> PipeState {
+        PipeState {
+            #[cfg(debug_assertions)]
+            name: self.name.clone(),
+            read_timeout: AtomicU64::new(self.read_timeout.load(Relaxed)),
+            write_timeout: AtomicU64::new(self.write_timeout.load(Relaxed)),
+        }
+    }
 }
 
 impl IntoInner<Handle> for AnonPipe {
     fn into_inner(self) -> Handle {
         match self {
-            Self::Sync(handle) => handle,
-            Self::Async(handle) => handle,
+            Self::Sync(handle, _) => handle,
+            Self::Async(handle, _) => handle,
         }
     }
 }
 
+impl AsHandle for AnonPipe {
+    fn as_handle(&self) -> BorrowedHandle<'_> {
+        self.handle().as_handle()
+    }
+}
+
+impl AsRawHandle for AnonPipe {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.handle().as_raw_handle()
+    }
+}
+
+impl IntoRawHandle for AnonPipe {
+    fn into_raw_handle(self) -> RawHandle {
+        self.into_handle().into_raw_handle()
+    }
+}
+
+impl From<AnonPipe> for OwnedHandle {
+    fn from(pipe: AnonPipe) -> OwnedHandle {
+        pipe.into_handle().into_inner()
+    }
+}
+
+impl fmt::Debug for AnonPipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let variant = if self.is_async() { "AnonPipe::Async" } else { "AnonPipe::Sync" };
+        f.debug_tuple(variant).field(&self.as_raw_handle()).finish()
+    }
+}
+
+impl fmt::Display for AnonPipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.is_async() { "async" } else { "sync" };
+        write!(f, "anonymous pipe ({mode}, handle={:p})", self.as_raw_handle())
+    }
+}
+
+/// Pipes are equal if their handles refer to the same kernel object, such as
+/// a pipe and its `try_clone`.
+///
+/// Before Windows 10 there's no way to tell that, so only pipes with the same
+/// handle value compare equal.
+impl PartialEq for AnonPipe {
+    fn eq(&self, other: &AnonPipe) -> bool {
+        unsafe { c::CompareObjectHandles(self.as_raw_handle(), other.as_raw_handle()) != 0 }
+    }
+}
+
+impl Eq for AnonPipe {}
+
+#[derive(Debug)]
 pub struct Pipes {
     pub ours: AnonPipe,
     pub theirs: AnonPipe,
@@ -47,36 +196,99 @@ pub fn new_synchronous(
         ours_readable: bool,
         their_handle_inheritable: bool,
     ) -> io::Result<Self> {
-        unsafe {
-            // If `CreatePipe` succeeds, these will be our pipes.
-            let mut read = ptr::null_mut();
-            let mut write = ptr::null_mut();
+        PipeBuilder::new()
+            .ours_readable(ours_readable)
+            .their_handle_inheritable(their_handle_inheritable)
+            .synchronous(true)
+            .build()
+    }
 
-            if c::CreatePipe(&mut read, &mut write, ptr::null(), PIPE_BUFFER_CAPACITY) == 0 {
-                Err(io::Error::last_os_error())
-            } else {
-                let (ours, theirs) = if ours_readable { (read, write) } else { (write, read) };
-                let ours = Handle::from_raw_handle(ours);
-                #[cfg(not(target_vendor = "uwp"))]
-                let theirs = Handle::from_raw_handle(theirs);
-                #[cfg(target_vendor = "uwp")]
-                let mut theirs = Handle::from_raw_handle(theirs);
+    /// Wraps a pair of pipe handles that were created elsewhere.
+    ///
+    /// `ours` becomes an asynchronous pipe if `ours_is_async` is set, and a
+    /// synchronous one otherwise. `theirs` is always synchronous.
+    ///
+    /// # Safety
+    ///
+    /// Both handles must be open handles to pipes that aren't owned by
+    /// anything else, since the returned `Pipes` closes them when dropped.
+    /// `ours_is_async` must only be set if `ours` was opened with
+    /// `FILE_FLAG_OVERLAPPED`, and `theirs` must not have been.
+    #[allow(dead_code)]
+    pub unsafe fn from_raw_handles(
+        ours: RawHandle,
+        theirs: RawHandle,
+        ours_is_async: bool,
+    ) -> Pipes {
+        let ours = Handle::from_raw_handle(ours);
+        let theirs = Handle::from_raw_handle(theirs);
+        let ours = if ours_is_async {
+            AnonPipe::Async(ours, PipeState::unnamed())
+        } else {
+            AnonPipe::Sync(ours, PipeState::unnamed())
+        };
+        Pipes { ours, theirs: AnonPipe::Sync(theirs, PipeState::unnamed()) }
+    }
 
-                if their_handle_inheritable {
-                    #[cfg(not(target_vendor = "uwp"))]
-                    {
-                        theirs.set_inheritable()?;
-                    }
+    /// Exchanges `ours` and `theirs`.
+    ///
+    /// Each pipe stays synchronous or asynchronous as it was, so after
+    /// swapping the pipes from `anon_pipe` it's `theirs` that supports
+    /// overlapped I/O.
+    #[allow(dead_code)]
+    pub fn swap(&mut self) {
+        mem::swap(&mut self.ours, &mut self.theirs);
+    }
 
-                    #[cfg(target_vendor = "uwp")]
-                    {
-                        theirs = theirs.duplicate(0, true, c::DUPLICATE_SAME_ACCESS)?;
-                    }
-                }
+    /// Like `swap`, but takes and returns the pipes by value.
+    #[allow(dead_code)]
+    pub fn reversed(mut self) -> Pipes {
+        self.swap();
+        self
+    }
 
-                Ok(Pipes { ours: AnonPipe::Sync(ours), theirs: AnonPipe::Sync(theirs) })
-            }
-        }
+    /// Replaces both pipes with inheritable duplicates, so that both ends can
+    /// be handed to a child process.
+    ///
+    /// `ours` is reopened as a synchronous pipe first, since a child can't be
+    /// expected to use overlapped I/O on it.
+    ///
+    /// An inheritable handle is inherited by *every* child process spawned
+    /// with handle inheritance enabled while it's open, not just the intended
+    /// one, including children spawned concurrently by other threads. Such a
+    /// child holding on to the writing end keeps the reader from ever seeing
+    /// EOF, and one holding both ends can read and write everything sent
+    /// through the pipe. Close these handles as soon as the child has been
+    /// spawned, or pass them with `PROC_THREAD_ATTRIBUTE_HANDLE_LIST`.
+    #[allow(dead_code)]
+    pub fn inherit_all(self) -> io::Result<Pipes> {
+        let ours = self.ours.downgrade_to_sync()?;
+        Ok(Pipes {
+            ours: ours.try_clone_inheritable()?,
+            theirs: self.theirs.try_clone_inheritable()?,
+        })
+    }
+
+    /// Turns `theirs` into a `Stdio` to give to a child process, returning
+    /// it along with `ours` for talking to the child.
+    ///
+    /// The child's handle is duplicated as inheritable when it's spawned, so
+    /// `theirs` needn't be inheritable already.
+    #[allow(dead_code)]
+    pub fn stdio_for_child(self) -> (Stdio, AnonPipe) {
+        (self.theirs.into(), self.ours)
+    }
+
+    /// Returns `ours`, closing `theirs` straight away.
+    #[allow(dead_code)]
+    pub fn take_ours(self) -> AnonPipe {
+        self.ours
+    }
+
+    /// Returns `theirs`, closing `ours` straight away.
+    #[allow(dead_code)]
+    pub fn take_theirs(self) -> AnonPipe {
+        self.theirs
     }
 }
 
@@ -99,491 +311,4001 @@ pub fn new_synchronous(
 /// mode. This means that technically speaking it should only ever be used
 /// with `OVERLAPPED` instances, but also works out ok if it's only ever used
 /// once at a time (which we do indeed guarantee).
+///
+/// The pipe is really a named pipe with a random name. If that name turns out
+/// to be taken, up to 64 names are tried in total before giving up. From the
+/// fourth attempt on, we sleep before each retry: 16ms and then 32ms for every
+/// attempt after that. If every name is taken, the error says how many were
+/// tried and which was last.
 pub fn anon_pipe(ours_readable: bool, their_handle_inheritable: bool) -> io::Result<Pipes> {
-    // Note that we specifically do *not* use `CreatePipe` here because
-    // unfortunately the anonymous pipes returned do not support overlapped
-    // operations. Instead, we create a "hopefully unique" name and create a
-    // named pipe which has overlapped operations enabled.
-    //
-    // Once we do this, we connect do it as usual via `CreateFileW`, and then
-    // we return those reader/writer halves. Note that the `ours` pipe return
-    // value is always the named pipe, whereas `theirs` is just the normal file.
-    // This should hopefully shield us from child processes which assume their
-    // stdout is a named pipe, which would indeed be odd!
-    unsafe {
-        let ours;
-        let mut name;
-        let mut tries = 0;
-        let mut reject_remote_clients_flag = c::PIPE_REJECT_REMOTE_CLIENTS;
-        loop {
-            tries += 1;
-            name = format!(
-                r"\\.\pipe\__rust_anonymous_pipe1__.{}.{}",
-                c::GetCurrentProcessId(),
-                random_number()
-            );
-            let wide_name = OsStr::new(&name).encode_wide().chain(Some(0)).collect::<Vec<_>>();
-            let mut flags = c::FILE_FLAG_FIRST_PIPE_INSTANCE | c::FILE_FLAG_OVERLAPPED;
-            if ours_readable {
-                flags |= c::PIPE_ACCESS_INBOUND;
-            } else {
-                flags |= c::PIPE_ACCESS_OUTBOUND;
-            }
+    anon_pipe_with_prefix(DEFAULT_NAME_PREFIX, ours_readable, their_handle_inheritable)
+}
 
-            let handle = c::CreateNamedPipeW(
-                wide_name.as_ptr(),
-                flags,
-                c::PIPE_TYPE_BYTE
-                    | c::PIPE_READMODE_BYTE
-                    | c::PIPE_WAIT
-                    | reject_remote_clients_flag,
-                1,
-                PIPE_BUFFER_CAPACITY,
-                PIPE_BUFFER_CAPACITY,
-                0,
-                ptr::null_mut(),
-            );
+/// Like `anon_pipe`, but the name of the named pipe starts with `prefix`,
+/// see `PipeBuilder::name_prefix`.
+#[allow(dead_code)]
+pub fn anon_pipe_with_prefix(
+    prefix: &str,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+) -> io::Result<Pipes> {
+    PipeBuilder::new()
+        .name_prefix(prefix)
+        .ours_readable(ours_readable)
+        .their_handle_inheritable(their_handle_inheritable)
+        .build()
+}
 
-            // We pass the `FILE_FLAG_FIRST_PIPE_INSTANCE` flag above, and we're
-            // also just doing a best effort at selecting a unique name. If
-            // `ERROR_ACCESS_DENIED` is returned then it could mean that we
-            // accidentally conflicted with an already existing pipe, so we try
-            // again.
-            //
-            // Don't try again too much though as this could also perhaps be a
-            // legit error.
-            // If `ERROR_INVALID_PARAMETER` is returned, this probably means we're
-            // running on pre-Vista version where `PIPE_REJECT_REMOTE_CLIENTS` is
-            // not supported, so we continue retrying without it. This implies
-            // reduced security on Windows versions older than Vista by allowing
-            // connections to this pipe from remote machines.
-            // Proper fix would increase the number of FFI imports and introduce
-            // significant amount of Windows XP specific code with no clean
-            // testing strategy
-            // For more info, see https://github.com/rust-lang/rust/pull/37677.
-            if handle == c::INVALID_HANDLE_VALUE {
-                let err = io::Error::last_os_error();
-                let raw_os_err = err.raw_os_error();
-                if tries < 10 {
-                    if raw_os_err == Some(c::ERROR_ACCESS_DENIED as i32) {
-                        continue;
-                    } else if reject_remote_clients_flag != 0
-                        && raw_os_err == Some(c::ERROR_INVALID_PARAMETER as i32)
-                    {
-                        reject_remote_clients_flag = 0;
-                        tries -= 1;
-                        continue;
-                    }
-                }
-                return Err(err);
-            }
-            ours = Handle::from_raw_handle(handle);
-            break;
+/// Like `anon_pipe`, but both `ours` and `theirs` can be read from and
+/// written to.
+///
+/// Data written to one end is read from the other. Note that the kernel makes
+/// no guarantees about the relative ordering of a read and a write that are
+/// issued on the same handle at the same time.
+#[allow(dead_code)]
+pub fn anon_pipe_duplex(their_handle_inheritable: bool) -> io::Result<Pipes> {
+    PipeBuilder::new().duplex(true).their_handle_inheritable(their_handle_inheritable).build()
+}
+
+/// An error from creating or using a pipe, sorted into the cases callers
+/// are likely to want to handle.
+///
+/// This converts back into the `io::Error` it was made from, so it can be
+/// returned through `io::Result` with `?` without changing the error.
+#[derive(Debug)]
+pub struct PipeError {
+    kind: PipeErrorKind,
+    // The OS error code for every kind but `Other`.
+    code: c::DWORD,
+    // For `NameCollision`, how many names were tried and the last of them.
+    tried: Option<(u32, String)>,
+}
+
+#[allow(dead_code)] // not all variants are matched on yet
+#[derive(Debug)]
+pub enum PipeErrorKind {
+    /// No unused name could be found for an anonymous pipe.
+    NameCollision,
+    /// The pipe's security settings don't allow the access.
+    AccessDenied,
+    /// There isn't enough quota left for the pipe's buffer.
+    BufferFull,
+    /// The other end of the pipe has been closed, or was never connected.
+    PipeDisconnected,
+    Other(io::Error),
+}
+
+impl PipeError {
+    fn name_collision(attempts: u32, last_name: String) -> PipeError {
+        PipeError {
+            kind: PipeErrorKind::NameCollision,
+            code: c::ERROR_ACCESS_DENIED,
+            tried: Some((attempts, last_name)),
         }
+    }
 
-        // Connect to the named pipe we just created. This handle is going to be
-        // returned in `theirs`, so if `ours` is readable we want this to be
-        // writable, otherwise if `ours` is writable we want this to be
-        // readable.
-        //
-        // Additionally we don't enable overlapped mode on this because most
-        // client processes aren't enabled to work with that.
-        let mut opts = OpenOptions::new();
-        opts.write(ours_readable);
-        opts.read(!ours_readable);
-        opts.share_mode(0);
-        let size = mem::size_of::<c::SECURITY_ATTRIBUTES>();
-        let mut sa = c::SECURITY_ATTRIBUTES {
-            nLength: size as c::DWORD,
-            lpSecurityDescriptor: ptr::null_mut(),
-            bInheritHandle: their_handle_inheritable as i32,
+    #[allow(dead_code)]
+    pub fn kind(&self) -> &PipeErrorKind {
+        &self.kind
+    }
+}
+
+impl From<io::Error> for PipeError {
+    fn from(err: io::Error) -> PipeError {
+        let code = match err.raw_os_error() {
+            Some(code) => code as c::DWORD,
+            None => return PipeError { kind: PipeErrorKind::Other(err), code: 0, tried: None },
+        };
+        let kind = match code {
+            c::ERROR_ACCESS_DENIED => PipeErrorKind::AccessDenied,
+            c::ERROR_NOT_ENOUGH_QUOTA => PipeErrorKind::BufferFull,
+            c::ERROR_BROKEN_PIPE | c::ERROR_NO_DATA | c::ERROR_PIPE_NOT_CONNECTED => {
+                PipeErrorKind::PipeDisconnected
+            }
+            _ => PipeErrorKind::Other(err),
         };
-        opts.security_attributes(&mut sa);
-        let theirs = File::open(Path::new(&name), &opts)?;
-        let theirs = AnonPipe::Sync(theirs.into_inner());
+        PipeError { kind, code, tried: None }
+    }
+}
 
-        Ok(Pipes { ours: AnonPipe::Async(ours), theirs })
+impl From<PipeError> for io::Error {
+    fn from(err: PipeError) -> io::Error {
+        match (err.kind, err.tried) {
+            (PipeErrorKind::Other(err), _) => err,
+            // Keep the kind that `ERROR_ACCESS_DENIED` has, but say why.
+            (PipeErrorKind::NameCollision, Some((attempts, name))) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "no unused pipe name found after {attempts} attempts, last tried {name} \
+                     (os error {})",
+                    err.code
+                ),
+            ),
+            _ => io::Error::from_raw_os_error(err.code as i32),
+        }
     }
 }
 
-/// Takes an asynchronous source pipe and returns a synchronous pipe suitable
-/// for sending to a child process.
+/// An access control list to create pipes with.
 ///
-/// This is achieved by creating a new set of pipes and spawning a thread that
-/// relays messages between the source and the synchronous pipe.
-pub fn spawn_pipe_relay(
-    source: &Handle,
-    ours_readable: bool,
-    their_handle_inheritable: bool,
-) -> io::Result<AnonPipe> {
-    // We need this handle to live for the lifetime of the thread spawned below.
-    let source = AnonPipe::Async(source.duplicate(0, true, c::DUPLICATE_SAME_ACCESS)?);
+/// Access denied entries are always placed ahead of access allowed entries,
+/// whatever order they were added in, which is the order Windows expects.
+/// Any access that isn't explicitly allowed is denied.
+#[allow(dead_code)]
+pub struct PipeSecurityAttributes {
+    // `(allowed, access mask, SID)`. The SIDs are copied into `u32` buffers to
+    // keep them suitably aligned.
+    entries: Vec<(bool, c::DWORD, Vec<u32>)>,
+    acl: Vec<u32>,
+    descriptor: Box<c::SECURITY_DESCRIPTOR>,
+}
 
-    // create a new pair of anon pipes.
-    let Pipes { theirs, ours } = anon_pipe(ours_readable, their_handle_inheritable)?;
+#[allow(dead_code)]
+impl PipeSecurityAttributes {
+    pub fn new() -> PipeSecurityAttributes {
+        PipeSecurityAttributes {
+            entries: Vec::new(),
+            acl: Vec::new(),
+            descriptor: Box::new(unsafe { mem::zeroed() }),
+        }
+    }
 
-    // Spawn a thread that passes messages from one pipe to the other.
-    // Any errors will simply cause the thread to exit.
-    let (reader, writer) = if ours_readable { (ours, source) } else { (source, ours) };
-    crate::thread::spawn(move || {
-        let mut buf = [0_u8; 4096];
-        'reader: while let Ok(len) = reader.read(&mut buf) {
-            if len == 0 {
-                break;
+    /// Grants `access` to the given SID.
+    ///
+    /// # Safety
+    ///
+    /// `sid` must point to the start of a complete, valid SID.
+    pub unsafe fn allow_sid(&mut self, sid: &c::SID, access: c::DWORD) -> &mut Self {
+        self.push(true, sid, access);
+        self
+    }
+
+    /// Denies `access` to the given SID.
+    ///
+    /// # Safety
+    ///
+    /// `sid` must point to the start of a complete, valid SID.
+    pub unsafe fn deny_sid(&mut self, sid: &c::SID, access: c::DWORD) -> &mut Self {
+        self.push(false, sid, access);
+        self
+    }
+
+    unsafe fn push(&mut self, allowed: bool, sid: &c::SID, access: c::DWORD) {
+        let sid = sid as *const c::SID as *mut c::SID;
+        debug_assert!(c::IsValidSid(sid) != 0);
+        let len = c::GetLengthSid(sid) as usize;
+        let mut copy = vec![0u32; (len + 3) / 4];
+        ptr::copy_nonoverlapping(sid as *const u8, copy.as_mut_ptr() as *mut u8, len);
+        self.entries.push((allowed, access, copy));
+    }
+
+    /// Builds the security descriptor and returns attributes pointing at it.
+    ///
+    /// The returned attributes borrow from `self`, so they are only valid
+    /// until `self` is next modified or dropped. The handle is not
+    /// inheritable.
+    fn to_security_attributes(&mut self) -> io::Result<c::SECURITY_ATTRIBUTES> {
+        // Each ACE is a 4 byte header and a 4 byte access mask followed by
+        // the SID.
+        let size = mem::size_of::<c::ACL>()
+            + self.entries.iter().map(|(_, _, sid)| 8 + sid.len() * 4).sum::<usize>();
+        let size: c::DWORD = size.try_into().map_err(|_| {
+            io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "pipe access control list is too large",
+            )
+        })?;
+        self.acl = vec![0u32; (size as usize + 3) / 4];
+        let acl = self.acl.as_mut_ptr() as *mut c::ACL;
+        unsafe {
+            cvt(c::InitializeAcl(acl, size, c::ACL_REVISION))?;
+            for (_, access, sid) in self.entries.iter_mut().filter(|(allowed, ..)| !allowed) {
+                let sid = sid.as_mut_ptr() as *mut c::SID;
+                cvt(c::AddAccessDeniedAce(acl, c::ACL_REVISION, *access, sid))?;
             }
-            let mut start = 0;
-            while let Ok(written) = writer.write(&buf[start..len]) {
-                start += written;
-                if start == len {
-                    continue 'reader;
-                }
+            for (_, access, sid) in self.entries.iter_mut().filter(|(allowed, ..)| *allowed) {
+                let sid = sid.as_mut_ptr() as *mut c::SID;
+                cvt(c::AddAccessAllowedAce(acl, c::ACL_REVISION, *access, sid))?;
             }
-            break;
+
+            let descriptor = &mut *self.descriptor as *mut c::SECURITY_DESCRIPTOR;
+            cvt(c::InitializeSecurityDescriptor(descriptor, c::SECURITY_DESCRIPTOR_REVISION))?;
+            cvt(c::SetSecurityDescriptorDacl(descriptor, c::TRUE, acl, c::FALSE))?;
+            Ok(c::SECURITY_ATTRIBUTES {
+                nLength: mem::size_of::<c::SECURITY_ATTRIBUTES>() as c::DWORD,
+                lpSecurityDescriptor: descriptor as c::LPVOID,
+                bInheritHandle: c::FALSE,
+            })
         }
-    });
+    }
+}
 
-    // Return the pipe that should be sent to the child process.
-    Ok(theirs)
+/// Configuration for creating a pair of anonymous pipes.
+///
+/// By default this creates the same pipes as `anon_pipe`: `ours` is an
+/// overlapped named pipe and `theirs` is a synchronous handle to it. Setting
+/// `synchronous` creates both ends with `CreatePipe` instead, as
+/// `Pipes::new_synchronous` does.
+pub struct PipeBuilder<'a> {
+    buffer_capacity: usize,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    synchronous: bool,
+    duplex: bool,
+    message_mode: bool,
+    name_prefix: &'a str,
+    security: Option<&'a mut PipeSecurityAttributes>,
 }
 
-fn random_number() -> usize {
-    static N: AtomicUsize = AtomicUsize::new(0);
-    loop {
-        if N.load(SeqCst) != 0 {
-            return N.fetch_add(1, SeqCst);
+impl<'a> PipeBuilder<'a> {
+    pub fn new() -> PipeBuilder<'a> {
+        PipeBuilder {
+            buffer_capacity: PIPE_BUFFER_CAPACITY as usize,
+            ours_readable: false,
+            their_handle_inheritable: false,
+            synchronous: false,
+            duplex: false,
+            message_mode: false,
+            name_prefix: DEFAULT_NAME_PREFIX,
+            security: None,
         }
+    }
 
-        N.store(hashmap_random_keys().0 as usize, SeqCst);
+    /// Sets the size of the input and output buffers the kernel reserves for
+    /// the pipe.
+    ///
+    /// This is only advisory: the system may round the value or ignore it.
+    /// `build` fails with `InvalidInput` if it doesn't fit in a `DWORD`.
+    #[allow(dead_code)]
+    pub fn buffer_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.buffer_capacity = capacity;
+        self
     }
-}
 
-// Abstracts over `ReadFileEx` and `WriteFileEx`
-type AlertableIoFn = unsafe extern "system" fn(
-    BorrowedHandle<'_>,
-    c::LPVOID,
-    c::DWORD,
-    c::LPOVERLAPPED,
-    c::LPOVERLAPPED_COMPLETION_ROUTINE,
-) -> c::BOOL;
+    pub fn ours_readable(&mut self, ours_readable: bool) -> &mut Self {
+        self.ours_readable = ours_readable;
+        self
+    }
 
-impl AnonPipe {
-    pub fn handle(&self) -> &Handle {
-        match self {
-            Self::Async(ref handle) => handle,
-            Self::Sync(ref handle) => handle,
-        }
+    pub fn their_handle_inheritable(&mut self, inheritable: bool) -> &mut Self {
+        self.their_handle_inheritable = inheritable;
+        self
     }
-    pub fn into_handle(self) -> Handle {
-        self.into_inner()
+
+    pub fn synchronous(&mut self, synchronous: bool) -> &mut Self {
+        self.synchronous = synchronous;
+        self
     }
 
-    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
-        let result = unsafe {
-            let len = crate::cmp::min(buf.len(), c::DWORD::MAX as usize) as c::DWORD;
-            match self {
-                Self::Sync(ref handle) => handle.read(buf),
-                Self::Async(_) => {
-                    self.alertable_io_internal(c::ReadFileEx, buf.as_mut_ptr() as _, len)
-                }
-            }
-        };
+    /// Makes both ends of the pipe readable and writable, in which case
+    /// `ours_readable` is ignored.
+    ///
+    /// This can't be combined with `synchronous`.
+    pub fn duplex(&mut self, duplex: bool) -> &mut Self {
+        self.duplex = duplex;
+        self
+    }
 
-        match result {
-            // The special treatment of BrokenPipe is to deal with Windows
-            // pipe semantics, which yields this error when *reading* from
-            // a pipe after the other end has closed; we interpret that as
-            // EOF on the pipe.
-            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(0),
-            _ => result,
-        }
+    /// Uses `PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE` so that each write is
+    /// delivered to the reader as a single message.
+    ///
+    /// A read into a buffer that's too small for the next message fails with
+    /// `ERROR_MORE_DATA`, see `AnonPipe::read_message`.
+    ///
+    /// This can't be combined with `synchronous`.
+    #[allow(dead_code)]
+    pub fn message_mode(&mut self, message_mode: bool) -> &mut Self {
+        self.message_mode = message_mode;
+        self
     }
 
-    pub fn read_vectored(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
-        io::default_read_vectored(|buf| self.read(buf), bufs)
+    /// Starts the name of the named pipe behind the anonymous pipe with
+    /// `prefix` instead of `__rust_anonymous_pipe1__`, so that it can be
+    /// told apart in tools that list pipes. The process ID and a random
+    /// number are still added to it.
+    ///
+    /// `prefix` can't contain a backslash, and building fails with
+    /// `InvalidInput` if the whole name comes to more than 256 characters.
+    /// This has no effect on `synchronous` pipes, which don't have names.
+    #[allow(dead_code)]
+    pub fn name_prefix(&mut self, prefix: &'a str) -> &mut Self {
+        self.name_prefix = prefix;
+        self
     }
 
-    #[inline]
-    pub fn is_read_vectored(&self) -> bool {
-        false
+    /// Creates the pipe with the access control from `attrs` instead of the
+    /// default security descriptor.
+    #[allow(dead_code)]
+    pub fn security(&mut self, attrs: &'a mut PipeSecurityAttributes) -> &mut Self {
+        self.security = Some(attrs);
+        self
     }
 
-    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+    pub fn build(&mut self) -> io::Result<Pipes> {
+        self.try_build().map_err(io::Error::from)
+    }
+
+    /// Like `build`, but fails with a `PipeError` that says what went wrong.
+    #[allow(dead_code)]
+    pub fn try_build(&mut self) -> Result<Pipes, PipeError> {
+        let capacity = c::DWORD::try_from(self.buffer_capacity).map_err(|_| {
+            io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "pipe buffer capacity does not fit in a DWORD",
+            )
+        })?;
+        if self.synchronous && self.duplex {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "`CreatePipe` cannot create duplex pipes",
+            )
+            .into());
+        }
+        if self.synchronous && self.message_mode {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "`CreatePipe` cannot create message-mode pipes",
+            )
+            .into());
+        }
+        if self.name_prefix.contains('\\') {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "pipe name prefix cannot contain a backslash",
+            )
+            .into());
+        }
+        let security = match self.security {
+            Some(ref mut attrs) => Some(attrs.to_security_attributes()?),
+            None => None,
+        };
+        let security = security.as_ref().map_or(ptr::null(), |sa| sa as *const _);
+        if self.synchronous {
+            Ok(self.build_synchronous(capacity, security)?)
+        } else {
+            self.build_named(capacity, security)
+        }
+    }
+
+    fn build_synchronous(
+        &self,
+        capacity: c::DWORD,
+        security: *const c::SECURITY_ATTRIBUTES,
+    ) -> io::Result<Pipes> {
         unsafe {
-            let len = crate::cmp::min(buf.len(), c::DWORD::MAX as usize) as c::DWORD;
-            match self {
-                Self::Sync(ref handle) => handle.write(buf),
-                Self::Async(_) => {
-                    self.alertable_io_internal(c::WriteFileEx, buf.as_ptr() as _, len)
+            // If `CreatePipe` succeeds, these will be our pipes.
+            let mut read = ptr::null_mut();
+            let mut write = ptr::null_mut();
+
+            if c::CreatePipe(&mut read, &mut write, security, capacity) == 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                let (ours, theirs) = if self.ours_readable { (read, write) } else { (write, read) };
+                let ours = Handle::from_raw_handle(ours);
+                let mut theirs = Handle::from_raw_handle(theirs);
+                if self.their_handle_inheritable {
+                    theirs.make_inheritable()?;
                 }
+
+                Ok(Pipes {
+                    ours: AnonPipe::Sync(ours, PipeState::unnamed()),
+                    theirs: AnonPipe::Sync(theirs, PipeState::unnamed()),
+                })
             }
         }
     }
 
-    pub fn write_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
-        io::default_write_vectored(|buf| self.write(buf), bufs)
-    }
+    fn build_named(
+        &self,
+        capacity: c::DWORD,
+        security: *const c::SECURITY_ATTRIBUTES,
+    ) -> Result<Pipes, PipeError> {
+        let ours_readable = self.ours_readable;
+        // Note that we specifically do *not* use `CreatePipe` here because
+        // unfortunately the anonymous pipes returned do not support overlapped
+        // operations. Instead, we create a "hopefully unique" name and create a
+        // named pipe which has overlapped operations enabled.
+        //
+        // Once we do this, we connect do it as usual via `CreateFileW`, and then
+        // we return those reader/writer halves. Note that the `ours` pipe return
+        // value is always the named pipe, whereas `theirs` is just the normal file.
+        // This should hopefully shield us from child processes which assume their
+        // stdout is a named pipe, which would indeed be odd!
+        unsafe {
+            let ours;
+            let mut name;
+            let mut tries = 0;
+            let mut reject_remote_clients_flag = c::PIPE_REJECT_REMOTE_CLIENTS;
+            loop {
+                tries += 1;
+                name = anonymous_pipe_name(self.name_prefix)?;
+                let wide_name = wide_pipe_name(&name)?;
+                let mut flags = c::FILE_FLAG_FIRST_PIPE_INSTANCE | c::FILE_FLAG_OVERLAPPED;
+                if self.duplex {
+                    flags |= c::PIPE_ACCESS_DUPLEX;
+                } else if ours_readable {
+                    flags |= c::PIPE_ACCESS_INBOUND;
+                } else {
+                    flags |= c::PIPE_ACCESS_OUTBOUND;
+                }
 
-    #[inline]
-    pub fn is_write_vectored(&self) -> bool {
-        false
-    }
+                let pipe_mode = if self.message_mode {
+                    c::PIPE_TYPE_MESSAGE | c::PIPE_READMODE_MESSAGE
+                } else {
+                    c::PIPE_TYPE_BYTE | c::PIPE_READMODE_BYTE
+                };
 
-    /// Synchronizes asynchronous reads or writes using our anonymous pipe.
-    ///
-    /// This is a wrapper around [`ReadFileEx`] or [`WriteFileEx`] that uses
-    /// [Asynchronous Procedure Call] (APC) to synchronize reads or writes.
-    ///
-    /// Note: This should not be used for handles we don't create.
-    ///
-    /// # Safety
-    ///
+                let handle = c::CreateNamedPipeW(
+                    wide_name.as_ptr(),
+                    flags,
+                    pipe_mode | c::PIPE_WAIT | reject_remote_clients_flag,
+                    1,
+                    capacity,
+                    capacity,
+                    0,
+                    security as *mut _,
+                );
+
+                // We pass the `FILE_FLAG_FIRST_PIPE_INSTANCE` flag above, and we're
+                // also just doing a best effort at selecting a unique name. If
+                // `ERROR_ACCESS_DENIED` is returned then it could mean that we
+                // accidentally conflicted with an already existing pipe, so we try
+                // again.
+                //
+                // Don't try again too much though as this could also perhaps be a
+                // legit error. After the first few attempts we back off a little
+                // in case lots of threads are racing to create pipes. If every
+                // attempt is denied it's reported as a name collision.
+                // If `ERROR_INVALID_PARAMETER` is returned, this probably means we're
+                // running on pre-Vista version where `PIPE_REJECT_REMOTE_CLIENTS` is
+                // not supported, so we continue retrying without it. This implies
+                // reduced security on Windows versions older than Vista by allowing
+                // connections to this pipe from remote machines.
+                // Proper fix would increase the number of FFI imports and introduce
+                // significant amount of Windows XP specific code with no clean
+                // testing strategy
+                // For more info, see https://github.com/rust-lang/rust/pull/37677.
+                if handle == c::INVALID_HANDLE_VALUE {
+                    let err = io::Error::last_os_error();
+                    let raw_os_err = err.raw_os_error();
+                    if raw_os_err == Some(c::ERROR_ACCESS_DENIED as i32) {
+                        if tries >= MAX_NAME_TRIES {
+                            return Err(PipeError::name_collision(tries, name));
+                        }
+                        if tries >= 4 {
+                            c::Sleep(1 << crate::cmp::min(tries, 5));
+                        }
+                        continue;
+                    } else if tries < MAX_NAME_TRIES
+                        && reject_remote_clients_flag != 0
+                        && raw_os_err == Some(c::ERROR_INVALID_PARAMETER as i32)
+                    {
+                        reject_remote_clients_flag = 0;
+                        tries -= 1;
+                        continue;
+                    }
+                    return Err(err.into());
+                }
+                ours = Handle::from_raw_handle(handle);
+                break;
+            }
+
+            // Connect to the named pipe we just created. This handle is going to be
+            // returned in `theirs`, so if `ours` is readable we want this to be
+            // writable, otherwise if `ours` is writable we want this to be
+            // readable. Duplex pipes are both.
+            //
+            // Additionally we don't enable overlapped mode on this because most
+            // client processes aren't enabled to work with that.
+            let mut opts = OpenOptions::new();
+            opts.write(ours_readable || self.duplex);
+            opts.read(!ours_readable || self.duplex);
+            // Changing the mode of a pipe handle with `SetNamedPipeHandleState`
+            // needs `FILE_WRITE_ATTRIBUTES`, which a read-only handle doesn't
+            // get by default.
+            if !ours_readable && !self.duplex {
+                opts.access_mode(c::GENERIC_READ | c::FILE_WRITE_ATTRIBUTES);
+            }
+            opts.share_mode(0);
+            let size = mem::size_of::<c::SECURITY_ATTRIBUTES>();
+            let mut sa = c::SECURITY_ATTRIBUTES {
+                nLength: size as c::DWORD,
+                lpSecurityDescriptor: ptr::null_mut(),
+                bInheritHandle: self.their_handle_inheritable as i32,
+            };
+            opts.security_attributes(&mut sa);
+            let theirs = File::open(Path::new(&name), &opts)?;
+
+            // The client end of a named pipe is always opened in byte read
+            // mode, so it has to be switched over to match.
+            if self.message_mode {
+                theirs.as_inner().set_pipe_mode(PipeReadMode::Message, None)?;
+            }
+            let theirs = AnonPipe::Sync(theirs.into_inner(), PipeState::named(&name));
+
+            Ok(Pipes { ours: AnonPipe::Async(ours, PipeState::named(&name)), theirs })
+        }
+    }
+}
+
+/// Takes an asynchronous source pipe and returns a synchronous pipe suitable
+/// for sending to a child process.
+///
+/// This is achieved by creating a new set of pipes and spawning a thread that
+/// relays messages between the source and the synchronous pipe. The thread
+/// stops at EOF or at the first error, which can be retrieved by joining it.
+pub fn spawn_pipe_relay(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>)> {
+    spawn_relay(source, ours_readable, their_handle_inheritable, RelayConfig::new())
+}
+
+/// Like `spawn_pipe_relay`, but the relay thread is called `name` rather than
+/// `pipe-relay`, to tell relays apart when debugging.
+#[allow(dead_code)]
+pub fn spawn_pipe_relay_with_name(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    name: &str,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>)> {
+    let config = RelayConfig { name, ..RelayConfig::new() };
+    spawn_relay(source, ours_readable, their_handle_inheritable, config)
+}
+
+/// Like `spawn_pipe_relay`, but each chunk read from the source is passed
+/// through `transform` before it's written, for example to convert line
+/// endings or tag each line.
+///
+/// Chunks are whatever a single read returns, so `transform` can't rely on
+/// them ending anywhere in particular.
+#[allow(dead_code)]
+pub fn spawn_pipe_relay_transform<F>(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    transform: F,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>)>
+where
+    F: Fn(&[u8]) -> Cow<'_, [u8]> + Send + 'static,
+{
+    let config = RelayConfig { transform: Some(Box::new(transform)), ..RelayConfig::new() };
+    spawn_relay(source, ours_readable, their_handle_inheritable, config)
+}
+
+/// Like `spawn_pipe_relay`, but the relay stops once it has passed on
+/// `max_bytes`, closing its end of the destination so the reader sees EOF.
+///
+/// The read that reaches the limit is cut short rather than dropped, so
+/// exactly `max_bytes` get through if the source has that many. Stopping at
+/// the limit isn't an error.
+#[allow(dead_code)]
+pub fn spawn_pipe_relay_with_max_bytes(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    max_bytes: u64,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>)> {
+    let config = RelayConfig { max_bytes, ..RelayConfig::new() };
+    spawn_relay(source, ours_readable, their_handle_inheritable, config)
+}
+
+/// Like `spawn_pipe_relay`, but also returns a count of the bytes that the
+/// relay has passed on so far.
+///
+/// The count goes up after each successful write, and is updated with
+/// `Relaxed` ordering, so it's exact once the relay thread has been joined.
+#[allow(dead_code)]
+pub fn spawn_pipe_relay_counted(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>, Arc<AtomicU64>)> {
+    spawn_relay_counted(source, ours_readable, their_handle_inheritable, RelayConfig::new())
+}
+
+/// Like `spawn_pipe_relay_with_buf`, but also returns a count of the bytes
+/// that the relay has passed on so far, as `spawn_pipe_relay_counted` does.
+#[allow(dead_code)]
+pub fn spawn_pipe_relay_with_buf_counted(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    buf_size: usize,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>, Arc<AtomicU64>)> {
+    let config = RelayConfig { buf_size, ..RelayConfig::new() };
+    spawn_relay_counted(source, ours_readable, their_handle_inheritable, config)
+}
+
+/// Like `spawn_pipe_relay`, but the relay thread runs at `priority`.
+///
+/// This keeps a busy relay from being starved by the threads at either end
+/// of it when those run at a raised priority.
+#[allow(dead_code)]
+pub fn spawn_pipe_relay_with_priority(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    priority: ThreadPriority,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>)> {
+    let config = RelayConfig { priority: Some(priority), ..RelayConfig::new() };
+    spawn_relay(source, ours_readable, their_handle_inheritable, config)
+}
+
+/// One of the `THREAD_PRIORITY_*` levels, relative to the priority class of
+/// the process.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ThreadPriority(i32);
+
+#[allow(dead_code)]
+impl ThreadPriority {
+    pub const IDLE: ThreadPriority = ThreadPriority(c::THREAD_PRIORITY_IDLE);
+    pub const LOWEST: ThreadPriority = ThreadPriority(c::THREAD_PRIORITY_LOWEST);
+    pub const BELOW_NORMAL: ThreadPriority = ThreadPriority(c::THREAD_PRIORITY_BELOW_NORMAL);
+    pub const NORMAL: ThreadPriority = ThreadPriority(c::THREAD_PRIORITY_NORMAL);
+    pub const ABOVE_NORMAL: ThreadPriority = ThreadPriority(c::THREAD_PRIORITY_ABOVE_NORMAL);
+    pub const HIGHEST: ThreadPriority = ThreadPriority(c::THREAD_PRIORITY_HIGHEST);
+    pub const TIME_CRITICAL: ThreadPriority = ThreadPriority(c::THREAD_PRIORITY_TIME_CRITICAL);
+
+    /// The `THREAD_PRIORITY_*` value.
+    pub fn as_raw(self) -> i32 {
+        self.0
+    }
+
+    /// Sets the priority of the calling thread.
+    fn set_current(self) -> io::Result<()> {
+        cvt(unsafe { c::SetThreadPriority(c::GetCurrentThread(), self.0) }).map(drop)
+    }
+}
+
+/// The largest buffer `spawn_pipe_relay_with_buf` will allocate.
+const MAX_RELAY_BUFFER: usize = 64 * 1024 * 1024;
+
+/// How `spawn_relay` sets up a relay, which the `spawn_pipe_relay_*`
+/// functions each change a part of.
+struct RelayConfig<'a> {
+    buf_size: usize,
+    /// How many chunks are held between the reading and writing threads, or
+    /// `None` to read and write on the one thread.
+    queue_len: Option<usize>,
+    name: &'a str,
+    /// The priority of the relay's threads, if not the default.
+    priority: Option<ThreadPriority>,
+    transform: Option<RelayTransform>,
+    /// How many bytes to read from the source before stopping.
+    max_bytes: u64,
+}
+
+/// What `spawn_pipe_relay_transform` does to each chunk.
+type RelayTransform = Box<dyn Fn(&[u8]) -> Cow<'_, [u8]> + Send>;
+
+impl RelayConfig<'static> {
+    fn new() -> RelayConfig<'static> {
+        RelayConfig {
+            buf_size: 4096,
+            queue_len: None,
+            name: "pipe-relay",
+            priority: None,
+            transform: None,
+            max_bytes: u64::MAX,
+        }
+    }
+}
+
+/// Like `spawn_pipe_relay`, but the relay thread copies through a heap buffer
+/// of `buf_size` bytes.
+///
+/// A bigger buffer means fewer round trips through the relay thread when a
+/// lot of data is passed through. `buf_size` must be between 1 byte and
+/// 64 MiB.
+pub fn spawn_pipe_relay_with_buf(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    buf_size: usize,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>)> {
+    let config = RelayConfig { buf_size, ..RelayConfig::new() };
+    spawn_relay(source, ours_readable, their_handle_inheritable, config)
+}
+
+/// Like `spawn_pipe_relay_with_buf`, but up to `queue_len` chunks of at most
+/// `buf_size` bytes are held between reading and writing.
+///
+/// The relay reads on one thread and writes on another, so while the
+/// destination isn't being read the source can still be drained into the
+/// queue, and the writer needing time doesn't stop the reader. Once the queue
+/// is full the reader waits for the writer, so the relay never holds more
+/// than that. A `queue_len` of 0 hands every chunk straight to the writer.
+///
+/// The returned thread is the reading one, which finishes once the writing
+/// one has, with the writer's error if there was one. If writing fails, the
+/// writer cancels the read in progress, so the source is closed without any
+/// more being read from it. The writing thread is called `pipe-relay-writer`.
+pub fn spawn_pipe_relay_with_queue(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    buf_size: usize,
+    queue_len: usize,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>)> {
+    let config = RelayConfig { buf_size, queue_len: Some(queue_len), ..RelayConfig::new() };
+    spawn_relay(source, ours_readable, their_handle_inheritable, config)
+}
+
+fn spawn_relay(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    config: RelayConfig<'_>,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>)> {
+    let (theirs, relay_thread, _) =
+        spawn_relay_counted(source, ours_readable, their_handle_inheritable, config)?;
+    Ok((theirs, relay_thread))
+}
+
+fn spawn_relay_counted(
+    source: &Handle,
+    ours_readable: bool,
+    their_handle_inheritable: bool,
+    config: RelayConfig<'_>,
+) -> io::Result<(AnonPipe, JoinHandle<io::Result<()>>, Arc<AtomicU64>)> {
+    let RelayConfig { buf_size, queue_len, name, priority, transform, max_bytes } = config;
+    if buf_size == 0 || buf_size > MAX_RELAY_BUFFER {
+        return Err(io::const_io_error!(
+            io::ErrorKind::InvalidInput,
+            "pipe relay buffer size must be between 1 byte and 64 MiB",
+        ));
+    }
+    if name.contains('\0') {
+        return Err(io::const_io_error!(
+            io::ErrorKind::InvalidInput,
+            "pipe relay names can't contain nul characters",
+        ));
+    }
+
+    // We need this handle to live for the lifetime of the thread spawned below.
+    let source = source.duplicate(0, true, c::DUPLICATE_SAME_ACCESS)?;
+    let source = AnonPipe::Async(source, PipeState::unnamed());
+
+    // create a new pair of anon pipes.
+    let Pipes { theirs, ours } = anon_pipe(ours_readable, their_handle_inheritable)?;
+
+    // Spawn a thread that passes messages from one pipe to the other.
+    let (reader, writer) = if ours_readable { (ours, source) } else { (source, ours) };
+    let written = Arc::new(AtomicU64::new(0));
+    let relay = Relay {
+        reader,
+        writer,
+        buf_size,
+        queue_len,
+        writer_name: format!("{name}-writer"),
+        priority,
+        transform,
+        max_bytes,
+        written: written.clone(),
+    };
+    let relay_thread = thread::Builder::new().name(name.to_owned()).spawn(move || relay.run())?;
+
+    // Return the pipe that should be sent to the child process.
+    Ok((theirs, relay_thread, written))
+}
+
+/// Relays data between two duplex pipes in both directions at once, using
+/// one thread for each direction.
+///
+/// Both `a` and `b` must have been opened for overlapped I/O, so that a read
+/// and a write can be in progress on each of them at the same time. The
+/// first thread copies from `a` to `b` and the second from `b` to `a`, each
+/// until it reaches EOF or an error.
+///
+/// Each thread holds its own duplicates of both handles. When either one
+/// finishes it tells the other to stop too, cancelling the read it's blocked
+/// in, and both close their duplicates. So once one peer closes its end and
+/// the caller's handles are closed, the other peer sees EOF, even if it waits
+/// for that before closing its own end.
+#[allow(dead_code)]
+pub fn spawn_bidirectional_relay(
+    a: &Handle,
+    b: &Handle,
+) -> io::Result<(JoinHandle<io::Result<()>>, JoinHandle<io::Result<()>>)> {
+    // Manual reset, so that it stays set for whichever thread looks later.
+    let stop = Arc::new(Handle::new_event(true, false)?);
+    let spawn = |reader: &Handle, writer: &Handle| -> io::Result<JoinHandle<io::Result<()>>> {
+        let reader = reader.duplicate(0, false, c::DUPLICATE_SAME_ACCESS)?;
+        let writer = writer.duplicate(0, false, c::DUPLICATE_SAME_ACCESS)?;
+        let writer = AnonPipe::Async(writer, PipeState::unnamed());
+        let stop = stop.clone();
+        thread::Builder::new().name("pipe-relay".to_owned()).spawn(move || {
+            let result = relay_until_stopped(&reader, &writer, &stop, &mut [0_u8; 4096]);
+            unsafe { c::SetEvent(stop.as_raw_handle()) };
+            result
+        })
+    };
+    let a_to_b = spawn(a, b)?;
+    let b_to_a = spawn(b, a)?;
+    Ok((a_to_b, b_to_a))
+}
+
+/// Like `relay`, but also stops when `stop` is signalled while waiting for a
+/// read.
+fn relay_until_stopped(
+    reader: &Handle,
+    writer: &AnonPipe,
+    stop: &Handle,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let event = Handle::new_event(true, false)?;
+    loop {
+        let mut overlapped: c::OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.hEvent = event.as_raw_handle();
+        let len = match unsafe { reader.read_overlapped(buf, &mut overlapped)? } {
+            Some(len) => len,
+            None => {
+                let objs = [event.as_raw_handle(), stop.as_raw_handle()];
+                let res =
+                    unsafe { c::WaitForMultipleObjects(2, objs.as_ptr(), c::FALSE, c::INFINITE) };
+                if res == c::WAIT_OBJECT_0 + 1 {
+                    // Cancel the read and wait for it, since `buf` and
+                    // `overlapped` must outlive it. If it completed first,
+                    // pass on what it read before stopping.
+                    let _ = reader.cancel_io_ex(&mut overlapped);
+                    return match reader.overlapped_result(&mut overlapped, true) {
+                        Ok(len) => writer.write_all(&buf[..len]),
+                        Err(e) if e.raw_os_error() == Some(c::ERROR_OPERATION_ABORTED as i32) => {
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    };
+                } else if res != c::WAIT_OBJECT_0 {
+                    // The read is still pending, so it can't be left behind.
+                    let err = io::Error::last_os_error();
+                    let _ = reader.cancel_io_ex(&mut overlapped);
+                    let _ = reader.overlapped_result(&mut overlapped, true);
+                    return Err(err);
+                }
+                reader.overlapped_result(&mut overlapped, true)?
+            }
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        writer.write_all(&buf[..len])?;
+    }
+}
+
+/// Copies everything from `pipe` into `file` on a thread of its own, until
+/// `pipe` reaches EOF or an error. The thread returns how many bytes it
+/// copied.
+///
+/// There's no way to have the kernel move data from a pipe straight into a
+/// file, so this still reads and then writes. It goes through a buffer on the
+/// thread's stack rather than the heap, though. The thread writes through its
+/// own duplicate of `file`'s handle, which shares the file position.
+#[allow(dead_code)]
+pub fn relay_pipe_to_file(pipe: AnonPipe, file: &File) -> io::Result<JoinHandle<io::Result<u64>>> {
+    let file = file.duplicate()?;
+    thread::Builder::new().name("pipe-relay".to_owned()).spawn(move || {
+        let mut buf = [0_u8; PIPE_BUFFER_CAPACITY as usize];
+        let mut total = 0;
+        loop {
+            let len = pipe.read(&mut buf)?;
+            if len == 0 {
+                return Ok(total);
+            }
+            let mut chunk = &buf[..len];
+            while !chunk.is_empty() {
+                match file.write(chunk)? {
+                    0 => {
+                        return Err(io::const_io_error!(
+                            io::ErrorKind::WriteZero,
+                            "failed to write whole buffer",
+                        ));
+                    }
+                    n => chunk = &chunk[n..],
+                }
+            }
+            total += len as u64;
+        }
+    })
+}
+
+/// Copies everything from `reader` to `writer` through `buf`, stopping at EOF
+/// or at the first error.
+///
+/// Returns the number of bytes copied.
+fn relay(reader: &AnonPipe, writer: &AnonPipe, buf: &mut [u8]) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let len = reader.read(buf)?;
+        if len == 0 {
+            return Ok(total);
+        }
+        writer.write_all(&buf[..len])?;
+        total += len as u64;
+    }
+}
+
+/// A relay spawned by `spawn_relay`, with everything its threads need.
+struct Relay {
+    reader: AnonPipe,
+    writer: AnonPipe,
+    buf_size: usize,
+    queue_len: Option<usize>,
+    writer_name: String,
+    /// The priority of the relay's threads, if not the default.
+    priority: Option<ThreadPriority>,
+    /// Applied to each chunk on the reading thread before it's queued.
+    transform: Option<RelayTransform>,
+    /// How many bytes to read from the source before stopping.
+    max_bytes: u64,
+    /// How many bytes have been written so far, after any `transform`.
+    written: Arc<AtomicU64>,
+}
+
+impl Relay {
+    fn run(self) -> io::Result<()> {
+        if let Some(priority) = self.priority {
+            priority.set_current()?;
+        }
+        match self.queue_len {
+            None => self.run_direct(),
+            Some(queue_len) => self.run_queued(queue_len),
+        }
+    }
+
+    /// Reads and writes on this thread, stopping at the first error. Both
+    /// ends are closed as soon as the thread does.
+    fn run_direct(self) -> io::Result<()> {
+        let mut buf = vec![0_u8; self.buf_size];
+        let mut left = self.max_bytes;
+        while left > 0 {
+            let max = crate::cmp::min(buf.len() as u64, left) as usize;
+            let len = self.reader.read(&mut buf[..max])?;
+            if len == 0 {
+                break;
+            }
+            left -= len as u64;
+            let data = match self.transform {
+                Some(ref transform) => transform(&buf[..len]),
+                None => Cow::Borrowed(&buf[..len]),
+            };
+            self.writer.write_all(&data)?;
+            self.written.fetch_add(data.len() as u64, Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Runs both halves of the relay: this thread reads into the queue, and a
+    /// thread of its own called `writer_name` writes from it.
+    fn run_queued(self, queue_len: usize) -> io::Result<()> {
+        let Relay {
+            reader,
+            writer,
+            buf_size,
+            queue_len: _,
+            writer_name,
+            priority,
+            transform,
+            max_bytes,
+            written,
+        } = self;
+        // The writer needs the reader to cancel its reads, which only works
+        // through the handle they were started on.
+        let reader = Arc::new(reader);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let reader_done = Arc::new(AtomicBool::new(false));
+
+        // Written chunks are sent back to be filled again, so once there are
+        // enough of them to go round nothing more is allocated. At most
+        // `queue_len` are queued, one is being written and one filled.
+        let (queue, chunks) = mpsc::sync_channel::<Vec<u8>>(queue_len);
+        let (recycle, recycled) = mpsc::sync_channel::<Vec<u8>>(queue_len + 2);
+        let write_thread = {
+            let reader = reader.clone();
+            let stopped = stopped.clone();
+            let reader_done = reader_done.clone();
+            thread::Builder::new().name(writer_name).spawn(move || -> io::Result<()> {
+                let result = (|| -> io::Result<()> {
+                    if let Some(priority) = priority {
+                        priority.set_current()?;
+                    }
+                    for mut chunk in &chunks {
+                        writer.write_all(&chunk)?;
+                        written.fetch_add(chunk.len() as u64, Relaxed);
+                        chunk.clear();
+                        let _ = recycle.try_send(chunk);
+                    }
+                    Ok(())
+                })();
+                if result.is_err() {
+                    // Stop the reader, whether it's waiting to queue a chunk
+                    // or for the source. A read can start just after it's
+                    // cancelled, so keep at it until the reader has given up.
+                    drop(chunks);
+                    stopped.store(true, Relaxed);
+                    while !reader_done.load(Relaxed) {
+                        let _ = reader.handle().cancel_io_ex(ptr::null_mut());
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                result
+            })?
+        };
+
+        let mut buf = vec![0_u8; buf_size];
+        let mut left = max_bytes;
+        let read_result = loop {
+            // Once the limit is reached, stopping here closes both ends.
+            if left == 0 || stopped.load(Relaxed) {
+                break Ok(());
+            }
+            let max = crate::cmp::min(buf.len() as u64, left) as usize;
+            let len = match reader.read(&mut buf[..max]) {
+                Ok(0) => break Ok(()),
+                // The writer failed, and returns its error below.
+                _ if stopped.load(Relaxed) => break Ok(()),
+                Ok(len) => len,
+                Err(e) => break Err(e),
+            };
+            left -= len as u64;
+            let data = match transform {
+                Some(ref transform) => transform(&buf[..len]),
+                None => Cow::Borrowed(&buf[..len]),
+            };
+            let chunk = match data {
+                Cow::Owned(chunk) => chunk,
+                Cow::Borrowed(data) => {
+                    let mut chunk =
+                        recycled.try_recv().unwrap_or_else(|_| Vec::with_capacity(buf_size));
+                    chunk.extend_from_slice(data);
+                    chunk
+                }
+            };
+            // The writer only goes away after an error, which it returns below.
+            if queue.send(chunk).is_err() {
+                break Ok(());
+            }
+        };
+
+        // Let the writer finish what's queued, or stop cancelling reads.
+        reader_done.store(true, Relaxed);
+        drop(queue);
+        let write_result = write_thread.join().unwrap_or_else(|e| panic::resume_unwind(e));
+        write_result.and(read_result)
+    }
+}
+
+/// The double-buffered copy from `AnonPipe::copy_to`.
+///
+/// Both handles must be opened for overlapped I/O.
+unsafe fn copy_overlapped(src: &Handle, dst: &Handle) -> io::Result<u64> {
+    let mut front = vec![0_u8; PIPE_BUFFER_CAPACITY as usize];
+    let mut back = vec![0_u8; PIPE_BUFFER_CAPACITY as usize];
+    let read_event = Handle::new_event(true, false)?;
+    let write_event = Handle::new_event(true, false)?;
+    let mut read_overlapped: c::OVERLAPPED = mem::zeroed();
+    read_overlapped.hEvent = read_event.as_raw_handle();
+    let mut write_overlapped: c::OVERLAPPED = mem::zeroed();
+    write_overlapped.hEvent = write_event.as_raw_handle();
+
+    let mut total = 0;
+    let mut len = match src.read_overlapped(&mut front, &mut read_overlapped)? {
+        Some(len) => len,
+        None => src.overlapped_result(&mut read_overlapped, true)?,
+    };
+    while len != 0 {
+        // Read the next chunk into `back` while `front` is being written.
+        let read = src.read_overlapped(&mut back, &mut read_overlapped)?;
+        let written = write_all_overlapped(dst, &front[..len], &mut write_overlapped);
+
+        // The read must be finished before returning, even if the write
+        // failed, since the kernel still has pointers to `back` and
+        // `read_overlapped`.
+        let next = match read {
+            Some(next) => Ok(next),
+            None => {
+                if written.is_err() {
+                    let _ = src.cancel_io_ex(&mut read_overlapped);
+                }
+                src.overlapped_result(&mut read_overlapped, true)
+            }
+        };
+        written?;
+        total += len as u64;
+        len = next?;
+        mem::swap(&mut front, &mut back);
+    }
+    Ok(total)
+}
+
+/// Writes all of `buf` to the overlapped handle `dst`, waiting for each
+/// write to complete.
+unsafe fn write_all_overlapped(
+    dst: &Handle,
+    mut buf: &[u8],
+    overlapped: &mut c::OVERLAPPED,
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        let written = match dst.write_overlapped(buf, overlapped)? {
+            Some(written) => written,
+            None => dst.overlapped_result(overlapped, true)?,
+        };
+        if written == 0 {
+            return Err(io::const_io_error!(
+                io::ErrorKind::WriteZero,
+                "failed to write whole buffer",
+            ));
+        }
+        buf = &buf[written..];
+    }
+    Ok(())
+}
+
+/// The most UTF-16 units a pipe name can have, `\\.\pipe\` included.
+const MAX_PIPE_NAME_LEN: usize = 256;
+
+/// Encodes `name` as a nul-terminated wide string, without allocating.
+///
+/// Windows doesn't accept pipe names longer than 256 characters, so a buffer
+/// on the stack always fits one, and longer names fail with `InvalidInput`.
+fn wide_pipe_name(name: &str) -> io::Result<[u16; MAX_PIPE_NAME_LEN + 1]> {
+    // Everything after the name is left as 0, terminating it.
+    let mut wide = [0; MAX_PIPE_NAME_LEN + 1];
+    for (i, unit) in name.encode_utf16().enumerate() {
+        if i == MAX_PIPE_NAME_LEN {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "pipe names can't be longer than 256 characters",
+            ));
+        }
+        if unit == 0 {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "pipe names can't contain nul characters",
+            ));
+        }
+        wide[i] = unit;
+    }
+    Ok(wide)
+}
+
+/// Picks a "hopefully unique" name for the named pipe behind an anonymous
+/// pipe.
+fn anonymous_pipe_name(prefix: &str) -> io::Result<String> {
+    Ok(format!(r"\\.\pipe\{}.{}.{}", prefix, unsafe { c::GetCurrentProcessId() }, random_number()?))
+}
+
+/// Returns a number from a counter that starts at a random value, so the
+/// names of our pipes can't be guessed from the process ID alone.
+///
+/// Once the seed is set this takes no locks and doesn't loop: it's a check
+/// of `SEEDED` and then relaxed atomics, as uniqueness only needs the
+/// counter's `fetch_add`.
+fn random_number() -> io::Result<usize> {
+    static SEEDED: Once = Once::new();
+    static SEED: AtomicU64 = AtomicU64::new(0);
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    if !SEEDED.is_completed() {
+        // Failing to get a seed leaves `SEEDED` unset, so the next call tries
+        // again. Threads racing here each get one, but only the first is kept.
+        let mut seed: u64 = 0;
+        let ret = unsafe {
+            c::BCryptGenRandom(
+                ptr::null_mut(),
+                &mut seed as *mut u64 as *mut u8,
+                mem::size_of::<u64>() as c::ULONG,
+                c::BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+            )
+        };
+        // `BCryptGenRandom` returns an `NTSTATUS`.
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(unsafe {
+                c::RtlNtStatusToDosError(ret) as i32
+            }));
+        }
+        SEEDED.call_once(|| SEED.store(seed, Relaxed));
+    }
+    // `SEEDED` being complete makes the store to `SEED` visible.
+    Ok((SEED.load(Relaxed) as usize).wrapping_add(COUNTER.fetch_add(1, Relaxed)))
+}
+
+// Abstracts over `ReadFileEx` and `WriteFileEx`
+type AlertableIoFn = unsafe extern "system" fn(
+    BorrowedHandle<'_>,
+    c::LPVOID,
+    c::DWORD,
+    c::LPOVERLAPPED,
+    c::LPOVERLAPPED_COMPLETION_ROUTINE,
+) -> c::BOOL;
+
+/// The amount of data waiting to be read from a pipe, as reported by
+/// `PeekNamedPipe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PipeStatus {
+    /// The total number of bytes that can be read.
+    pub bytes_available: u32,
+    /// The number of bytes left in the current message. This is always zero
+    /// for byte-mode pipes.
+    pub bytes_left_in_message: u32,
+}
+
+impl AnonPipe {
+    pub fn handle(&self) -> &Handle {
+        match self {
+            Self::Async(ref handle, _) => handle,
+            Self::Sync(ref handle, _) => handle,
+        }
+    }
+    pub fn into_handle(self) -> Handle {
+        self.into_inner()
+    }
+
+    /// Returns whether this pipe was opened for overlapped I/O.
+    #[allow(dead_code)]
+    pub fn is_async(&self) -> bool {
+        matches!(self, Self::Async(..))
+    }
+
+    #[allow(dead_code)]
+    pub fn is_sync(&self) -> bool {
+        matches!(self, Self::Sync(..))
+    }
+
+    fn state(&self) -> &PipeState {
+        match self {
+            Self::Sync(_, state) | Self::Async(_, state) => state,
+        }
+    }
+
+    /// Makes `read` fail with `TimedOut` if no data arrives within `timeout`,
+    /// or removes the timeout if it's `None`.
+    ///
+    /// Like `TcpStream::set_read_timeout`, a zero duration is rejected with
+    /// `InvalidInput`. The timeout is copied by `try_clone` but is otherwise
+    /// only for this `AnonPipe`, not for other handles to the same pipe.
+    #[allow(dead_code)]
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        store_timeout(&self.state().read_timeout, timeout)
+    }
+
+    /// Makes `write` fail with `TimedOut` if it can't complete within
+    /// `timeout`, or removes the timeout if it's `None`.
+    ///
+    /// A write to a synchronous pipe can't be interrupted once it has
+    /// started, so setting a write timeout on one fails with `Unsupported`.
+    #[allow(dead_code)]
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout.is_some() && self.is_sync() {
+            return Err(io::const_io_error!(
+                io::ErrorKind::Unsupported,
+                "write timeouts are only supported for asynchronous pipes",
+            ));
+        }
+        store_timeout(&self.state().write_timeout, timeout)
+    }
+
+    #[allow(dead_code)]
+    pub fn read_timeout(&self) -> Option<Duration> {
+        load_timeout(&self.state().read_timeout)
+    }
+
+    #[allow(dead_code)]
+    pub fn write_timeout(&self) -> Option<Duration> {
+        load_timeout(&self.state().write_timeout)
+    }
+
+    /// Reopens a synchronous pipe for overlapped I/O, closing the original
+    /// handle.
+    ///
+    /// Asynchronous pipes are returned as they are.
+    #[allow(dead_code)]
+    pub fn upgrade_to_async(self) -> io::Result<AnonPipe> {
+        match self {
+            Self::Async(..) => Ok(self),
+            Self::Sync(handle, state) => {
+                Ok(Self::Async(handle.reopen(c::FILE_FLAG_OVERLAPPED)?, state))
+            }
+        }
+    }
+
+    /// Reopens an asynchronous pipe without `FILE_FLAG_OVERLAPPED`, closing
+    /// the original handle.
+    ///
+    /// Any overlapped I/O on the pipe must have completed or been cancelled
+    /// first. Synchronous pipes are returned as they are.
+    #[allow(dead_code)]
+    pub fn downgrade_to_sync(self) -> io::Result<AnonPipe> {
+        match self {
+            Self::Sync(..) => Ok(self),
+            Self::Async(handle, state) => Ok(Self::Sync(handle.reopen(0)?, state)),
+        }
+    }
+
+    /// Converts this into a `PipeReader`, failing with `InvalidInput` if
+    /// the pipe isn't readable.
+    #[allow(dead_code)]
+    pub fn into_reader(self) -> io::Result<PipeReader> {
+        if self.handle().granted_access()? & c::FILE_READ_DATA == 0 {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "pipe is not the reading end",
+            ));
+        }
+        Ok(PipeReader(self))
+    }
+
+    /// Converts this into a `PipeWriter`, failing with `InvalidInput` if
+    /// the pipe isn't writable.
+    #[allow(dead_code)]
+    pub fn into_writer(self) -> io::Result<PipeWriter> {
+        if self.handle().granted_access()? & c::FILE_WRITE_DATA == 0 {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "pipe is not the writing end",
+            ));
+        }
+        Ok(PipeWriter(self))
+    }
+
+    /// Returns the path of the named pipe behind this pipe, which looks like
+    /// `\\.\pipe\__rust_anonymous_pipe1__.<pid>.<n>`.
+    ///
+    /// This is only known in builds with debug assertions, and only for
+    /// pipes created with `anon_pipe` or a `PipeBuilder` that isn't
+    /// `synchronous`.
+    #[allow(dead_code)]
+    pub fn debug_name(&self) -> Option<&str> {
+        match self {
+            Self::Sync(_, state) | Self::Async(_, state) => state.name(),
+        }
+    }
+
+    /// Duplicates the underlying handle, keeping the pipe synchronous or
+    /// asynchronous as it was.
+    ///
+    /// The new handle is not inheritable. Use `try_clone_inheritable` for
+    /// handles that are going to be passed to a child process.
+    #[allow(dead_code)]
+    pub fn try_clone(&self) -> io::Result<AnonPipe> {
+        self.duplicate_same_access(false)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_clone_inheritable(&self) -> io::Result<AnonPipe> {
+        self.duplicate_same_access(true)
+    }
+
+    fn duplicate_same_access(&self, inherit: bool) -> io::Result<AnonPipe> {
+        self.duplicate(0, inherit)
+    }
+
+    /// Duplicates the underlying handle, keeping the pipe synchronous or
+    /// asynchronous as it was.
+    ///
+    /// The duplicate always has the same access as this handle, so
+    /// `desired_access` is ignored by `DuplicateHandle` and should be 0.
+    /// Both handles refer to the same pipe, so whatever is read through one
+    /// of them isn't seen through the other.
+    #[allow(dead_code)]
+    pub fn duplicate(&self, desired_access: c::DWORD, inherit: bool) -> io::Result<AnonPipe> {
+        let handle = self.handle().duplicate(desired_access, inherit, c::DUPLICATE_SAME_ACCESS)?;
+        Ok(match self {
+            Self::Sync(_, ref state) => Self::Sync(handle, state.clone()),
+            Self::Async(_, ref state) => Self::Async(handle, state.clone()),
+        })
+    }
+
+    /// Moves this pipe into the process `target`, returning the value of its
+    /// handle there.
+    ///
+    /// This lets a parent hand a pipe straight to another process, instead
+    /// of running a relay thread to pass data on. The handle is inheritable
+    /// in `target`, which needs some other way to find out its value. Our
+    /// handle is closed even if duplicating it fails.
+    #[allow(dead_code)]
+    pub fn send_to_process(self, target: c::HANDLE) -> io::Result<RawHandle> {
+        // `DUPLICATE_CLOSE_SOURCE` closes our handle whatever happens, so it
+        // mustn't be closed again when `self` is dropped.
+        let source = self.into_raw_handle();
+        let mut new_handle = ptr::null_mut();
+        cvt(unsafe {
+            c::DuplicateHandle(
+                c::GetCurrentProcess(),
+                source,
+                target,
+                &mut new_handle,
+                0,
+                c::TRUE,
+                c::DUPLICATE_SAME_ACCESS | c::DUPLICATE_CLOSE_SOURCE,
+            )
+        })?;
+        Ok(new_handle)
+    }
+
+    /// Returns the handle's `HANDLE_FLAG_*` flags, as reported by
+    /// `GetHandleInformation`.
+    #[cfg(not(target_vendor = "uwp"))]
+    #[allow(dead_code)]
+    pub fn windows_handle_flags(&self) -> io::Result<u32> {
+        let mut flags = 0;
+        cvt(unsafe { c::GetHandleInformation(self.handle().as_raw_handle(), &mut flags) })?;
+        Ok(flags)
+    }
+
+    #[cfg(not(target_vendor = "uwp"))]
+    fn set_handle_flag(&self, flag: c::DWORD, on: bool) -> io::Result<()> {
+        let flags = if on { flag } else { 0 };
+        cvt(unsafe { c::SetHandleInformation(self.handle().as_raw_handle(), flag, flags) })?;
+        Ok(())
+    }
+
+    /// Sets whether child processes inherit this pipe, changing the handle's
+    /// `HANDLE_FLAG_INHERIT` flag in place.
+    #[cfg(not(target_vendor = "uwp"))]
+    #[allow(dead_code)]
+    pub fn set_inheritable(&self, inheritable: bool) -> io::Result<()> {
+        self.set_handle_flag(c::HANDLE_FLAG_INHERIT, inheritable)
+    }
+
+    #[cfg(not(target_vendor = "uwp"))]
+    #[allow(dead_code)]
+    pub fn is_inheritable(&self) -> io::Result<bool> {
+        Ok(self.windows_handle_flags()? & c::HANDLE_FLAG_INHERIT != 0)
+    }
+
+    /// Sets `HANDLE_FLAG_PROTECT_FROM_CLOSE`, which makes `CloseHandle` fail
+    /// on this handle.
+    ///
+    /// A protected handle is leaked when the pipe is dropped, so clear the
+    /// flag again before letting go of it.
+    #[cfg(not(target_vendor = "uwp"))]
+    #[allow(dead_code)]
+    pub fn set_protected(&self, protected: bool) -> io::Result<()> {
+        self.set_handle_flag(c::HANDLE_FLAG_PROTECT_FROM_CLOSE, protected)
+    }
+
+    #[cfg(not(target_vendor = "uwp"))]
+    #[allow(dead_code)]
+    pub fn is_protected(&self) -> io::Result<bool> {
+        Ok(self.windows_handle_flags()? & c::HANDLE_FLAG_PROTECT_FROM_CLOSE != 0)
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = match self.read_timeout() {
+            Some(timeout) => self.read_with_timeout(buf, timeout),
+            None => unsafe {
+                let len = crate::cmp::min(buf.len(), c::DWORD::MAX as usize) as c::DWORD;
+                match self {
+                    Self::Sync(ref handle, _) => handle.read(buf),
+                    Self::Async(..) => {
+                        self.alertable_io_internal(c::ReadFileEx, buf.as_mut_ptr() as _, len)
+                    }
+                }
+            },
+        };
+
+        match result {
+            // In non-blocking mode an empty pipe gives `ERROR_NO_DATA`, which
+            // would otherwise be decoded as `BrokenPipe` below. Since that
+            // can also mean the pipe is being closed, check the mode first.
+            Err(ref e)
+                if e.raw_os_error() == Some(c::ERROR_NO_DATA as i32) && self.is_non_blocking() =>
+            {
+                Err(io::const_io_error!(
+                    io::ErrorKind::WouldBlock,
+                    "no data is available in the pipe",
+                ))
+            }
+            // The special treatment of BrokenPipe is to deal with Windows
+            // pipe semantics, which yields this error when *reading* from
+            // a pipe after the other end has closed; we interpret that as
+            // EOF on the pipe.
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(0),
+            _ => result,
+        }
+    }
+
+    fn read_with_timeout(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let timed_out = || io::const_io_error!(io::ErrorKind::TimedOut, "pipe read timed out");
+        match self {
+            Self::Async(ref handle, _) => {
+                let mut overlapped: c::OVERLAPPED = unsafe { mem::zeroed() };
+                handle.read_overlapped_timeout(buf, &mut overlapped, timeout)?.ok_or_else(timed_out)
+            }
+            // A synchronous read can't be cancelled, so instead wait for data
+            // to arrive before reading. If someone else reads the data first
+            // the read blocks anyway.
+            Self::Sync(ref handle, _) => {
+                let deadline = Instant::now().checked_add(timeout);
+                loop {
+                    match self.poll_read() {
+                        Ok(0) => {}
+                        // Errors here mostly mean the pipe was closed, which the
+                        // read reports as EOF.
+                        _ => return handle.read(buf),
+                    }
+                    if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                        return Err(timed_out());
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+    }
+
+    /// Switches the pipe to or from the `PIPE_NOWAIT` mode.
+    ///
+    /// In that mode a read from an empty pipe fails with `WouldBlock`, and a
+    /// write only writes as much as fits in the pipe's buffer (possibly
+    /// nothing). `PIPE_NOWAIT` is a legacy mode that Microsoft recommends
+    /// against, and it doesn't work with overlapped I/O, so this is only
+    /// useful for synchronous pipes. New code should use asynchronous pipes
+    /// instead.
+    #[allow(dead_code)]
+    pub fn set_non_blocking(&self, nonblocking: bool) -> io::Result<()> {
+        let wait_mode = if nonblocking { PipeWaitMode::NoWait } else { PipeWaitMode::Wait };
+        self.handle().set_pipe_wait_mode(wait_mode)
+    }
+
+    fn is_non_blocking(&self) -> bool {
+        self.handle().pipe_mode().map_or(false, |mode| mode & c::PIPE_NOWAIT != 0)
+    }
+
+    /// Reads until `buf` is full, failing with `UnexpectedEof` if the pipe
+    /// reaches EOF first.
+    #[allow(dead_code)]
+    pub fn read_exact(&self, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf) {
+                Ok(0) => {
+                    return Err(io::const_io_error!(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ));
+                }
+                Ok(n) => buf = &mut buf[n..],
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads until EOF, appending everything to `buf`.
+    ///
+    /// Whenever `buf` is full its capacity grows to the next power of two, and
+    /// each read fills as much of the spare capacity as it can. Before
+    /// growing, a small read checks for EOF so that a `buf` which is already
+    /// big enough is never reallocated.
+    ///
+    /// Returns the number of bytes read.
+    #[allow(dead_code)]
+    pub fn read_to_end<A: Allocator>(&self, buf: &mut Vec<u8, A>) -> io::Result<usize> {
+        let start = buf.len();
+        let mut probe = [0u8; 32];
+        loop {
+            if buf.len() == buf.capacity() {
+                let n = self.read(&mut probe)?;
+                if n == 0 {
+                    return Ok(buf.len() - start);
+                }
+                let cap = (buf.capacity() + n).next_power_of_two().max(16);
+                buf.reserve_exact(cap - buf.len());
+                buf.extend_from_slice(&probe[..n]);
+                continue;
+            }
+
+            let len = buf.len();
+            let spare = unsafe { slice_to_end(buf) };
+            let spare = &mut spare[..crate::cmp::min(spare.len(), c::DWORD::MAX as usize)];
+            match self.read(spare)? {
+                0 => return Ok(buf.len() - start),
+                amt => unsafe { buf.set_len(len + amt) },
+            }
+        }
+    }
+
+    /// Reads at most `n` bytes onto the end of `buf` with a single `read`,
+    /// reserving room for them first.
+    ///
+    /// `n` is only an upper bound: like `read`, this returns as soon as any
+    /// data is available, so fewer bytes may be read. `buf` grows by exactly
+    /// the number of bytes returned.
+    #[allow(dead_code)]
+    pub fn read_at_most(&self, n: usize, buf: &mut Vec<u8>) -> io::Result<usize> {
+        buf.reserve(n);
+        let len = buf.len();
+        let spare = unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr().add(len), n) };
+        let amt = self.read(spare)?;
+        unsafe { buf.set_len(len + amt) };
+        Ok(amt)
+    }
+
+    /// Reads the next message from a message-mode pipe and appends it to
+    /// `buf`, growing `buf` until the whole message fits.
+    ///
+    /// Returns the length of the message.
+    #[allow(dead_code)]
+    pub fn read_message(&self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        loop {
+            let len = buf.len();
+            let spare = unsafe { slice_to_end(buf) };
+            let spare = &mut spare[..crate::cmp::min(spare.len(), c::DWORD::MAX as usize)];
+            match self.read(spare) {
+                Ok(amt) => {
+                    unsafe { buf.set_len(len + amt) };
+                    return Ok(buf.len() - start);
+                }
+                // The read filled `spare` but there's more of the message left.
+                Err(ref e) if e.raw_os_error() == Some(c::ERROR_MORE_DATA as i32) => {
+                    let amt = spare.len();
+                    unsafe { buf.set_len(len + amt) };
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes `request` as one message and reads the reply message into
+    /// `response` with `TransactNamedPipe`, which is quicker than a separate
+    /// write and read.
+    ///
+    /// The reply is appended to `response`, which grows until the whole
+    /// message fits. Returns the length of the reply. This fails with
+    /// `InvalidInput` unless the pipe was created in message mode, and there
+    /// must be no unread messages waiting in the pipe.
+    #[allow(dead_code)]
+    pub fn transact(&self, request: &[u8], response: &mut Vec<u8>) -> io::Result<usize> {
+        if self.info()?.flags & c::PIPE_TYPE_MESSAGE == 0 {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "`TransactNamedPipe` requires a message-mode pipe",
+            ));
+        }
+        let request_len = c::DWORD::try_from(request.len()).map_err(|_| {
+            io::const_io_error!(io::ErrorKind::InvalidInput, "request does not fit in a message")
+        })?;
+
+        let start = response.len();
+        let spare = unsafe { slice_to_end(response) };
+        let spare_len = crate::cmp::min(spare.len(), c::DWORD::MAX as usize) as c::DWORD;
+        let result = unsafe {
+            match self {
+                Self::Sync(ref handle, _) => {
+                    let mut read = 0;
+                    cvt(c::TransactNamedPipe(
+                        handle.as_raw_handle(),
+                        request.as_ptr() as c::LPVOID,
+                        request_len,
+                        spare.as_mut_ptr() as c::LPVOID,
+                        spare_len,
+                        &mut read,
+                        ptr::null_mut(),
+                    ))
+                    .map(|_| read as usize)
+                }
+                // An overlapped handle needs an `OVERLAPPED`, even though we
+                // wait for the result right away.
+                Self::Async(ref handle, _) => {
+                    let event = Handle::new_event(true, false)?;
+                    let mut overlapped: c::OVERLAPPED = mem::zeroed();
+                    overlapped.hEvent = event.as_raw_handle();
+                    let res = cvt(c::TransactNamedPipe(
+                        handle.as_raw_handle(),
+                        request.as_ptr() as c::LPVOID,
+                        request_len,
+                        spare.as_mut_ptr() as c::LPVOID,
+                        spare_len,
+                        ptr::null_mut(),
+                        &mut overlapped,
+                    ));
+                    match res {
+                        Ok(_) => {}
+                        Err(ref e)
+                            if e.raw_os_error() == Some(c::ERROR_IO_PENDING as i32)
+                                || e.raw_os_error() == Some(c::ERROR_MORE_DATA as i32) => {}
+                        Err(e) => return Err(e),
+                    }
+                    handle.overlapped_result(&mut overlapped, true)
+                }
+            }
+        };
+
+        match result {
+            Ok(amt) => {
+                unsafe { response.set_len(start + amt) };
+                Ok(amt)
+            }
+            // The reply filled `spare`, so read the rest of it.
+            Err(ref e) if e.raw_os_error() == Some(c::ERROR_MORE_DATA as i32) => {
+                unsafe { response.set_len(start + spare_len as usize) };
+                self.read_message(response)?;
+                Ok(response.len() - start)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Queries the type and buffer sizes of the pipe.
+    #[allow(dead_code)]
+    pub fn info(&self) -> io::Result<NamedPipeInfo> {
+        self.handle().pipe_info()
+    }
+
+    /// Returns how much data is waiting in the pipe without reading it.
+    ///
+    /// This never blocks. Note that the result may be out of date as soon as
+    /// it's returned if another thread or process is using the pipe.
+    #[allow(dead_code)]
+    pub fn peek(&self) -> io::Result<PipeStatus> {
+        let mut bytes_available = 0;
+        let mut bytes_left_in_message = 0;
+        cvt(unsafe {
+            c::PeekNamedPipe(
+                self.handle().as_raw_handle(),
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                &mut bytes_available,
+                &mut bytes_left_in_message,
+            )
+        })?;
+        Ok(PipeStatus { bytes_available, bytes_left_in_message })
+    }
+
+    /// Returns whether the other end of the pipe is still open.
+    ///
+    /// `false` isn't an error, it just means the other end was closed (or,
+    /// for a named pipe server, that no client has connected yet) and reads
+    /// will see EOF. Windows only reports a pipe as disconnected once
+    /// everything written before the other end closed has been read, so
+    /// until then this still returns `true`.
+    ///
+    /// This uses `PeekNamedPipe`, which needs read access, so a write-only
+    /// end is always reported as connected; the first write after the
+    /// reader goes away fails instead.
+    #[allow(dead_code)]
+    pub fn is_connected(&self) -> bool {
+        match self.peek() {
+            Err(e) => !matches!(
+                e.raw_os_error().map(|code| code as c::DWORD),
+                Some(c::ERROR_BROKEN_PIPE | c::ERROR_PIPE_NOT_CONNECTED | c::ERROR_PIPE_LISTENING)
+            ),
+            Ok(_) => true,
+        }
+    }
+
+    /// Returns the total number of bytes waiting in the pipe, without
+    /// reading any of them.
+    ///
+    /// This is `peek().bytes_available` without asking for the size of the
+    /// current message, for when all that matters is whether there's
+    /// anything to read.
+    #[allow(dead_code)]
+    pub fn available_bytes(&self) -> io::Result<u32> {
+        let mut bytes_available = 0;
+        cvt(unsafe {
+            c::PeekNamedPipe(
+                self.handle().as_raw_handle(),
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                &mut bytes_available,
+                ptr::null_mut(),
+            )
+        })?;
+        Ok(bytes_available)
+    }
+
+    /// Returns the size of the next message in a message-mode pipe, so that
+    /// a buffer of exactly the right size can be allocated for it.
+    ///
+    /// If a message has been partly read already, this is the size of what's
+    /// left of it. `None` is returned for byte-mode pipes and when there are
+    /// no messages. `PeekNamedPipe` reports a pending zero-length message the
+    /// same way as no message at all, so that's `None` as well.
+    #[allow(dead_code)]
+    pub fn next_message_size(&self) -> io::Result<Option<u32>> {
+        let size = self.peek()?.bytes_left_in_message;
+        Ok(if size == 0 { None } else { Some(size) })
+    }
+
+    /// Returns the number of bytes that can be read from the pipe without
+    /// blocking.
+    ///
+    /// Zero means that nothing has been written yet, not EOF. This is the
+    /// same as `available_bytes`.
+    #[allow(dead_code)]
+    pub fn poll_read(&self) -> io::Result<usize> {
+        Ok(self.available_bytes()? as usize)
+    }
+
+    /// Reads whatever is waiting in the pipe, failing with `WouldBlock`
+    /// instead of blocking if nothing is.
+    ///
+    /// Unlike `set_non_blocking` this doesn't change the pipe's mode, so it
+    /// doesn't affect anyone else using the pipe. It checks for data with
+    /// `PeekNamedPipe` before reading though, so if another reader takes
+    /// the data in between the read blocks anyway. Only use this when
+    /// nothing else reads from the pipe.
+    #[allow(dead_code)]
+    pub fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.poll_read() {
+            Ok(0) => Err(io::const_io_error!(
+                io::ErrorKind::WouldBlock,
+                "no data is available in the pipe",
+            )),
+            Ok(_) => self.read(buf),
+            // The writing end has been closed.
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn read_vectored(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        match self {
+            Self::Sync(..) => io::default_read_vectored(|buf| self.read(buf), bufs),
+            Self::Async(..) => self.read_vectored_true(bufs),
+        }
+    }
+
+    #[inline]
+    pub fn is_read_vectored(&self) -> bool {
+        match self {
+            Self::Sync(..) => false,
+            Self::Async(..) => true,
+        }
+    }
+
+    /// Reads into each of `bufs` in turn, going on to the next one once a
+    /// buffer is full if the pipe still has data waiting.
+    ///
+    /// Pipes can't do a scatter read with `ReadFileScatter`, so this is one
+    /// read per buffer. Only the first read can block: if it fills its
+    /// buffer, `PeekNamedPipe` is asked once how much more is waiting, and
+    /// that much is spread across the rest. So like `read` this returns as
+    /// soon as there's any data, unless another reader takes what was
+    /// peeked first.
+    pub fn read_vectored_true(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let mut bufs = bufs.iter_mut().filter(|buf| !buf.is_empty());
+        let first = match bufs.next() {
+            Some(buf) => buf,
+            None => return Ok(0),
+        };
+        let mut total = self.read(first)?;
+        if total < first.len() {
+            return Ok(total);
+        }
+        let mut available = self.available_bytes().unwrap_or(0) as usize;
+        for buf in bufs {
+            if available == 0 {
+                break;
+            }
+            let len = crate::cmp::min(buf.len(), available);
+            let n = self.read(&mut buf[..len])?;
+            total += n;
+            available = if n < len { 0 } else { available - n };
+        }
+        Ok(total)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(timeout) = self.write_timeout() {
+            return self.write_with_timeout(buf, timeout);
+        }
+        unsafe {
+            let len = crate::cmp::min(buf.len(), c::DWORD::MAX as usize) as c::DWORD;
+            match self {
+                Self::Sync(ref handle, _) => handle.write(buf),
+                Self::Async(..) => {
+                    self.alertable_io_internal(c::WriteFileEx, buf.as_ptr() as _, len)
+                }
+            }
+        }
+    }
+
+    // `set_write_timeout` only allows this for asynchronous pipes.
+    fn write_with_timeout(&self, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        let mut overlapped: c::OVERLAPPED = unsafe { mem::zeroed() };
+        self.handle()
+            .write_overlapped_timeout(buf, &mut overlapped, timeout)?
+            .ok_or_else(|| io::const_io_error!(io::ErrorKind::TimedOut, "pipe write timed out"))
+    }
+
+    /// Writes as much of `buf` as fits in the pipe right now, failing with
+    /// `WouldBlock` instead of blocking if nothing does.
+    ///
+    /// A synchronous pipe is switched to `PIPE_NOWAIT` for the write and back
+    /// to `PIPE_WAIT` afterwards. The mode belongs to the handle, not the
+    /// call, so anything using the same handle on another thread in the
+    /// meantime won't block either. Don't use this on a pipe that's shared
+    /// between threads.
+    ///
+    /// An asynchronous pipe starts an overlapped write and cancels it if it
+    /// doesn't complete immediately.
+    #[allow(dead_code)]
+    pub fn try_write(&self, buf: &[u8]) -> io::Result<usize> {
+        let would_block =
+            || io::const_io_error!(io::ErrorKind::WouldBlock, "no space is available in the pipe");
+        match self {
+            Self::Sync(ref handle, _) => {
+                handle.set_pipe_wait_mode(PipeWaitMode::NoWait)?;
+                let res = handle.write(buf);
+                // Whatever was written can't be taken back, so it's reported
+                // even if the mode can't be restored. Only a failed write
+                // reports that error instead.
+                let restored = handle.set_pipe_wait_mode(PipeWaitMode::Wait);
+                match res {
+                    Ok(0) if !buf.is_empty() => restored.and(Err(would_block())),
+                    Ok(amt) => Ok(amt),
+                    Err(e) => restored.and(Err(e)),
+                }
+            }
+            Self::Async(ref handle, _) => {
+                let mut overlapped: c::OVERLAPPED = unsafe { mem::zeroed() };
+                handle
+                    .write_overlapped_timeout(buf, &mut overlapped, Duration::ZERO)?
+                    .ok_or_else(would_block)
+            }
+        }
+    }
+
+    /// Copies everything from this pipe to `dst` until EOF, returning the
+    /// number of bytes copied.
+    ///
+    /// This blocks the calling thread until it's done. If both pipes are
+    /// asynchronous the next read is started while the previous chunk is
+    /// still being written, alternating between two buffers, so that neither
+    /// pipe has to wait for the other. Otherwise this is a plain read and
+    /// write loop.
+    #[allow(dead_code)]
+    pub fn copy_to(&self, dst: &AnonPipe) -> io::Result<u64> {
+        match (self, dst) {
+            (Self::Async(src, _), Self::Async(dst, _)) => unsafe { copy_overlapped(src, dst) },
+            _ => relay(self, dst, &mut vec![0_u8; PIPE_BUFFER_CAPACITY as usize]),
+        }
+    }
+
+    /// Writes all of `buf`, failing with `WriteZero` if the pipe stops
+    /// accepting data first.
+    pub fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf) {
+                Ok(0) => {
+                    return Err(io::const_io_error!(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ));
+                }
+                Ok(n) => buf = &buf[n..],
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes from several buffers at once.
+    ///
+    /// For asynchronous pipes this tries `WriteFileGather` first, but that
+    /// only takes buffers made of whole pages, each starting on a page
+    /// boundary (and not every kind of handle supports it). Otherwise up to
+    /// `PIPE_BUFFER_CAPACITY` bytes of the buffers are copied into one so
+    /// that they still go out in a single write. A first buffer that big is
+    /// written on its own without copying it.
+    pub fn write_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        const CAP: usize = PIPE_BUFFER_CAPACITY as usize;
+        match self {
+            Self::Sync(..) => io::default_write_vectored(|buf| self.write(buf), bufs),
+            Self::Async(ref handle, _) => {
+                if let Some(amt) = unsafe { write_gather(handle, bufs)? } {
+                    return Ok(amt);
+                }
+                let first = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &**buf);
+                if first.len() >= CAP {
+                    return self.write(first);
+                }
+                let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+                let mut joined = Vec::with_capacity(len.min(CAP));
+                for buf in bufs {
+                    let take = buf.len().min(CAP - joined.len());
+                    joined.extend_from_slice(&buf[..take]);
+                    if joined.len() == CAP {
+                        break;
+                    }
+                }
+                self.write(&joined)
+            }
+        }
+    }
+
+    #[inline]
+    pub fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Sync(..) => false,
+            Self::Async(..) => true,
+        }
+    }
+
+    /// Like `write_all`, but writes all of `bufs` with `write_vectored`.
+    ///
+    /// What's left is tracked in a copy of `bufs`, whose first slice is
+    /// shortened after a partial write.
+    #[allow(dead_code)]
+    pub fn write_all_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<()> {
+        let mut remaining = bufs.to_vec();
+        let mut bufs = &mut remaining[..];
+        // Skip empty slices, so that nothing's written if there's no data.
+        IoSlice::advance_slices(&mut bufs, 0);
+        while !bufs.is_empty() {
+            match self.write_vectored(bufs) {
+                Ok(0) => {
+                    return Err(io::const_io_error!(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ));
+                }
+                Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocks until all data written to the pipe has been read by the other
+    /// end, using `FlushFileBuffers`.
+    ///
+    /// Writes to an asynchronous pipe have always completed by the time
+    /// `write` returns, so there's never an overlapped write left to wait for.
+    /// If nothing is waiting in the pipe this returns `Ok(())` immediately.
+    #[allow(dead_code)]
+    pub fn flush(&self) -> io::Result<()> {
+        cvt(unsafe { c::FlushFileBuffers(self.handle().as_raw_handle()) })?;
+        Ok(())
+    }
+
+    /// Synchronizes asynchronous reads or writes using our anonymous pipe.
+    ///
+    /// This is a wrapper around [`ReadFileEx`] or [`WriteFileEx`] that uses
+    /// [Asynchronous Procedure Call] (APC) to synchronize reads or writes.
+    ///
+    /// Note: This should not be used for handles we don't create.
+    ///
+    /// # Safety
+    ///
     /// `buf` must be a pointer to a buffer that's valid for reads or writes
     /// up to `len` bytes. The `AlertableIoFn` must be either `ReadFileEx` or `WriteFileEx`
     ///
-    /// [`ReadFileEx`]: https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-readfileex
-    /// [`WriteFileEx`]: https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-writefileex
-    /// [Asynchronous Procedure Call]: https://docs.microsoft.com/en-us/windows/win32/sync/asynchronous-procedure-calls
-    unsafe fn alertable_io_internal(
-        &self,
-        io: AlertableIoFn,
-        buf: c::LPVOID,
-        len: c::DWORD,
-    ) -> io::Result<usize> {
-        // Use "alertable I/O" to synchronize the pipe I/O.
-        // This has four steps.
+    /// [`ReadFileEx`]: https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-readfileex
+    /// [`WriteFileEx`]: https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-writefileex
+    /// [Asynchronous Procedure Call]: https://docs.microsoft.com/en-us/windows/win32/sync/asynchronous-procedure-calls
+    unsafe fn alertable_io_internal(
+        &self,
+        io: AlertableIoFn,
+        buf: c::LPVOID,
+        len: c::DWORD,
+    ) -> io::Result<usize> {
+        // Use "alertable I/O" to synchronize the pipe I/O.
+        // This has four steps.
+        //
+        // STEP 1: Start the asynchronous I/O operation.
+        //         This simply calls either `ReadFileEx` or `WriteFileEx`,
+        //         giving it a pointer to the buffer and callback function.
+        //
+        // STEP 2: Enter an alertable state.
+        //         The callback set in step 1 will not be called until the thread
+        //         enters an "alertable" state. This can be done using `SleepEx`.
+        //
+        // STEP 3: The callback
+        //         Once the I/O is complete and the thread is in an alertable state,
+        //         the callback will be run on the same thread as the call to
+        //         `ReadFileEx` or `WriteFileEx` done in step 1.
+        //         In the callback we simply set the result of the async operation.
+        //
+        // STEP 4: Return the result.
+        //         At this point we'll have a result from the callback function
+        //         and can simply return it. Note that we must not return earlier,
+        //         while the I/O is still in progress.
+
+        // The result that will be set from the asynchronous callback.
+        let mut async_result: Option<AsyncResult> = None;
+        struct AsyncResult {
+            error: u32,
+            transfered: u32,
+        }
+
+        // STEP 3: The callback.
+        unsafe extern "system" fn callback(
+            dwErrorCode: u32,
+            dwNumberOfBytesTransfered: u32,
+            lpOverlapped: *mut c::OVERLAPPED,
+        ) {
+            // Set `async_result` using a pointer smuggled through `hEvent`.
+            let result = AsyncResult { error: dwErrorCode, transfered: dwNumberOfBytesTransfered };
+            *(*lpOverlapped).hEvent.cast::<Option<AsyncResult>>() = Some(result);
+        }
+
+        // STEP 1: Start the I/O operation.
+        let mut overlapped: c::OVERLAPPED = crate::mem::zeroed();
+        // `hEvent` is unused by `ReadFileEx` and `WriteFileEx`.
+        // Therefore the documentation suggests using it to smuggle a pointer to the callback.
+        overlapped.hEvent = &mut async_result as *mut _ as *mut _;
+
+        // Asynchronous read of the pipe.
+        // If successful, `callback` will be called once it completes.
+        let result = io(self.handle().as_handle(), buf, len, &mut overlapped, callback);
+        if result == c::FALSE {
+            // We can return here because the call failed.
+            // After this we must not return until the I/O completes.
+            return Err(io::Error::last_os_error());
+        }
+
+        // Wait indefinitely for the result.
+        //
+        // Even if the I/O completed synchronously, `callback` has still been
+        // queued as an APC rather than run, so the result is never known
+        // without entering an alertable state. Taking the result some other
+        // way (such as from `GetOverlappedResult`) and returning would leave
+        // that APC to write to `async_result` after this frame is gone.
+        let result = loop {
+            // STEP 2: Enter an alertable state.
+            // The second parameter of `SleepEx` is used to make this sleep alertable.
+            c::SleepEx(c::INFINITE, c::TRUE);
+            if let Some(result) = async_result {
+                break result;
+            }
+        };
+        // STEP 4: Return the result.
+        // `async_result` is always `Some` at this point
+        match result.error {
+            c::ERROR_SUCCESS => Ok(result.transfered as usize),
+            error => Err(io::Error::from_raw_os_error(error as _)),
+        }
+    }
+}
+
+// Each of these methods already handles both synchronous and asynchronous
+// pipes, so the traits only have to forward to them.
+impl io::Read for AnonPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        AnonPipe::read(self, buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        AnonPipe::read_vectored(self, bufs)
+    }
+
+    #[inline]
+    fn is_read_vectored(&self) -> bool {
+        AnonPipe::is_read_vectored(self)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        AnonPipe::read_to_end(self, buf)
+    }
+}
+
+impl io::Write for AnonPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        AnonPipe::write(self, buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        AnonPipe::write_vectored(self, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        AnonPipe::is_write_vectored(self)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        AnonPipe::flush(self)
+    }
+}
+
+/// The reading end of a pipe, returned by `AnonPipe::into_reader`.
+///
+/// Duplex pipes can be turned into either.
+pub struct PipeReader(AnonPipe);
+
+/// The writing end of a pipe, returned by `AnonPipe::into_writer`.
+pub struct PipeWriter(AnonPipe);
+
+#[allow(dead_code)]
+impl PipeReader {
+    pub fn into_anon_pipe(self) -> AnonPipe {
+        self.0
+    }
+}
+
+#[allow(dead_code)]
+impl PipeWriter {
+    pub fn into_anon_pipe(self) -> AnonPipe {
+        self.0
+    }
+}
+
+impl io::Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.0.read_vectored(bufs)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.0.read_to_end(buf)
+    }
+}
+
+impl io::Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.0.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// The upper bounds of the buckets in `PipeMetrics::read_latency_ns`, in
+/// nanoseconds. Anything slower goes in the last bucket.
+const LATENCY_BUCKETS_NS: [u64; 7] =
+    [1_000, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000, 1_000_000_000];
+
+/// Counts kept by a `MeteredPipe`.
+///
+/// Each count is updated on its own with `Relaxed` ordering, so a snapshot
+/// taken while the pipe is in use may be a call or two apart between them.
+#[derive(Default)]
+pub struct PipeMetrics {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// A histogram of how long successful reads took, with a bucket for each
+    /// bound in `LATENCY_BUCKETS_NS` and one for anything slower.
+    read_latency_ns: [AtomicU64; 8],
+}
+
+#[allow(dead_code)]
+impl PipeMetrics {
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Relaxed)
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Relaxed)
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Relaxed)
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Relaxed)
+    }
+
+    /// How many reads fell into each latency bucket.
+    pub fn read_latency_ns(&self) -> [u64; 8] {
+        let mut histogram = [0; 8];
+        for (count, bucket) in histogram.iter_mut().zip(&self.read_latency_ns) {
+            *count = bucket.load(Relaxed);
+        }
+        histogram
+    }
+
+    /// The upper bound of the bucket that the `percent`th percentile read
+    /// latency falls in, or `None` if there haven't been any reads.
+    ///
+    /// Reads slower than a second are all reported as `Duration::MAX`.
+    pub fn read_latency_percentile(&self, percent: u8) -> Option<Duration> {
+        let histogram = self.read_latency_ns();
+        let total: u64 = histogram.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        // The rank of the read we're after, rounding up so that 0 is the
+        // fastest read and 100 the slowest.
+        let rank = (total * u64::from(percent.min(100)) + 99) / 100;
+        let mut seen = 0;
+        for (i, &count) in histogram.iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return Some(match LATENCY_BUCKETS_NS.get(i) {
+                    Some(&ns) => Duration::from_nanos(ns),
+                    None => Duration::MAX,
+                });
+            }
+        }
+        Some(Duration::MAX)
+    }
+
+    fn record_read(&self, amt: usize, elapsed: Duration) {
+        let ns = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_NS.iter().position(|&max| ns < max).unwrap_or(7);
+        self.read_latency_ns[bucket].fetch_add(1, Relaxed);
+        self.reads.fetch_add(1, Relaxed);
+        self.bytes_read.fetch_add(amt as u64, Relaxed);
+    }
+
+    fn record_write(&self, amt: usize) {
+        self.writes.fetch_add(1, Relaxed);
+        self.bytes_written.fetch_add(amt as u64, Relaxed);
+    }
+}
+
+/// An `AnonPipe` that keeps `PipeMetrics` about its reads and writes.
+///
+/// Only successful calls are counted. Timing a read takes two `Instant::now`
+/// calls, and nothing is done that needs a system call of its own.
+pub struct MeteredPipe(AnonPipe, Arc<PipeMetrics>);
+
+#[allow(dead_code)]
+impl MeteredPipe {
+    pub fn new(pipe: AnonPipe) -> MeteredPipe {
+        MeteredPipe(pipe, Arc::default())
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let amt = self.0.read(buf)?;
+        self.1.record_read(amt, start.elapsed());
+        Ok(amt)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let amt = self.0.write(buf)?;
+        self.1.record_write(amt);
+        Ok(amt)
+    }
+
+    /// The metrics for this pipe, which keep being updated as it's used.
+    pub fn metrics(&self) -> Arc<PipeMetrics> {
+        self.1.clone()
+    }
+
+    pub fn into_inner(self) -> AnonPipe {
+        self.0
+    }
+}
+
+/// Writes `bufs` to the overlapped `handle` with `WriteFileGather`, waiting
+/// for the write to complete.
+///
+/// Returns `None` without writing anything if the buffers aren't suitable for
+/// `WriteFileGather` or the handle doesn't support it.
+unsafe fn write_gather(handle: &Handle, bufs: &[IoSlice<'_>]) -> io::Result<Option<usize>> {
+    let page_size = page_size();
+    let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+    let aligned =
+        bufs.iter().all(|buf| buf.as_ptr() as usize % page_size == 0 && buf.len() % page_size == 0);
+    if len == 0 || !aligned || len > c::DWORD::MAX as usize {
+        return Ok(None);
+    }
+
+    // One element per page, followed by a null terminator.
+    let mut segments = Vec::with_capacity(len / page_size + 1);
+    for buf in bufs {
+        for page in buf.chunks(page_size) {
+            segments.push(c::FILE_SEGMENT_ELEMENT { Alignment: page.as_ptr() as usize as u64 });
+        }
+    }
+    segments.push(c::FILE_SEGMENT_ELEMENT { Alignment: 0 });
+
+    let event = Handle::new_event(true, false)?;
+    let mut overlapped: c::OVERLAPPED = mem::zeroed();
+    overlapped.hEvent = event.as_raw_handle();
+    let res = cvt(c::WriteFileGather(
+        handle.as_raw_handle(),
+        segments.as_mut_ptr(),
+        len as c::DWORD,
+        ptr::null_mut(),
+        &mut overlapped,
+    ));
+    match res {
+        Ok(_) => {}
+        Err(ref e) if e.raw_os_error() == Some(c::ERROR_IO_PENDING as i32) => {}
+        Err(ref e)
+            if e.raw_os_error() == Some(c::ERROR_INVALID_FUNCTION as i32)
+                || e.raw_os_error() == Some(c::ERROR_INVALID_PARAMETER as i32)
+                || e.raw_os_error() == Some(c::ERROR_NOT_SUPPORTED as i32) =>
+        {
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    }
+
+    let mut written = 0;
+    cvt(c::GetOverlappedResult(handle.as_raw_handle(), &mut overlapped, &mut written, c::TRUE))?;
+    Ok(Some(written as usize))
+}
+
+fn page_size() -> usize {
+    unsafe {
+        let mut info: c::SYSTEM_INFO = mem::zeroed();
+        c::GetSystemInfo(&mut info);
+        info.dwPageSize as usize
+    }
+}
+
+pub fn read2(p1: AnonPipe, v1: &mut Vec<u8>, p2: AnonPipe, v2: &mut Vec<u8>) -> io::Result<()> {
+    read2_select(p1, v1, p2, v2).map(drop)
+}
+
+/// Which of the pipes passed to `read2_select` produced data first.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SelectResult {
+    /// `p1` had data before `p2` did.
+    First,
+    /// `p2` had data before `p1` did.
+    Second,
+    /// Data from both pipes turned up in the same wait.
+    Both,
+    /// Both pipes reached EOF without producing any data.
+    Neither,
+}
+
+impl SelectResult {
+    fn from_data(first: bool, second: bool) -> SelectResult {
+        match (first, second) {
+            (true, false) => SelectResult::First,
+            (false, true) => SelectResult::Second,
+            (true, true) => SelectResult::Both,
+            (false, false) => SelectResult::Neither,
+        }
+    }
+}
+
+/// Like `read2`, but also reports which pipe produced data first.
+///
+/// Reads of both pipes are started before waiting for either, so if both
+/// already have data waiting the result is `Both`.
+pub fn read2_select(
+    p1: AnonPipe,
+    v1: &mut Vec<u8>,
+    p2: AnonPipe,
+    v2: &mut Vec<u8>,
+) -> io::Result<SelectResult> {
+    let p1 = AsyncPipe::new(p1.into_handle(), v1, usize::MAX)?;
+    let p2 = AsyncPipe::new(p2.into_handle(), v2, usize::MAX)?;
+    read2_async(p1, p2)
+}
+
+/// Like `read2`, but first reserves room for at least `cap1` more bytes in
+/// `v1` and `cap2` more bytes in `v2`.
+///
+/// When the size of the output is roughly known up front this avoids
+/// reallocating the vectors over and over as they fill up.
+pub fn read2_with_capacity(
+    p1: AnonPipe,
+    v1: &mut Vec<u8>,
+    cap1: usize,
+    p2: AnonPipe,
+    v2: &mut Vec<u8>,
+    cap2: usize,
+) -> io::Result<()> {
+    v1.reserve(cap1);
+    v2.reserve(cap2);
+    read2_with_limit(p1, v1, usize::MAX, p2, v2, usize::MAX)
+}
+
+/// Like `read2`, but fails with `OutOfMemory` as soon as more than `limit1`
+/// bytes are read from `p1` or more than `limit2` bytes from `p2`.
+///
+/// Reading stops at that point, so at most one byte more than the limit is
+/// stored in `v1` or `v2`.
+pub fn read2_with_limit(
+    p1: AnonPipe,
+    v1: &mut Vec<u8>,
+    limit1: usize,
+    p2: AnonPipe,
+    v2: &mut Vec<u8>,
+    limit2: usize,
+) -> io::Result<()> {
+    let p1 = AsyncPipe::new(p1.into_handle(), v1, limit1)?;
+    let p2 = AsyncPipe::new(p2.into_handle(), v2, limit2)?;
+    read2_async(p1, p2).map(drop)
+}
+
+/// Like `read2`, but writes the data read from `p1` to `w1` and from `p2` to
+/// `w2` as it arrives instead of collecting it in memory.
+///
+/// If writing to either writer fails, reading stops and that error is
+/// returned.
+#[allow(dead_code)]
+pub fn read2_to_writers(
+    p1: AnonPipe,
+    w1: &mut dyn io::Write,
+    p2: AnonPipe,
+    w2: &mut dyn io::Write,
+) -> io::Result<()> {
+    let p1 = AsyncPipe::with_writer(p1.into_handle(), w1)?;
+    let p2 = AsyncPipe::with_writer(p2.into_handle(), w2)?;
+    read2_async(p1, p2).map(drop)
+}
+
+/// Like `read2`, but appends the output of `p1` to `s1` and of `p2` to `s2`,
+/// failing with `InvalidData` if either isn't valid UTF-8.
+///
+/// A code point split across two reads is held back until the rest of it
+/// arrives, so only output that is invalid as a whole is rejected.
+#[allow(dead_code)]
+pub fn read2_strings(
+    p1: AnonPipe,
+    s1: &mut String,
+    p2: AnonPipe,
+    s2: &mut String,
+) -> io::Result<()> {
+    let p1 = AsyncPipe::with_string(p1.into_handle(), s1)?;
+    let p2 = AsyncPipe::with_string(p2.into_handle(), s2)?;
+    read2_async(p1, p2).map(drop)
+}
+
+/// Like `read2`, but calls `on1` or `on2` with each line read from `p1` or
+/// `p2` as soon as it's complete, rather than collecting the output.
+///
+/// Lines are passed with their trailing `\n`. If a pipe's output doesn't end
+/// with a newline, the last line is passed without one once the pipe reaches
+/// EOF.
+#[allow(dead_code)]
+pub fn read2_lines(
+    p1: AnonPipe,
+    on1: &mut dyn FnMut(&[u8]),
+    p2: AnonPipe,
+    on2: &mut dyn FnMut(&[u8]),
+) -> io::Result<()> {
+    let mut w1 = LineSplitter { partial: Vec::new(), on_line: on1 };
+    let mut w2 = LineSplitter { partial: Vec::new(), on_line: on2 };
+    read2_to_writers(p1, &mut w1, p2, &mut w2)?;
+    w1.finish();
+    w2.finish();
+    Ok(())
+}
+
+/// Calls `on_line` with each complete line written to it.
+struct LineSplitter<'a> {
+    /// The start of a line that hasn't been finished yet.
+    partial: Vec<u8>,
+    on_line: &'a mut dyn FnMut(&[u8]),
+}
+
+impl LineSplitter<'_> {
+    /// Passes on whatever is left over as the last line.
+    fn finish(&mut self) {
+        if !self.partial.is_empty() {
+            (self.on_line)(&self.partial);
+            self.partial.clear();
+        }
+    }
+}
+
+impl io::Write for LineSplitter<'_> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while let Some(i) = memchr::memchr(b'\n', buf) {
+            let (line, rest) = buf.split_at(i + 1);
+            if self.partial.is_empty() {
+                (self.on_line)(line);
+            } else {
+                self.partial.extend_from_slice(line);
+                (self.on_line)(&self.partial);
+                self.partial.clear();
+            }
+            buf = rest;
+        }
+        self.partial.extend_from_slice(buf);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Like `read2`, but waits for the reads to complete with an I/O completion
+/// port instead of `WaitForMultipleObjects`.
+///
+/// Once associated with a completion port a handle stays associated with it
+/// until it's closed, which is fine here as both pipes are closed on return.
+#[allow(dead_code)]
+pub fn read2_iocp(
+    p1: AnonPipe,
+    v1: &mut Vec<u8>,
+    p2: AnonPipe,
+    v2: &mut Vec<u8>,
+) -> io::Result<()> {
+    let port = unsafe { c::CreateIoCompletionPort(c::INVALID_HANDLE_VALUE, ptr::null_mut(), 0, 1) };
+    if port.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let port = unsafe { Handle::from_raw_handle(port) };
+
+    // `pipes` is declared after `port` so that any pending reads are
+    // cancelled before the port is closed.
+    let mut pipes = [
+        AsyncPipe::new(p1.into_handle(), v1, usize::MAX)?,
+        AsyncPipe::new(p2.into_handle(), v2, usize::MAX)?,
+    ];
+    let mut reading = [false; 2];
+    for (key, pipe) in pipes.iter_mut().enumerate() {
+        let res = unsafe {
+            c::CreateIoCompletionPort(pipe.pipe.as_raw_handle(), port.as_raw_handle(), key, 0)
+        };
+        if res.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        reading[key] = pipe.schedule_read()?;
+    }
+
+    // Every read that gets scheduled posts a completion packet, even if it
+    // finished straight away, so a pipe is only touched again once its
+    // packet has arrived. The packet carries the pipe's index as its key.
+    while reading.iter().any(|&r| r) {
+        let mut amt = 0;
+        let mut key = 0;
+        let mut overlapped = ptr::null_mut();
+        unsafe {
+            c::GetQueuedCompletionStatus(
+                port.as_raw_handle(),
+                &mut amt,
+                &mut key,
+                &mut overlapped,
+                c::INFINITE,
+            );
+        }
+        if overlapped.is_null() {
+            // Waiting failed, rather than one of the reads.
+            return Err(io::Error::last_os_error());
+        }
+
+        // A read that succeeded without returning any data still posts a
+        // packet after we've stopped reading that pipe, so ignore those.
+        if !reading[key] || overlapped != &mut *pipes[key].overlapped as *mut c::OVERLAPPED {
+            continue;
+        }
+        // `result` picks up how the read went from the `OVERLAPPED`, without
+        // blocking now that it's complete.
+        let pipe = &mut pipes[key];
+        reading[key] = pipe.result()? && pipe.schedule_read()?;
+    }
+    Ok(())
+}
+
+/// Like `read2`, but reads with `ReadFileEx` and completion routines, the way
+/// `alertable_io_internal` does, rather than waiting on events.
+///
+/// Both pipes have a read outstanding until they reach EOF, and in between
+/// this thread sleeps in an alertable state until a completion routine has run
+/// for at least one of them.
+#[allow(dead_code)]
+pub fn read2_apc(p1: AnonPipe, v1: &mut Vec<u8>, p2: AnonPipe, v2: &mut Vec<u8>) -> io::Result<()> {
+    let mut pipes = [ApcPipe::new(p1.into_handle(), v1), ApcPipe::new(p2.into_handle(), v2)];
+    loop {
+        for pipe in pipes.iter_mut() {
+            pipe.collect()?;
+            if !pipe.reading && !pipe.eof {
+                pipe.schedule_read()?;
+            }
+        }
+        if !pipes.iter().any(|p| p.reading) {
+            return Ok(());
+        }
+        // Completion routines only run while the thread is alertable, and
+        // `SleepEx` returns once any of them have.
+        unsafe { c::SleepEx(c::INFINITE, c::TRUE) };
+    }
+}
+
+fn read2_async(mut p1: AsyncPipe<'_>, mut p2: AsyncPipe<'_>) -> io::Result<SelectResult> {
+    let objs = [p1.event.as_raw_handle(), p2.event.as_raw_handle()];
+
+    // In a loop we wait for either pipe's scheduled read operation to complete.
+    // If the operation completes with 0 bytes, that means EOF was reached, in
+    // which case we just finish out the other pipe entirely.
+    //
+    // Both reads are scheduled before the first wait, so that if both pipes
+    // already have data it's picked up in the same round.
+    //
+    // Note that overlapped I/O is in general super unsafe because we have to
+    // be careful to ensure that all pointers in play are valid for the entire
+    // duration of the I/O operation (where tons of operations can also fail).
+    // The destructor for `AsyncPipe` ends up taking care of most of this.
+    let mut select = SelectResult::Neither;
+    let mut p1_done = !p1.schedule_read()?;
+    let mut p2_done = !p1_done && !p2.schedule_read()?;
+    while !p1_done && !p2_done {
+        let res = unsafe { c::WaitForMultipleObjects(2, objs.as_ptr(), c::FALSE, c::INFINITE) };
+        let ready = res.wrapping_sub(c::WAIT_OBJECT_0);
+        if ready > 1 {
+            return Err(io::Error::last_os_error());
+        }
+        if select == SelectResult::Neither {
+            select = SelectResult::from_data(p1.has_data()?, p2.has_data()?);
+        }
+        if ready == 0 {
+            p1_done = !p1.result()? || !p1.schedule_read()?;
+        } else {
+            p2_done = !p2.result()? || !p2.schedule_read()?;
+        }
+    }
+
+    // If nothing has been read yet, whatever the other pipe reads now is
+    // the first data.
+    if p1_done {
+        p2.finish()?;
+        if select == SelectResult::Neither && p2.read_any {
+            select = SelectResult::Second;
+        }
+    } else {
+        p1.finish()?;
+        if select == SelectResult::Neither && p1.read_any {
+            select = SelectResult::First;
+        }
+    }
+    Ok(select)
+}
+
+/// Reads each pipe to EOF into the vector it's paired with, like `read2` but
+/// for any number of pipes up to `MAXIMUM_WAIT_OBJECTS` (64).
+#[allow(dead_code)]
+pub fn read_all_pipes(pairs: Vec<(AnonPipe, &mut Vec<u8>)>) -> io::Result<()> {
+    if pairs.len() > c::MAXIMUM_WAIT_OBJECTS as usize {
+        return Err(io::const_io_error!(
+            io::ErrorKind::InvalidInput,
+            "cannot wait on more than 64 pipes at once",
+        ));
+    }
+    let pipes = pairs
+        .into_iter()
+        .map(|(pipe, dst)| AsyncPipe::new(pipe.into_handle(), dst, usize::MAX))
+        .collect::<io::Result<Vec<_>>>()?;
+    read_until_eof(pipes, None)
+}
+
+/// Like `read2`, but fails with `TimedOut` if both pipes haven't reached EOF
+/// within `timeout`.
+///
+/// On timeout both pipes are closed and whatever was read up to that point
+/// is left in `v1` and `v2`.
+#[allow(dead_code)]
+pub fn read2_timeout(
+    p1: AnonPipe,
+    v1: &mut Vec<u8>,
+    p2: AnonPipe,
+    v2: &mut Vec<u8>,
+    timeout: Duration,
+) -> io::Result<()> {
+    let deadline = Instant::now().checked_add(timeout);
+    let p1 = AsyncPipe::new(p1.into_handle(), v1, usize::MAX)?;
+    let p2 = AsyncPipe::new(p2.into_handle(), v2, usize::MAX)?;
+    read_until_eof(vec![p1, p2], deadline)
+}
+
+/// Drives reads on all of `pipes` until each has reached EOF, or until
+/// `deadline` passes if there is one.
+///
+/// This works the same as `read2`, except that pipes are removed once they
+/// reach EOF and we keep going until there are none left.
+fn read_until_eof(mut pipes: Vec<AsyncPipe<'_>>, deadline: Option<Instant>) -> io::Result<()> {
+    // `objs` is kept in the same order as `pipes`.
+    let mut objs = pipes.iter().map(|p| p.event.as_raw_handle()).collect::<Vec<_>>();
+    while !pipes.is_empty() {
+        let timeout = match deadline {
+            Some(deadline) => dur2timeout(deadline.saturating_duration_since(Instant::now())),
+            None => c::INFINITE,
+        };
+        let res = unsafe {
+            c::WaitForMultipleObjects(objs.len() as c::DWORD, objs.as_ptr(), c::FALSE, timeout)
+        };
+        if res == c::WAIT_TIMEOUT {
+            // Dropping `pipes` cancels any pending reads and closes the pipes.
+            return Err(io::const_io_error!(
+                io::ErrorKind::TimedOut,
+                "timed out reading from pipes",
+            ));
+        }
+        let i = res.wrapping_sub(c::WAIT_OBJECT_0) as usize;
+        if i >= pipes.len() {
+            return Err(io::Error::last_os_error());
+        }
+        if !pipes[i].result()? || !pipes[i].schedule_read()? {
+            pipes.swap_remove(i);
+            objs.swap_remove(i);
+        }
+    }
+    Ok(())
+}
+
+struct AsyncPipe<'a> {
+    pipe: Handle,
+    event: Handle,
+    overlapped: Box<c::OVERLAPPED>, // needs a stable address
+    dst: Sink<'a>,
+    limit: usize,
+    state: State,
+    /// Whether any read so far has returned data.
+    read_any: bool,
+}
+
+/// Where the data read by an `AsyncPipe` ends up.
+enum Sink<'a> {
+    /// Appended to the vector.
+    Vec(&'a mut Vec<u8>),
+    /// Read into `buf` and then written to `writer` after each read.
+    Writer { buf: Vec<u8>, writer: &'a mut dyn io::Write },
+    /// Read into `buf` and then appended to `dst` once validated as UTF-8.
+    /// Anything left in `buf` is the start of a code point cut off by the
+    /// end of the last read.
+    Utf8 { buf: Vec<u8>, dst: &'a mut String },
+}
+
+impl Sink<'_> {
+    /// The buffer that reads are done into.
+    fn buf(&mut self) -> &mut Vec<u8> {
+        match self {
+            Sink::Vec(dst) => dst,
+            Sink::Writer { buf, .. } => buf,
+            Sink::Utf8 { buf, .. } => buf,
+        }
+    }
+}
+
+/// Moves as much of `buf` into `dst` as is valid UTF-8.
+///
+/// Unless `eof` is set, an incomplete code point at the end of `buf` is left
+/// in it for the next read to finish.
+fn append_utf8(buf: &mut Vec<u8>, dst: &mut String, eof: bool) -> io::Result<()> {
+    let valid = match str::from_utf8(buf) {
+        Ok(s) => s.len(),
+        Err(e) if e.error_len().is_none() && !eof => e.valid_up_to(),
+        Err(_) => {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidData,
+                "pipe output was not valid UTF-8",
+            ));
+        }
+    };
+    // SAFETY: `from_utf8` just checked that the first `valid` bytes are UTF-8.
+    dst.push_str(unsafe { str::from_utf8_unchecked(&buf[..valid]) });
+    buf.drain(..valid);
+    Ok(())
+}
+
+#[derive(PartialEq, Debug)]
+enum State {
+    NotReading,
+    Reading,
+    Read(usize),
+}
+
+impl fmt::Debug for AsyncPipe<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buf_len = match self.dst {
+            Sink::Vec(ref dst) => dst.len(),
+            Sink::Writer { ref buf, .. } | Sink::Utf8 { ref buf, .. } => buf.len(),
+        };
+        f.debug_struct("AsyncPipe")
+            .field("pipe", &self.pipe.as_raw_handle())
+            .field("state", &self.state)
+            .field("buf_len", &buf_len)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> AsyncPipe<'a> {
+    fn new(pipe: Handle, dst: &'a mut Vec<u8>, limit: usize) -> io::Result<AsyncPipe<'a>> {
+        AsyncPipe::with_sink(pipe, Sink::Vec(dst), limit)
+    }
+
+    fn with_writer(pipe: Handle, writer: &'a mut dyn io::Write) -> io::Result<AsyncPipe<'a>> {
+        let buf = Vec::with_capacity(PIPE_BUFFER_CAPACITY as usize);
+        AsyncPipe::with_sink(pipe, Sink::Writer { buf, writer }, usize::MAX)
+    }
+
+    fn with_string(pipe: Handle, dst: &'a mut String) -> io::Result<AsyncPipe<'a>> {
+        let buf = Vec::with_capacity(PIPE_BUFFER_CAPACITY as usize);
+        AsyncPipe::with_sink(pipe, Sink::Utf8 { buf, dst }, usize::MAX)
+    }
+
+    fn with_sink(pipe: Handle, dst: Sink<'a>, limit: usize) -> io::Result<AsyncPipe<'a>> {
+        // Create an event which we'll use to coordinate our overlapped
+        // operations, this event will be used in WaitForMultipleObjects
+        // and passed as part of the OVERLAPPED handle.
+        //
+        // Note that we do a somewhat clever thing here by flagging the
+        // event as being manually reset and setting it initially to the
+        // signaled state. This means that we'll naturally fall through the
+        // WaitForMultipleObjects call above for pipes created initially,
+        // and the only time an even will go back to "unset" will be once an
+        // I/O operation is successfully scheduled (what we want).
+        let event = Handle::new_event(true, true)?;
+        let mut overlapped: Box<c::OVERLAPPED> = unsafe { Box::new(mem::zeroed()) };
+        overlapped.hEvent = event.as_raw_handle();
+        Ok(AsyncPipe {
+            pipe,
+            overlapped,
+            event,
+            dst,
+            limit,
+            state: State::NotReading,
+            read_any: false,
+        })
+    }
+
+    /// Executes an overlapped read operation.
+    ///
+    /// Must not currently be reading, and returns whether the pipe is currently
+    /// at EOF or not. If the pipe is not at EOF then `result()` must be called
+    /// to complete the read later on (may block), but if the pipe is at EOF
+    /// then `result()` should not be called as it will just block forever.
+    fn schedule_read(&mut self) -> io::Result<bool> {
+        assert_eq!(self.state, State::NotReading);
+        // Never read more than one byte past the limit, which is enough to
+        // tell that it's been exceeded.
+        let max = self.limit.saturating_sub(self.dst.buf().len()).saturating_add(1);
+        let amt = unsafe {
+            let slice = slice_to_end(self.dst.buf());
+            let len = crate::cmp::min(slice.len(), max);
+            self.pipe.read_overlapped(&mut slice[..len], &mut *self.overlapped)?
+        };
+
+        // If this read finished immediately then our overlapped event will
+        // remain signaled (it was signaled coming in here) and we'll progress
+        // down to the method below.
         //
-        // STEP 1: Start the asynchronous I/O operation.
-        //         This simply calls either `ReadFileEx` or `WriteFileEx`,
-        //         giving it a pointer to the buffer and callback function.
+        // Otherwise the I/O operation is scheduled and the system set our event
+        // to not signaled, so we flag ourselves into the reading state and move
+        // on.
+        self.state = match amt {
+            Some(0) => return Ok(false),
+            Some(amt) => State::Read(amt),
+            None => State::Reading,
+        };
+        Ok(true)
+    }
+
+    /// Wait for the result of the overlapped operation previously executed.
+    ///
+    /// Takes a parameter `wait` which indicates if this pipe is currently being
+    /// read whether the function should block waiting for the read to complete.
+    ///
+    /// Returns values:
+    ///
+    /// * `true` - finished any pending read and the pipe is not at EOF (keep
+    ///            going)
+    /// * `false` - finished any pending read and pipe is at EOF (stop issuing
+    ///             reads)
+    fn result(&mut self) -> io::Result<bool> {
+        let amt = match self.state {
+            State::NotReading => return Ok(true),
+            State::Reading => self.pipe.overlapped_result(&mut *self.overlapped, true)?,
+            State::Read(amt) => amt,
+        };
+        self.state = State::NotReading;
+        self.read_any |= amt != 0;
+        unsafe {
+            let len = self.dst.buf().len();
+            self.dst.buf().set_len(len + amt);
+        }
+        match self.dst {
+            Sink::Vec(ref dst) if dst.len() > self.limit => {
+                return Err(io::const_io_error!(
+                    io::ErrorKind::OutOfMemory,
+                    "pipe output exceeded the size limit",
+                ));
+            }
+            Sink::Vec(_) => {}
+            Sink::Writer { ref mut buf, ref mut writer } => {
+                writer.write_all(buf)?;
+                buf.clear();
+            }
+            Sink::Utf8 { ref mut buf, ref mut dst } => append_utf8(buf, dst, amt == 0)?,
+        }
+        Ok(amt != 0)
+    }
+
+    /// Whether a read has finished with data that `result` hasn't picked up
+    /// yet. Never blocks.
+    fn has_data(&mut self) -> io::Result<bool> {
+        match self.state {
+            State::NotReading => Ok(false),
+            State::Read(amt) => Ok(amt != 0),
+            State::Reading => {
+                let signaled = unsafe { c::WaitForSingleObject(self.event.as_raw_handle(), 0) };
+                if signaled != c::WAIT_OBJECT_0 {
+                    return Ok(false);
+                }
+                Ok(self.pipe.overlapped_result(&mut *self.overlapped, false)? != 0)
+            }
+        }
+    }
+
+    /// Finishes out reading this pipe entirely.
+    ///
+    /// Waits for any pending and schedule read, and then calls `read_to_end`
+    /// if necessary to read all the remaining information.
+    fn finish(&mut self) -> io::Result<()> {
+        while self.result()? && self.schedule_read()? {
+            // ...
+        }
+        Ok(())
+    }
+
+    /// Reads this pipe to EOF on its own, appending the output to the string
+    /// it was created with by `with_string`.
+    #[allow(dead_code)]
+    fn read_into_string(&mut self) -> io::Result<()> {
+        debug_assert!(matches!(self.dst, Sink::Utf8 { .. }));
+        self.finish()
+    }
+}
+
+impl<'a> Drop for AsyncPipe<'a> {
+    fn drop(&mut self) {
+        match self.state {
+            State::Reading => {}
+            _ => return,
+        }
+
+        // If we have a pending read operation, then we have to make sure that
+        // it's *done* before we actually drop this type. The kernel requires
+        // that the `OVERLAPPED` and buffer pointers are valid for the entire
+        // I/O operation.
         //
-        // STEP 2: Enter an alertable state.
-        //         The callback set in step 1 will not be called until the thread
-        //         enters an "alertable" state. This can be done using `SleepEx`.
+        // To do that, we call `CancelIoEx` to cancel the pending operation, and
+        // if that succeeds we wait for the overlapped result. Only our own
+        // operation is cancelled, even if another `AsyncPipe` is reading from
+        // the same pipe. `ERROR_NOT_FOUND` means the read completed before it
+        // could be cancelled, so there's still a result to wait for.
         //
-        // STEP 3: The callback
-        //         Once the I/O is complete and the thread is in an alertable state,
-        //         the callback will be run on the same thread as the call to
-        //         `ReadFileEx` or `WriteFileEx` done in step 1.
-        //         In the callback we simply set the result of the async operation.
+        // A cancelled read completes with `ERROR_OPERATION_ABORTED`, which
+        // `GetOverlappedResult` only reports once the kernel is done with the
+        // buffer, so that counts as finished too. This waits directly rather
+        // than going through `result`, so nothing is passed on to the sink.
         //
-        // STEP 4: Return the result.
-        //         At this point we'll have a result from the callback function
-        //         and can simply return it. Note that we must not return earlier,
-        //         while the I/O is still in progress.
+        // If anything else fails, there's not really much we can do, so we leak
+        // the buffer/OVERLAPPED pointers to ensure we're at least memory safe.
+        let is = |e: &io::Error, code: c::DWORD| e.raw_os_error() == Some(code as i32);
+        let cancelled = match self.pipe.cancel_io_ex(&mut *self.overlapped) {
+            Ok(()) => true,
+            Err(e) => is(&e, c::ERROR_NOT_FOUND),
+        };
+        let finished = cancelled
+            && match self.pipe.overlapped_result(&mut *self.overlapped, true) {
+                Ok(_) => true,
+                Err(e) => is(&e, c::ERROR_OPERATION_ABORTED),
+            };
+        if !finished {
+            let buf = mem::take(self.dst.buf());
+            let overlapped = Box::new(unsafe { mem::zeroed() });
+            let overlapped = mem::replace(&mut self.overlapped, overlapped);
+            mem::forget((buf, overlapped));
+        }
+    }
+}
 
-        // The result that will be set from the asynchronous callback.
-        let mut async_result: Option<AsyncResult> = None;
-        struct AsyncResult {
-            error: u32,
-            transfered: u32,
+/// A pipe being read by `read2_apc`.
+struct ApcPipe<'a> {
+    pipe: Handle,
+    dst: &'a mut Vec<u8>,
+    // Both need a stable address while a read is outstanding.
+    overlapped: Box<c::OVERLAPPED>,
+    result: Box<Option<ApcResult>>,
+    reading: bool,
+    eof: bool,
+}
+
+/// What the completion routine of an `ApcPipe` read was called with.
+#[derive(Clone, Copy)]
+struct ApcResult {
+    error: u32,
+    transferred: u32,
+}
+
+unsafe extern "system" fn apc_read_done(
+    dwErrorCode: u32,
+    dwNumberOfBytesTransfered: u32,
+    lpOverlapped: *mut c::OVERLAPPED,
+) {
+    // Set `result` using a pointer smuggled through `hEvent`.
+    let result = ApcResult { error: dwErrorCode, transferred: dwNumberOfBytesTransfered };
+    *(*lpOverlapped).hEvent.cast::<Option<ApcResult>>() = Some(result);
+}
+
+impl<'a> ApcPipe<'a> {
+    fn new(pipe: Handle, dst: &'a mut Vec<u8>) -> ApcPipe<'a> {
+        let overlapped = unsafe { Box::new(mem::zeroed()) };
+        ApcPipe { pipe, dst, overlapped, result: Box::new(None), reading: false, eof: false }
+    }
+
+    /// Starts a read into the spare capacity of `dst`.
+    ///
+    /// Its completion routine runs the next time this thread is alertable
+    /// after the read is done, even if it finishes straight away.
+    fn schedule_read(&mut self) -> io::Result<()> {
+        assert!(!self.reading);
+        *self.result = None;
+        *self.overlapped = unsafe { mem::zeroed() };
+        // `hEvent` is unused by `ReadFileEx`, so it carries a pointer to
+        // `result` for the completion routine.
+        self.overlapped.hEvent = &mut *self.result as *mut Option<ApcResult> as c::HANDLE;
+        let res = unsafe {
+            let slice = slice_to_end(self.dst);
+            self.pipe.read_overlapped_ex(slice, &mut *self.overlapped, apc_read_done)?
+        };
+        match res {
+            None => self.reading = true,
+            Some(_) => self.eof = true,
         }
+        Ok(())
+    }
 
-        // STEP 3: The callback.
-        unsafe extern "system" fn callback(
-            dwErrorCode: u32,
-            dwNumberOfBytesTransfered: u32,
-            lpOverlapped: *mut c::OVERLAPPED,
-        ) {
-            // Set `async_result` using a pointer smuggled through `hEvent`.
-            let result = AsyncResult { error: dwErrorCode, transfered: dwNumberOfBytesTransfered };
-            *(*lpOverlapped).hEvent.cast::<Option<AsyncResult>>() = Some(result);
+    /// Picks up the outstanding read if its completion routine has run.
+    fn collect(&mut self) -> io::Result<()> {
+        let result = match *self.result {
+            Some(result) if self.reading => result,
+            _ => return Ok(()),
+        };
+        self.reading = false;
+        match result.error {
+            c::ERROR_SUCCESS => {}
+            c::ERROR_BROKEN_PIPE | c::ERROR_HANDLE_EOF => {
+                self.eof = true;
+                return Ok(());
+            }
+            error => return Err(io::Error::from_raw_os_error(error as i32)),
+        }
+        unsafe {
+            let len = self.dst.len();
+            self.dst.set_len(len + result.transferred as usize);
         }
+        self.eof = result.transferred == 0;
+        Ok(())
+    }
+}
 
-        // STEP 1: Start the I/O operation.
-        let mut overlapped: c::OVERLAPPED = crate::mem::zeroed();
-        // `hEvent` is unused by `ReadFileEx` and `WriteFileEx`.
-        // Therefore the documentation suggests using it to smuggle a pointer to the callback.
-        overlapped.hEvent = &mut async_result as *mut _ as *mut _;
+impl Drop for ApcPipe<'_> {
+    fn drop(&mut self) {
+        // The buffer and `result` have to stay valid until the completion
+        // routine of an outstanding read has run. Cancelling the read still
+        // queues it, so wait alertably for that.
+        if self.reading {
+            let _ = self.pipe.cancel_io_ex(&mut *self.overlapped);
+            while self.result.is_none() {
+                unsafe { c::SleepEx(c::INFINITE, c::TRUE) };
+            }
+        }
+    }
+}
 
-        // Asynchronous read of the pipe.
-        // If successful, `callback` will be called once it completes.
-        let result = io(self.handle().as_handle(), buf, len, &mut overlapped, callback);
-        if result == c::FALSE {
-            // We can return here because the call failed.
-            // After this we must not return until the I/O completes.
+unsafe fn slice_to_end<A: Allocator>(v: &mut Vec<u8, A>) -> &mut [u8] {
+    // At least double the capacity once it runs out so that reading a lot of
+    // data only reallocates a logarithmic number of times.
+    if v.capacity() == v.len() {
+        v.reserve(v.capacity().max(8192));
+    }
+    slice::from_raw_parts_mut(v.as_mut_ptr().add(v.len()), v.capacity() - v.len())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Named pipes
+////////////////////////////////////////////////////////////////////////////////
+
+/// Waits until an instance of the named pipe `name` is available for
+/// connecting to, or until `timeout_ms` milliseconds pass.
+///
+/// If `timeout_ms` is `None` this waits forever. Note that `Some(0)` uses the
+/// default timeout the server gave when creating the pipe.
+///
+/// If no pipe called `name` exists this fails immediately with `NotFound`, and
+/// if the timeout elapses it fails with `TimedOut`.
+///
+/// This doesn't open the pipe. Another client may connect to the instance
+/// first, in which case connecting after this returns will still fail.
+#[allow(dead_code)]
+pub fn wait_named_pipe(name: &OsStr, timeout_ms: Option<u32>) -> io::Result<()> {
+    let name = to_u16s(name)?;
+    let timeout = timeout_ms.unwrap_or(c::NMPWAIT_WAIT_FOREVER);
+    // `ERROR_FILE_NOT_FOUND` and `ERROR_SEM_TIMEOUT` already decode to
+    // `NotFound` and `TimedOut` respectively.
+    cvt(unsafe { c::WaitNamedPipeW(name.as_ptr(), timeout) })?;
+    Ok(())
+}
+
+/// Connects to the message-mode pipe `name`, writes `request` as one message
+/// and reads back one reply message with `CallNamedPipeW`, which closes the
+/// pipe again afterwards.
+///
+/// `timeout_ms` is how long to wait for an instance of the pipe to become
+/// available, as with `wait_named_pipe`.
+///
+/// If the reply doesn't fit in our buffer, the rest of it is lost when the
+/// pipe is closed. In that case the whole call is repeated with a buffer twice
+/// as big, which sends `request` again, so this should only be used for
+/// requests that are safe to repeat.
+#[allow(dead_code)]
+pub fn call_named_pipe(
+    name: &OsStr,
+    request: &[u8],
+    timeout_ms: Option<u32>,
+) -> io::Result<Vec<u8>> {
+    let name = to_u16s(name)?;
+    let request_len = c::DWORD::try_from(request.len()).map_err(|_| {
+        io::const_io_error!(io::ErrorKind::InvalidInput, "request does not fit in a message")
+    })?;
+    let timeout = timeout_ms.unwrap_or(c::NMPWAIT_WAIT_FOREVER);
+    let mut response = Vec::with_capacity(4096);
+    loop {
+        let capacity = crate::cmp::min(response.capacity(), c::DWORD::MAX as usize) as c::DWORD;
+        let mut read = 0;
+        let res = cvt(unsafe {
+            c::CallNamedPipeW(
+                name.as_ptr(),
+                request.as_ptr() as c::LPVOID,
+                request_len,
+                response.as_mut_ptr() as c::LPVOID,
+                capacity,
+                &mut read,
+                timeout,
+            )
+        });
+        match res {
+            Ok(_) => {
+                unsafe { response.set_len(read as usize) };
+                return Ok(response);
+            }
+            Err(ref e)
+                if e.raw_os_error() == Some(c::ERROR_MORE_DATA as i32)
+                    && capacity < c::DWORD::MAX =>
+            {
+                response.reserve(response.capacity() * 2);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The server end of a named pipe.
+///
+/// This owns a single pipe instance which clients connect to one at a time:
+/// `accept` waits for a client and `disconnect` drops the client again, after
+/// which the instance is listening for the next `accept`.
+pub struct NamedPipeServer {
+    instance: Handle,
+    // Whether `instance` was created with `FILE_FLAG_OVERLAPPED`.
+    overlapped: bool,
+}
+
+/// A client connected to a `NamedPipeServer`.
+///
+/// The connection stays usable until `NamedPipeServer::disconnect` is
+/// called, even if this is dropped first.
+pub struct NamedPipeConnection {
+    handle: Handle,
+    overlapped: bool,
+}
+
+#[allow(dead_code)]
+impl NamedPipeServer {
+    /// Creates the named pipe `name`, allowing up to `max_instances`
+    /// instances of it to exist in total.
+    ///
+    /// This fails with `PermissionDenied` if a pipe called `name` already
+    /// exists, so that another process can't get in front of our clients.
+    pub fn bind(name: &OsStr, max_instances: u32) -> io::Result<NamedPipeServer> {
+        NamedPipeServer::bind_with_flags(name, max_instances, 0)
+    }
+
+    /// Like `bind`, but creates the pipe with `FILE_FLAG_OVERLAPPED` so that
+    /// clients can be accepted with `accept_overlapped`.
+    ///
+    /// All I/O on the connections still blocks the calling thread.
+    pub fn bind_overlapped(name: &OsStr, max_instances: u32) -> io::Result<NamedPipeServer> {
+        NamedPipeServer::bind_with_flags(name, max_instances, c::FILE_FLAG_OVERLAPPED)
+    }
+
+    fn bind_with_flags(
+        name: &OsStr,
+        max_instances: u32,
+        flags: c::DWORD,
+    ) -> io::Result<NamedPipeServer> {
+        let name = to_u16s(name)?;
+        let handle = unsafe {
+            c::CreateNamedPipeW(
+                name.as_ptr(),
+                c::PIPE_ACCESS_DUPLEX | c::FILE_FLAG_FIRST_PIPE_INSTANCE | flags,
+                c::PIPE_TYPE_BYTE
+                    | c::PIPE_READMODE_BYTE
+                    | c::PIPE_WAIT
+                    | c::PIPE_REJECT_REMOTE_CLIENTS,
+                max_instances,
+                PIPE_BUFFER_CAPACITY,
+                PIPE_BUFFER_CAPACITY,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if handle == c::INVALID_HANDLE_VALUE {
             return Err(io::Error::last_os_error());
         }
+        Ok(NamedPipeServer {
+            instance: unsafe { Handle::from_raw_handle(handle) },
+            overlapped: flags & c::FILE_FLAG_OVERLAPPED != 0,
+        })
+    }
+
+    /// Blocks until a client connects.
+    ///
+    /// If a client is already connected, for example after
+    /// `accept_overlapped`, this returns its connection straight away.
+    pub fn accept(&self) -> io::Result<NamedPipeConnection> {
+        if self.overlapped {
+            self.instance.connect_named_pipe_overlapped()?;
+        } else {
+            let res =
+                cvt(unsafe { c::ConnectNamedPipe(self.instance.as_raw_handle(), ptr::null_mut()) });
+            match res {
+                // A client may connect between creating the instance and
+                // calling `ConnectNamedPipe`, which is just as good.
+                Err(ref e) if e.raw_os_error() == Some(c::ERROR_PIPE_CONNECTED as i32) => {}
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+        let handle = self.instance.duplicate(0, false, c::DUPLICATE_SAME_ACCESS)?;
+        Ok(NamedPipeConnection { handle, overlapped: self.overlapped })
+    }
+
+    /// Starts waiting for a client to connect without blocking, for a server
+    /// created with `bind_overlapped`.
+    ///
+    /// This returns `Ok(true)` if a client is already connected, in which case
+    /// `event` isn't signaled. Otherwise it returns `Ok(false)` and `event`
+    /// is signaled once a client connects, after which the accept has to be
+    /// finished with `accept_overlapped_result`. Either way `accept` then
+    /// returns the connection.
+    ///
+    /// # Safety
+    ///
+    /// If this returns `Ok(false)`, `overlapped` must stay where it is until
+    /// `accept_overlapped_result` has returned, and `event` must stay open
+    /// until then too.
+    pub unsafe fn accept_overlapped(
+        &self,
+        event: &Handle,
+        overlapped: *mut c::OVERLAPPED,
+    ) -> io::Result<bool> {
+        if !self.overlapped {
+            return Err(io::const_io_error!(
+                io::ErrorKind::InvalidInput,
+                "the server was not created with `bind_overlapped`",
+            ));
+        }
+        (*overlapped).hEvent = event.as_raw_handle();
+        match cvt(c::ConnectNamedPipe(self.instance.as_raw_handle(), overlapped)) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.raw_os_error() == Some(c::ERROR_PIPE_CONNECTED as i32) => Ok(true),
+            Err(ref e) if e.raw_os_error() == Some(c::ERROR_IO_PENDING as i32) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits for an accept started by `accept_overlapped` to finish, and
+    /// returns whether it succeeded.
+    pub fn accept_overlapped_result(&self, overlapped: &mut c::OVERLAPPED) -> io::Result<()> {
+        self.instance.overlapped_result(overlapped, true).map(drop)
+    }
 
-        // Wait indefinitely for the result.
-        let result = loop {
-            // STEP 2: Enter an alertable state.
-            // The second parameter of `SleepEx` is used to make this sleep alertable.
-            c::SleepEx(c::INFINITE, c::TRUE);
-            if let Some(result) = async_result {
-                break result;
+    /// Disconnects the current client so that the next `accept` can succeed.
+    ///
+    /// Any data the client hasn't read yet is discarded. Flush the connection
+    /// first to wait for the client to read everything.
+    pub fn disconnect(&self) -> io::Result<()> {
+        cvt(unsafe { c::DisconnectNamedPipe(self.instance.as_raw_handle()) })?;
+        Ok(())
+    }
+
+    /// Serves clients one after another until `f` returns `Ok(false)` or an
+    /// error occurs.
+    ///
+    /// Each connection is flushed and disconnected after `f` returns.
+    pub fn listen_loop<F>(&self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut NamedPipeConnection) -> io::Result<bool>,
+    {
+        loop {
+            let mut conn = self.accept()?;
+            let keep_going = f(&mut conn)?;
+            io::Write::flush(&mut conn)?;
+            drop(conn);
+            self.disconnect()?;
+            if !keep_going {
+                return Ok(());
             }
-        };
-        // STEP 4: Return the result.
-        // `async_result` is always `Some` at this point
-        match result.error {
-            c::ERROR_SUCCESS => Ok(result.transfered as usize),
-            error => Err(io::Error::from_raw_os_error(error as _)),
         }
     }
 }
 
-pub fn read2(p1: AnonPipe, v1: &mut Vec<u8>, p2: AnonPipe, v2: &mut Vec<u8>) -> io::Result<()> {
-    let p1 = p1.into_handle();
-    let p2 = p2.into_handle();
+impl NamedPipeConnection {
+    /// Starts an overlapped read or write with `start` and waits for it to
+    /// finish.
+    fn overlapped_io<F>(&self, start: F) -> io::Result<usize>
+    where
+        F: FnOnce(*mut c::OVERLAPPED) -> io::Result<Option<usize>>,
+    {
+        let event = Handle::new_event(true, false)?;
+        let mut overlapped: c::OVERLAPPED = unsafe { mem::zeroed() };
+        overlapped.hEvent = event.as_raw_handle();
+        match start(&mut overlapped)? {
+            Some(amt) => Ok(amt),
+            None => self.handle.overlapped_result(&mut overlapped, true),
+        }
+    }
+}
 
-    let mut p1 = AsyncPipe::new(p1, v1)?;
-    let mut p2 = AsyncPipe::new(p2, v2)?;
-    let objs = [p1.event.as_raw_handle(), p2.event.as_raw_handle()];
+impl io::Read for NamedPipeConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.overlapped {
+            self.overlapped_io(|overlapped| unsafe { self.handle.read_overlapped(buf, overlapped) })
+        } else {
+            self.handle.read(buf)
+        }
+    }
 
-    // In a loop we wait for either pipe's scheduled read operation to complete.
-    // If the operation completes with 0 bytes, that means EOF was reached, in
-    // which case we just finish out the other pipe entirely.
-    //
-    // Note that overlapped I/O is in general super unsafe because we have to
-    // be careful to ensure that all pointers in play are valid for the entire
-    // duration of the I/O operation (where tons of operations can also fail).
-    // The destructor for `AsyncPipe` ends up taking care of most of this.
-    loop {
-        let res = unsafe { c::WaitForMultipleObjects(2, objs.as_ptr(), c::FALSE, c::INFINITE) };
-        if res == c::WAIT_OBJECT_0 {
-            if !p1.result()? || !p1.schedule_read()? {
-                return p2.finish();
-            }
-        } else if res == c::WAIT_OBJECT_0 + 1 {
-            if !p2.result()? || !p2.schedule_read()? {
-                return p1.finish();
-            }
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        if self.overlapped {
+            io::default_read_vectored(|buf| self.read(buf), bufs)
         } else {
-            return Err(io::Error::last_os_error());
+            self.handle.read_vectored(bufs)
         }
     }
 }
 
-struct AsyncPipe<'a> {
-    pipe: Handle,
-    event: Handle,
-    overlapped: Box<c::OVERLAPPED>, // needs a stable address
-    dst: &'a mut Vec<u8>,
-    state: State,
+impl io::Write for NamedPipeConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.overlapped {
+            self.overlapped_io(|overlapped| unsafe {
+                self.handle.write_overlapped(buf, overlapped)
+            })
+        } else {
+            self.handle.write(buf)
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        if self.overlapped {
+            io::default_write_vectored(|buf| self.write(buf), bufs)
+        } else {
+            self.handle.write_vectored(bufs)
+        }
+    }
+
+    /// Blocks until the client has read everything written so far.
+    fn flush(&mut self) -> io::Result<()> {
+        cvt(unsafe { c::FlushFileBuffers(self.handle.as_raw_handle()) })?;
+        Ok(())
+    }
 }
 
-#[derive(PartialEq, Debug)]
-enum State {
-    NotReading,
-    Reading,
-    Read(usize),
+/// A named pipe server that creates a new instance of the pipe for every
+/// client, like a `TcpListener`.
+///
+/// Iterating over the listener accepts clients one after another.
+pub struct NamedPipeListener {
+    name: Vec<u16>,
+    // The instance the next client connects to. It's created in advance so
+    // that clients don't find the pipe missing between two `accept`s.
+    next: Cell<Option<Handle>>,
+    closed: Cell<bool>,
+    max_instances: u32,
+    live: Arc<LiveStreams>,
 }
 
-impl<'a> AsyncPipe<'a> {
-    fn new(pipe: Handle, dst: &'a mut Vec<u8>) -> io::Result<AsyncPipe<'a>> {
-        // Create an event which we'll use to coordinate our overlapped
-        // operations, this event will be used in WaitForMultipleObjects
-        // and passed as part of the OVERLAPPED handle.
-        //
-        // Note that we do a somewhat clever thing here by flagging the
-        // event as being manually reset and setting it initially to the
-        // signaled state. This means that we'll naturally fall through the
-        // WaitForMultipleObjects call above for pipes created initially,
-        // and the only time an even will go back to "unset" will be once an
-        // I/O operation is successfully scheduled (what we want).
-        let event = Handle::new_event(true, true)?;
-        let mut overlapped: Box<c::OVERLAPPED> = unsafe { Box::new(mem::zeroed()) };
-        overlapped.hEvent = event.as_raw_handle();
-        Ok(AsyncPipe { pipe, overlapped, event, dst, state: State::NotReading })
+/// The number of streams accepted by a listener that are still open.
+struct LiveStreams {
+    count: Mutex<u32>,
+    dropped: Condvar,
+}
+
+/// Counts a stream as live until it's dropped.
+struct LiveStream(Arc<LiveStreams>);
+
+impl Drop for LiveStream {
+    fn drop(&mut self) {
+        *self.0.count.lock().unwrap() -= 1;
+        self.0.dropped.notify_one();
     }
+}
 
-    /// Executes an overlapped read operation.
+/// Either end of a connection to a named pipe: the server end from
+/// `NamedPipeListener::accept`, or the client end from `NamedPipeStream::connect`.
+pub struct NamedPipeStream {
+    handle: Handle,
+    // Set for the server end, accepted by a listener. This is dropped after
+    // `handle` is closed, so that the listener can create another instance
+    // as soon as it's told.
+    live: Option<LiveStream>,
+}
+
+#[allow(dead_code)]
+impl NamedPipeListener {
+    /// Creates the named pipe `name`.
     ///
-    /// Must not currently be reading, and returns whether the pipe is currently
-    /// at EOF or not. If the pipe is not at EOF then `result()` must be called
-    /// to complete the read later on (may block), but if the pipe is at EOF
-    /// then `result()` should not be called as it will just block forever.
-    fn schedule_read(&mut self) -> io::Result<bool> {
-        assert_eq!(self.state, State::NotReading);
-        let amt = unsafe {
-            let slice = slice_to_end(self.dst);
-            self.pipe.read_overlapped(slice, &mut *self.overlapped)?
-        };
+    /// Like `NamedPipeServer::bind`, this fails with `PermissionDenied` if a
+    /// pipe called `name` already exists.
+    pub fn bind(name: &OsStr) -> io::Result<NamedPipeListener> {
+        NamedPipeListener::with_max_instances(name, c::PIPE_UNLIMITED_INSTANCES)
+    }
 
-        // If this read finished immediately then our overlapped event will
-        // remain signaled (it was signaled coming in here) and we'll progress
-        // down to the method below.
-        //
-        // Otherwise the I/O operation is scheduled and the system set our event
-        // to not signaled, so we flag ourselves into the reading state and move
-        // on.
-        self.state = match amt {
-            Some(0) => return Ok(false),
-            Some(amt) => State::Read(amt),
-            None => State::Reading,
+    /// Like `bind`, but allows at most `max_instances` clients to be
+    /// connected at once, which is from 1 to 254 or
+    /// `PIPE_UNLIMITED_INSTANCES`.
+    ///
+    /// Once that many streams have been accepted, `accept` blocks until one
+    /// of them is dropped.
+    pub fn with_max_instances(name: &OsStr, max_instances: u32) -> io::Result<NamedPipeListener> {
+        check_max_instances(max_instances)?;
+        let name = to_u16s(name)?;
+        let first = new_listener_instance(&name, true, max_instances)?;
+        Ok(NamedPipeListener {
+            name,
+            next: Cell::new(Some(first)),
+            closed: Cell::new(false),
+            max_instances,
+            live: Arc::new(LiveStreams { count: Mutex::new(0), dropped: Condvar::new() }),
+        })
+    }
+
+    /// Returns how many of the streams accepted by this listener are still
+    /// open.
+    pub fn instance_count(&self) -> u32 {
+        *self.live.count.lock().unwrap()
+    }
+
+    /// Blocks until a client connects.
+    pub fn accept(&self) -> io::Result<NamedPipeStream> {
+        if self.closed.get() {
+            return Err(io::const_io_error!(
+                io::ErrorKind::NotConnected,
+                "the named pipe listener has been closed",
+            ));
+        }
+        if self.max_instances != c::PIPE_UNLIMITED_INSTANCES {
+            let mut count = self.live.count.lock().unwrap();
+            while *count >= self.max_instances {
+                count = self.live.dropped.wait(count).unwrap();
+            }
+        }
+        let instance = match self.next.take() {
+            Some(instance) => instance,
+            None => new_listener_instance(&self.name, false, self.max_instances)?,
         };
-        Ok(true)
+        let res = cvt(unsafe { c::ConnectNamedPipe(instance.as_raw_handle(), ptr::null_mut()) });
+        match res {
+            // A client may connect between creating the instance and calling
+            // `ConnectNamedPipe`, which is just as good.
+            Err(ref e) if e.raw_os_error() == Some(c::ERROR_PIPE_CONNECTED as i32) => {}
+            Err(e) => {
+                self.next.set(Some(instance));
+                return Err(e);
+            }
+            Ok(_) => {}
+        }
+        // This fails while all instances are in use, in which case the next
+        // `accept` tries again once a stream has been dropped.
+        self.next.set(new_listener_instance(&self.name, false, self.max_instances).ok());
+        *self.live.count.lock().unwrap() += 1;
+        Ok(NamedPipeStream { handle: instance, live: Some(LiveStream(self.live.clone())) })
     }
 
-    /// Wait for the result of the overlapped operation previously executed.
+    /// Closes the instance waiting for the next client, so that clients can't
+    /// connect any more and `accept` fails.
     ///
-    /// Takes a parameter `wait` which indicates if this pipe is currently being
-    /// read whether the function should block waiting for the read to complete.
+    /// Streams that have already been accepted aren't affected.
+    pub fn close(&self) {
+        self.closed.set(true);
+        drop(self.next.take());
+    }
+}
+
+impl Iterator for NamedPipeListener {
+    type Item = io::Result<NamedPipeStream>;
+
+    /// Accepts the next client, or returns `None` once the listener has been
+    /// closed.
+    fn next(&mut self) -> Option<io::Result<NamedPipeStream>> {
+        if self.closed.get() { None } else { Some(self.accept()) }
+    }
+}
+
+#[allow(dead_code)]
+impl NamedPipeStream {
+    /// Connects to the byte-mode pipe `name` for reading and writing.
     ///
-    /// Returns values:
+    /// If all instances of the pipe are busy this waits up to 5 seconds at a
+    /// time for one to become available. This fails with `NotFound` if there
+    /// is no pipe called `name`.
+    pub fn connect(name: &OsStr) -> io::Result<NamedPipeStream> {
+        loop {
+            match NamedPipeStream::try_connect(name)? {
+                Some(stream) => return Ok(stream),
+                None => wait_named_pipe(name, Some(5000))?,
+            }
+        }
+    }
+
+    /// Like `connect`, but waits at most `timeout` for an instance of the
+    /// pipe to become available, failing with `TimedOut` if none does.
     ///
-    /// * `true` - finished any pending read and the pipe is not at EOF (keep
-    ///            going)
-    /// * `false` - finished any pending read and pipe is at EOF (stop issuing
-    ///             reads)
-    fn result(&mut self) -> io::Result<bool> {
-        let amt = match self.state {
-            State::NotReading => return Ok(true),
-            State::Reading => self.pipe.overlapped_result(&mut *self.overlapped, true)?,
-            State::Read(amt) => amt,
-        };
-        self.state = State::NotReading;
-        unsafe {
-            let len = self.dst.len();
-            self.dst.set_len(len + amt);
+    /// Timeouts too long for `WaitNamedPipeW` wait forever. Like `connect`
+    /// this fails with `NotFound` straight away if there is no pipe called
+    /// `name`, rather than waiting for it to be created.
+    pub fn connect_timeout(name: &OsStr, timeout: Duration) -> io::Result<NamedPipeStream> {
+        if let Some(stream) = NamedPipeStream::try_connect(name)? {
+            return Ok(stream);
         }
-        Ok(amt != 0)
+        // A timeout of 0 would mean the default timeout of the pipe.
+        wait_named_pipe(name, Some(crate::cmp::max(dur2timeout(timeout), 1)))?;
+        // Another client may still get the instance first, and there's no
+        // time left to wait for the next one.
+        NamedPipeStream::try_connect(name)?.ok_or_else(|| {
+            io::const_io_error!(io::ErrorKind::TimedOut, "all pipe instances are busy")
+        })
     }
 
-    /// Finishes out reading this pipe entirely.
+    /// Switches between reading whole messages and reading a stream of
+    /// bytes.
     ///
-    /// Waits for any pending and schedule read, and then calls `read_to_end`
-    /// if necessary to read all the remaining information.
-    fn finish(&mut self) -> io::Result<()> {
-        while self.result()? && self.schedule_read()? {
-            // ...
+    /// A client end always starts out in byte mode, even if the server
+    /// created a message-mode pipe. Message mode can only be used with
+    /// pipes created with `PIPE_TYPE_MESSAGE`.
+    pub fn set_message_mode(&self, message: bool) -> io::Result<()> {
+        let read_mode = if message { PipeReadMode::Message } else { PipeReadMode::Byte };
+        self.handle.set_pipe_mode(read_mode, None)
+    }
+
+    pub fn is_message_mode(&self) -> io::Result<bool> {
+        Ok(self.handle.pipe_mode()? & c::PIPE_READMODE_MESSAGE != 0)
+    }
+
+    /// Returns the ID of the process at the other end of the pipe: the
+    /// client's for a stream from `NamedPipeListener::accept`, and the
+    /// server's for one from `connect`.
+    pub fn peer_process_id(&self) -> io::Result<u32> {
+        if self.live.is_some() {
+            self.handle.pipe_client_process_id()
+        } else {
+            self.handle.pipe_server_process_id()
+        }
+    }
+
+    /// Makes the current thread impersonate the client until the returned
+    /// guard is dropped, see `Handle::impersonate_named_pipe_client`.
+    ///
+    /// This is only possible on the server end, once something has been read
+    /// from the client. Any failure is reported as `PermissionDenied`.
+    #[cfg(not(target_vendor = "uwp"))]
+    pub fn impersonate(&self) -> io::Result<ImpersonationGuard> {
+        self.handle.impersonate_named_pipe_client().map_err(|e| {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                e
+            } else {
+                io::const_io_error!(
+                    io::ErrorKind::PermissionDenied,
+                    "cannot impersonate the client of this pipe",
+                )
+            }
+        })
+    }
+
+    /// Connects to the pipe `name`, returning `None` if all of its instances
+    /// are busy.
+    fn try_connect(name: &OsStr) -> io::Result<Option<NamedPipeStream>> {
+        let mut opts = OpenOptions::new();
+        opts.read(true);
+        opts.write(true);
+        match File::open(Path::new(name), &opts) {
+            Ok(file) => Ok(Some(NamedPipeStream { handle: file.into_inner(), live: None })),
+            Err(ref e) if e.raw_os_error() == Some(c::ERROR_PIPE_BUSY as i32) => Ok(None),
+            Err(e) => Err(e),
         }
+    }
+}
+
+impl AsHandle for NamedPipeStream {
+    fn as_handle(&self) -> BorrowedHandle<'_> {
+        self.handle.as_handle()
+    }
+}
+
+/// Creates an instance of the duplex, byte-mode pipe `name`, which must be
+/// nul-terminated.
+fn new_listener_instance(name: &[u16], first: bool, max_instances: u32) -> io::Result<Handle> {
+    let mut flags = c::PIPE_ACCESS_DUPLEX;
+    if first {
+        flags |= c::FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+    let handle = unsafe {
+        c::CreateNamedPipeW(
+            name.as_ptr(),
+            flags,
+            c::PIPE_TYPE_BYTE
+                | c::PIPE_READMODE_BYTE
+                | c::PIPE_WAIT
+                | c::PIPE_REJECT_REMOTE_CLIENTS,
+            max_instances,
+            PIPE_BUFFER_CAPACITY,
+            PIPE_BUFFER_CAPACITY,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if handle == c::INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { Handle::from_raw_handle(handle) })
+}
+
+fn check_max_instances(max_instances: u32) -> io::Result<()> {
+    if max_instances == 0 || max_instances > c::PIPE_UNLIMITED_INSTANCES {
+        return Err(io::const_io_error!(
+            io::ErrorKind::InvalidInput,
+            "the maximum number of pipe instances must be from 1 to 255",
+        ));
+    }
+    Ok(())
+}
+
+impl io::Read for NamedPipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.handle.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.handle.read_vectored(bufs)
+    }
+}
+
+impl io::Write for NamedPipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.handle.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.handle.write_vectored(bufs)
+    }
+
+    /// Blocks until the other end has read everything written so far.
+    fn flush(&mut self) -> io::Result<()> {
+        cvt(unsafe { c::FlushFileBuffers(self.handle.as_raw_handle()) })?;
         Ok(())
     }
 }
 
-impl<'a> Drop for AsyncPipe<'a> {
-    fn drop(&mut self) {
-        match self.state {
-            State::Reading => {}
-            _ => return,
+/// Configuration for creating a named pipe with any of the options that
+/// `CreateNamedPipeW` has.
+///
+/// Whether the pipe is inbound or outbound is from the server's point of view,
+/// and by default it's duplex. The defaults otherwise match
+/// `NamedPipeServer::bind`: a blocking byte-mode pipe that rejects remote
+/// clients, with unlimited instances and 64kb buffers.
+pub struct NamedPipeBuilder<'a> {
+    inbound: bool,
+    outbound: bool,
+    first_instance: bool,
+    write_through: bool,
+    overlapped: bool,
+    message_mode: bool,
+    nonblocking: bool,
+    reject_remote_clients: bool,
+    max_instances: u32,
+    out_buffer_size: u32,
+    in_buffer_size: u32,
+    default_timeout_ms: u32,
+    security: Option<&'a mut PipeSecurityAttributes>,
+}
+
+#[allow(dead_code)]
+impl<'a> NamedPipeBuilder<'a> {
+    pub fn new() -> NamedPipeBuilder<'a> {
+        NamedPipeBuilder {
+            inbound: true,
+            outbound: true,
+            first_instance: false,
+            write_through: false,
+            overlapped: false,
+            message_mode: false,
+            nonblocking: false,
+            reject_remote_clients: true,
+            max_instances: c::PIPE_UNLIMITED_INSTANCES,
+            out_buffer_size: PIPE_BUFFER_CAPACITY,
+            in_buffer_size: PIPE_BUFFER_CAPACITY,
+            default_timeout_ms: 0,
+            security: None,
         }
+    }
 
-        // If we have a pending read operation, then we have to make sure that
-        // it's *done* before we actually drop this type. The kernel requires
-        // that the `OVERLAPPED` and buffer pointers are valid for the entire
-        // I/O operation.
-        //
-        // To do that, we call `CancelIo` to cancel any pending operation, and
-        // if that succeeds we wait for the overlapped result.
-        //
-        // If anything here fails, there's not really much we can do, so we leak
-        // the buffer/OVERLAPPED pointers to ensure we're at least memory safe.
-        if self.pipe.cancel_io().is_err() || self.result().is_err() {
-            let buf = mem::take(self.dst);
-            let overlapped = Box::new(unsafe { mem::zeroed() });
-            let overlapped = mem::replace(&mut self.overlapped, overlapped);
-            mem::forget((buf, overlapped));
+    /// Whether clients can write to the server.
+    pub fn inbound(&mut self, inbound: bool) -> &mut Self {
+        self.inbound = inbound;
+        self
+    }
+
+    /// Whether the server can write to clients.
+    pub fn outbound(&mut self, outbound: bool) -> &mut Self {
+        self.outbound = outbound;
+        self
+    }
+
+    /// Makes `build_server` fail with `PermissionDenied` if the pipe already
+    /// exists, instead of creating another instance of it.
+    pub fn first_instance(&mut self, first_instance: bool) -> &mut Self {
+        self.first_instance = first_instance;
+        self
+    }
+
+    /// Makes writes wait until the data has reached the other machine. This
+    /// only makes a difference to pipes between different machines.
+    pub fn write_through(&mut self, write_through: bool) -> &mut Self {
+        self.write_through = write_through;
+        self
+    }
+
+    /// Opens both ends with `FILE_FLAG_OVERLAPPED`.
+    pub fn overlapped(&mut self, overlapped: bool) -> &mut Self {
+        self.overlapped = overlapped;
+        self
+    }
+
+    /// Sends data as messages, and reads it one message at a time at both
+    /// ends.
+    pub fn message_mode(&mut self, message_mode: bool) -> &mut Self {
+        self.message_mode = message_mode;
+        self
+    }
+
+    /// Creates the server end in `PIPE_NOWAIT` mode, see
+    /// `AnonPipe::set_non_blocking`.
+    pub fn nonblocking(&mut self, nonblocking: bool) -> &mut Self {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    pub fn reject_remote_clients(&mut self, reject: bool) -> &mut Self {
+        self.reject_remote_clients = reject;
+        self
+    }
+
+    /// Sets how many instances of the pipe can exist at once, which is from 1
+    /// to 254 or `PIPE_UNLIMITED_INSTANCES`.
+    pub fn max_instances(&mut self, max_instances: u32) -> &mut Self {
+        self.max_instances = max_instances;
+        self
+    }
+
+    /// Sets how much the kernel reserves for data written by the server.
+    pub fn out_buffer_size(&mut self, size: u32) -> &mut Self {
+        self.out_buffer_size = size;
+        self
+    }
+
+    /// Sets how much the kernel reserves for data written by clients.
+    pub fn in_buffer_size(&mut self, size: u32) -> &mut Self {
+        self.in_buffer_size = size;
+        self
+    }
+
+    /// Sets how long `wait_named_pipe` waits for an instance when it's not
+    /// given a timeout. 0 means 50ms.
+    pub fn default_timeout_ms(&mut self, timeout_ms: u32) -> &mut Self {
+        self.default_timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Creates the pipe with the access control from `attrs` instead of the
+    /// default security descriptor.
+    pub fn security(&mut self, attrs: &'a mut PipeSecurityAttributes) -> &mut Self {
+        self.security = Some(attrs);
+        self
+    }
+
+    /// Creates an instance of the pipe `name` and returns its server end.
+    pub fn build_server(&mut self, name: &OsStr) -> io::Result<Handle> {
+        let mut open_mode = match (self.inbound, self.outbound) {
+            (true, true) => c::PIPE_ACCESS_DUPLEX,
+            (true, false) => c::PIPE_ACCESS_INBOUND,
+            (false, true) => c::PIPE_ACCESS_OUTBOUND,
+            (false, false) => {
+                return Err(io::const_io_error!(
+                    io::ErrorKind::InvalidInput,
+                    "a named pipe must be inbound, outbound or both",
+                ));
+            }
+        };
+        if self.first_instance {
+            open_mode |= c::FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+        check_max_instances(self.max_instances)?;
+        open_mode |= self.file_flags();
+
+        let mut pipe_mode = if self.message_mode {
+            c::PIPE_TYPE_MESSAGE | c::PIPE_READMODE_MESSAGE
+        } else {
+            c::PIPE_TYPE_BYTE | c::PIPE_READMODE_BYTE
+        };
+        if self.nonblocking {
+            pipe_mode |= c::PIPE_NOWAIT;
         }
+        if self.reject_remote_clients {
+            pipe_mode |= c::PIPE_REJECT_REMOTE_CLIENTS;
+        }
+
+        let security = match self.security {
+            Some(ref mut attrs) => Some(attrs.to_security_attributes()?),
+            None => None,
+        };
+        let name = to_u16s(name)?;
+        let handle = unsafe {
+            c::CreateNamedPipeW(
+                name.as_ptr(),
+                open_mode,
+                pipe_mode,
+                self.max_instances,
+                self.out_buffer_size,
+                self.in_buffer_size,
+                self.default_timeout_ms,
+                security.as_ref().map_or(ptr::null_mut(), |sa| sa as *const _ as *mut _),
+            )
+        };
+        if handle == c::INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { Handle::from_raw_handle(handle) })
     }
-}
 
-unsafe fn slice_to_end(v: &mut Vec<u8>) -> &mut [u8] {
-    if v.capacity() == 0 {
-        v.reserve(16);
+    /// Connects to the pipe `name` as a client, with the access and flags
+    /// that match a pipe from `build_server` with the same settings.
+    ///
+    /// This fails with `ERROR_PIPE_BUSY` if all instances of the pipe are in
+    /// use, see `wait_named_pipe`.
+    pub fn build_client(&self, name: &OsStr) -> io::Result<Handle> {
+        let mut opts = OpenOptions::new();
+        opts.read(self.outbound);
+        opts.write(self.inbound);
+        // `set_pipe_mode` needs `FILE_WRITE_ATTRIBUTES`, which a read-only
+        // handle doesn't get by default.
+        if self.message_mode && !self.inbound {
+            opts.access_mode(c::GENERIC_READ | c::FILE_WRITE_ATTRIBUTES);
+        }
+        opts.custom_flags(self.file_flags());
+        let handle = File::open(Path::new(name), &opts)?.into_inner();
+        // The client end of a named pipe is always opened in byte read mode.
+        if self.message_mode {
+            handle.set_pipe_mode(PipeReadMode::Message, None)?;
+        }
+        Ok(handle)
     }
-    if v.capacity() == v.len() {
-        v.reserve(1);
+
+    fn file_flags(&self) -> c::DWORD {
+        let mut flags = 0;
+        if self.write_through {
+            flags |= c::FILE_FLAG_WRITE_THROUGH;
+        }
+        if self.overlapped {
+            flags |= c::FILE_FLAG_OVERLAPPED;
+        }
+        flags
     }
-    slice::from_raw_parts_mut(v.as_mut_ptr().add(v.len()), v.capacity() - v.len())
 }
@@ -0,0 +1,2569 @@
+use super::{
+    anon_pipe, anon_pipe_duplex, anon_pipe_with_prefix, anonymous_pipe_name, call_named_pipe,
+    page_size, random_number, read2, read2_apc, read2_iocp, read2_lines, read2_select,
+    read2_strings, read2_timeout, read2_to_writers, read2_with_capacity, read2_with_limit,
+    read_all_pipes, relay, relay_pipe_to_file, slice_to_end, spawn_bidirectional_relay,
+    spawn_pipe_relay, spawn_pipe_relay_counted, spawn_pipe_relay_transform,
+    spawn_pipe_relay_with_buf, spawn_pipe_relay_with_buf_counted, spawn_pipe_relay_with_max_bytes,
+    spawn_pipe_relay_with_name, spawn_pipe_relay_with_priority, spawn_pipe_relay_with_queue,
+    wait_named_pipe, wide_pipe_name, AnonPipe, AsyncPipe, MeteredPipe, NamedPipeBuilder,
+    NamedPipeListener, NamedPipeServer, NamedPipeStream, PipeBuilder, PipeError, PipeErrorKind,
+    PipeSecurityAttributes, Pipes, SelectResult, ThreadPriority, MAX_NAME_TRIES,
+    PIPE_BUFFER_CAPACITY,
+};
+use crate::alloc::{self, AllocError, Allocator, Global, Layout};
+use crate::borrow::Cow;
+use crate::cell::Cell;
+use crate::collections::HashSet;
+use crate::ffi::OsStr;
+use crate::fs::File;
+use crate::io::{self, Cursor, IoSlice, IoSliceMut, Read, Write};
+use crate::mem;
+use crate::os::windows::io::{AsHandle, AsRawHandle, IntoRawHandle, OwnedHandle};
+use crate::ptr::NonNull;
+use crate::sync::atomic::AtomicUsize;
+use crate::sync::atomic::Ordering::SeqCst;
+use crate::sys::c;
+use crate::sys::cvt;
+use crate::sys::handle::{Handle, PipeHandleState, PipeReadMode, PipeWaitMode};
+use crate::sys_common::io::test::tmpdir;
+use crate::sys_common::AsInner;
+use crate::thread;
+use crate::time::Duration;
+
+#[test]
+fn large_buffer_capacity() {
+    const CAPACITY: usize = 1024 * 1024;
+    const LEN: usize = 512 * 1024;
+
+    let pipes = PipeBuilder::new().buffer_capacity(CAPACITY).synchronous(true).build().unwrap();
+
+    // The whole buffer fits in the pipe, so this can't block waiting for a reader.
+    let data: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+    assert_eq!(pipes.ours.write(&data).unwrap(), LEN);
+    drop(pipes.ours);
+
+    let mut read = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        match pipes.theirs.read(&mut buf).unwrap() {
+            0 => break,
+            n => read.extend_from_slice(&buf[..n]),
+        }
+    }
+    assert_eq!(read, data);
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn buffer_capacity_too_large() {
+    let err = PipeBuilder::new().buffer_capacity(usize::MAX).build().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn try_clone() {
+    let Pipes { ours: writer, theirs: reader } = Pipes::new_synchronous(false, false).unwrap();
+
+    // Data written to the original is readable from the clone.
+    let reader2 = reader.try_clone().unwrap();
+    writer.write(b"hello").unwrap();
+    let mut buf = [0; 5];
+    reader2.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Dropping a clone of the writer doesn't close the pipe.
+    let writer2 = writer.try_clone().unwrap();
+    drop(writer2);
+    writer.write(b"world").unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"world");
+
+    // Once every writer is gone, the reader sees EOF.
+    drop(writer);
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn handle_traits() {
+    fn assert_impls<T: AsHandle + AsRawHandle + IntoRawHandle + Into<OwnedHandle>>() {}
+    assert_impls::<AnonPipe>();
+}
+
+#[test]
+fn duplex() {
+    let Pipes { ours, theirs } = anon_pipe_duplex(false).unwrap();
+    let mut buf = [0; 1];
+
+    ours.write(b"a").unwrap();
+    theirs.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"a");
+
+    theirs.write(b"b").unwrap();
+    ours.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"b");
+}
+
+#[test]
+fn message_mode() {
+    let Pipes { ours: writer, theirs: reader } =
+        PipeBuilder::new().message_mode(true).build().unwrap();
+
+    let messages: [&[u8]; 3] = [b"a", b"hello world", &[7; 1000]];
+    for message in messages {
+        assert_eq!(writer.write(message).unwrap(), message.len());
+    }
+
+    // Each message arrives on its own, even though they could all fit in one
+    // buffer.
+    for message in messages {
+        let mut buf = Vec::new();
+        assert_eq!(reader.read_message(&mut buf).unwrap(), message.len());
+        assert_eq!(buf, message);
+    }
+
+    // A plain read into a small buffer reports that there's more to come.
+    writer.write(b"hello").unwrap();
+    let mut buf = [0; 2];
+    let err = reader.read(&mut buf).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(c::ERROR_MORE_DATA as i32));
+    assert_eq!(&buf, b"he");
+}
+
+#[test]
+fn peek() {
+    let Pipes { ours: writer, theirs: reader } = Pipes::new_synchronous(false, false).unwrap();
+    assert_eq!(reader.peek().unwrap().bytes_available, 0);
+
+    writer.write(b"hello").unwrap();
+    assert_eq!(reader.peek().unwrap().bytes_available, 5);
+
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(reader.peek().unwrap().bytes_available, 3);
+}
+
+#[test]
+fn flush() {
+    let Pipes { ours: writer, theirs: reader } = Pipes::new_synchronous(false, false).unwrap();
+
+    // Nothing has been written yet so there's nothing to wait for.
+    writer.flush().unwrap();
+
+    writer.write(b"hello").unwrap();
+    let reader = thread::spawn(move || {
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        reader
+    });
+
+    // Once the flush returns the data has made it to the reader.
+    writer.flush().unwrap();
+    let reader = reader.join().unwrap();
+    assert_eq!(reader.peek().unwrap().bytes_available, 0);
+}
+
+#[test]
+fn info() {
+    const CAPACITY: usize = 128 * 1024;
+    const PIPE_SERVER_END: u32 = 0x00000001;
+
+    let pipes = PipeBuilder::new().buffer_capacity(CAPACITY).build().unwrap();
+    let info = pipes.ours.info().unwrap();
+    assert_eq!(info.out_buffer_size as usize, CAPACITY);
+    assert_eq!(info.in_buffer_size as usize, CAPACITY);
+    assert_eq!(info.max_instances, 1);
+    assert_eq!(info.flags & PIPE_SERVER_END, PIPE_SERVER_END);
+
+    let info = pipes.theirs.info().unwrap();
+    assert_eq!(info.flags & PIPE_SERVER_END, 0);
+}
+
+#[test]
+fn set_pipe_mode() {
+    let Pipes { ours: writer, theirs: reader } =
+        PipeBuilder::new().message_mode(true).build().unwrap();
+    let mut buf = [0; 2];
+
+    // In byte mode, messages can be read in pieces.
+    reader.handle().set_pipe_mode(PipeReadMode::Byte, None).unwrap();
+    writer.write(b"hello").unwrap();
+    assert_eq!(reader.read(&mut buf).unwrap(), 2);
+    let mut rest = [0; 3];
+    reader.read_exact(&mut rest).unwrap();
+    assert_eq!(&rest, b"llo");
+
+    // In message mode, a short read reports the rest of the message.
+    reader.handle().set_pipe_mode(PipeReadMode::Message, None).unwrap();
+    writer.write(b"hello").unwrap();
+    let err = reader.read(&mut buf).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(c::ERROR_MORE_DATA as i32));
+    let mut rest = Vec::new();
+    assert_eq!(reader.read_message(&mut rest).unwrap(), 3);
+    assert_eq!(rest, b"llo");
+}
+
+#[test]
+fn pipe_handle_state() {
+    // Only a pipe created as a message pipe can be switched to message mode.
+    let Pipes { ours: _ours, theirs } = PipeBuilder::new().message_mode(true).build().unwrap();
+    let handle = theirs.handle();
+
+    handle.set_pipe_mode(PipeReadMode::Byte, None).unwrap();
+    let state = handle.pipe_handle_state().unwrap();
+    let expected = PipeHandleState {
+        read_mode: PipeReadMode::Byte,
+        wait_mode: PipeWaitMode::Wait,
+        max_collection_count: 0,
+        collect_data_timeout_ms: 0,
+    };
+    assert_eq!(state, expected);
+
+    handle.set_pipe_mode(PipeReadMode::Message, Some(PipeWaitMode::NoWait)).unwrap();
+    let state = handle.pipe_handle_state().unwrap();
+    assert_eq!(state.read_mode, PipeReadMode::Message);
+    assert_eq!(state.wait_mode, PipeWaitMode::NoWait);
+}
+
+#[test]
+fn wait_named_pipe_blocks() {
+    let name = format!(r"\\.\pipe\__rust_test_wait_named_pipe.{}", crate::process::id());
+
+    // Connect to the only instance there is so that the pipe is busy...
+    let first = create_named_pipe(OsStr::new(&name));
+    let client = File::open(&name).unwrap();
+
+    // ...and only make a new one after a while.
+    let server = thread::spawn({
+        let name = name.clone();
+        move || {
+            thread::sleep(Duration::from_millis(100));
+            (first, client, create_named_pipe(OsStr::new(&name)))
+        }
+    });
+
+    wait_named_pipe(OsStr::new(&name), Some(10_000)).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn wait_named_pipe_not_found() {
+    let name = format!(r"\\.\pipe\__rust_test_wait_named_pipe_missing.{}", crate::process::id());
+    let err = wait_named_pipe(OsStr::new(&name), None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+fn create_named_pipe(name: &OsStr) -> Handle {
+    create_named_pipe_with_flags(name, 0)
+}
+
+fn create_named_pipe_with_flags(name: &OsStr, flags: u32) -> Handle {
+    use crate::os::windows::ffi::OsStrExt;
+    use crate::os::windows::io::FromRawHandle;
+    use crate::ptr;
+
+    let name: Vec<u16> = name.encode_wide().chain(Some(0)).collect();
+    unsafe {
+        let handle = c::CreateNamedPipeW(
+            name.as_ptr(),
+            c::PIPE_ACCESS_DUPLEX | flags,
+            c::PIPE_TYPE_BYTE | c::PIPE_READMODE_BYTE | c::PIPE_WAIT,
+            2,
+            0,
+            0,
+            0,
+            ptr::null_mut(),
+        );
+        assert_ne!(handle, c::INVALID_HANDLE_VALUE, "{}", io::Error::last_os_error());
+        Handle::from_raw_handle(handle)
+    }
+}
+
+#[test]
+fn named_pipe_server_echo() {
+    let name = format!(r"\\.\pipe\__rust_test_named_pipe_server.{}", crate::process::id());
+    let server = NamedPipeServer::bind(OsStr::new(&name), 1).unwrap();
+
+    let server = thread::spawn(move || {
+        let mut clients = 0;
+        server
+            .listen_loop(|conn| {
+                let mut buf = [0; 1];
+                conn.read_exact(&mut buf)?;
+                conn.write_all(&buf)?;
+                clients += 1;
+                Ok(clients < 2)
+            })
+            .unwrap();
+    });
+
+    for byte in [1, 2] {
+        let mut client = connect_named_pipe(OsStr::new(&name));
+        client.write_all(&[byte]).unwrap();
+        let mut buf = [0; 1];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [byte]);
+    }
+    server.join().unwrap();
+}
+
+fn connect_named_pipe(name: &OsStr) -> File {
+    loop {
+        match crate::fs::OpenOptions::new().read(true).write(true).open(name) {
+            Ok(file) => return file,
+            // The server is still busy with the previous client.
+            Err(e) if e.raw_os_error() == Some(c::ERROR_PIPE_BUSY as i32) => {
+                wait_named_pipe(name, None).unwrap()
+            }
+            Err(e) => panic!("failed to connect to {name:?}: {e}"),
+        }
+    }
+}
+
+#[test]
+fn connect_named_pipe_overlapped() {
+    let name = format!(r"\\.\pipe\__rust_test_connect_overlapped.{}", crate::process::id());
+    let server = create_named_pipe_with_flags(OsStr::new(&name), c::FILE_FLAG_OVERLAPPED);
+
+    let accept = thread::spawn(move || server.connect_named_pipe_overlapped().map(|_| server));
+    thread::sleep(Duration::from_millis(100));
+    let _client = connect_named_pipe(OsStr::new(&name));
+    accept.join().unwrap().unwrap();
+}
+
+#[test]
+fn pipe_process_ids() {
+    let pipes = PipeBuilder::new().build().unwrap();
+    let server = pipes.ours.handle();
+    assert_eq!(server.pipe_client_process_id().unwrap(), crate::process::id());
+    assert_eq!(server.pipe_server_process_id().unwrap(), crate::process::id());
+}
+
+#[test]
+fn pipe_session_ids() {
+    let mut session = 0;
+    cvt(unsafe { c::ProcessIdToSessionId(c::GetCurrentProcessId(), &mut session) }).unwrap();
+    let pipes = PipeBuilder::new().build().unwrap();
+    let server = pipes.ours.handle();
+    assert_eq!(server.pipe_server_session_id().unwrap(), session);
+    assert_eq!(server.pipe_client_session_id().unwrap(), session);
+}
+
+const ERROR_NO_TOKEN: i32 = 1008;
+
+/// Opens the current thread's own access token, which it only has while
+/// impersonating.
+fn thread_token() -> io::Result<Handle> {
+    use crate::os::windows::io::FromRawHandle;
+
+    const TOKEN_QUERY: u32 = 0x0008;
+    extern "system" {
+        fn OpenThreadToken(
+            thread: c::HANDLE,
+            access: u32,
+            open_as_self: c::BOOL,
+            token: *mut c::HANDLE,
+        ) -> c::BOOL;
+    }
+
+    let mut token = crate::ptr::null_mut();
+    unsafe {
+        if OpenThreadToken(c::GetCurrentThread(), TOKEN_QUERY, c::TRUE, &mut token) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Handle::from_raw_handle(token))
+    }
+}
+
+#[test]
+fn impersonation_guard_reverts_on_panic() {
+    use crate::panic::{self, AssertUnwindSafe};
+
+    // The server has to read from the pipe before it can impersonate.
+    let Pipes { ours: server, theirs: client } =
+        PipeBuilder::new().ours_readable(true).build().unwrap();
+    client.write(b"a").unwrap();
+    let mut buf = [0; 1];
+    server.read_exact(&mut buf).unwrap();
+
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        let _guard = server.handle().impersonate_named_pipe_client().unwrap();
+        thread_token().unwrap();
+        panic!("unwinding with the guard");
+    }));
+    assert!(res.is_err());
+
+    // Only impersonating threads have a token of their own.
+    let err = thread_token().unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(ERROR_NO_TOKEN));
+}
+
+#[test]
+fn read2_limit_exceeded() {
+    const LIMIT: usize = 1000;
+
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+    drop(child_err);
+    let writer = thread::spawn(move || {
+        // The write fails once the reader gives up, which is expected.
+        let _ = child_out.write(&[0; 10 * LIMIT]);
+    });
+
+    let (mut v1, mut v2) = (Vec::new(), Vec::new());
+    let e = read2_with_limit(out, &mut v1, LIMIT, err, &mut v2, usize::MAX).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::OutOfMemory);
+    assert_eq!(v1.len(), LIMIT + 1);
+    assert!(v2.is_empty());
+    writer.join().unwrap();
+}
+
+#[test]
+fn read2_lines_calls_back_per_line() {
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+    let writer = thread::spawn(move || {
+        // Split lines across writes, and put several in one write.
+        for chunk in ["one\ntw", "o\n", "three\nfour\nfi", "ve\n"] {
+            child_out.write_all(chunk.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        child_err.write_all(b"no newline").unwrap();
+    });
+
+    let (mut lines1, mut lines2) = (Vec::new(), Vec::new());
+    let mut on1 = |line: &[u8]| lines1.push(line.to_vec());
+    let mut on2 = |line: &[u8]| lines2.push(line.to_vec());
+    read2_lines(out, &mut on1, err, &mut on2).unwrap();
+    writer.join().unwrap();
+    assert_eq!(lines1, [&b"one\n"[..], b"two\n", b"three\n", b"four\n", b"five\n"]);
+    assert_eq!(lines2, [b"no newline"]);
+}
+
+#[test]
+fn async_pipe_drop_cancels_read() {
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+    let mut dst = Vec::with_capacity(64);
+    let ptr = dst.as_ptr();
+    let mut pipe = AsyncPipe::new(ours.into_handle(), &mut dst, usize::MAX).unwrap();
+    // Nothing has been written, so the read is left pending.
+    assert!(pipe.schedule_read().unwrap());
+    assert!(!pipe.has_data().unwrap());
+    drop(pipe);
+
+    // The cancelled read was waited for, so the buffer wasn't leaked and is
+    // safe to use again.
+    assert_eq!(dst.as_ptr(), ptr);
+    assert_eq!(dst.capacity(), 64);
+    assert!(dst.is_empty());
+    dst.extend_from_slice(&[1; 64]);
+    let err = theirs.write(b"late").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(dst, [1; 64]);
+}
+
+#[test]
+fn read_into_string_split_code_point() {
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+    let text = "h\u{e9}llo \u{1f980}";
+    let writer = thread::spawn(move || {
+        // Cut both the two-byte and the four-byte code point in half.
+        for chunk in [&text.as_bytes()[..2], &text.as_bytes()[2..9], &text.as_bytes()[9..]] {
+            theirs.write_all(chunk).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+
+    let mut s = String::new();
+    AsyncPipe::with_string(ours.into_handle(), &mut s).unwrap().read_into_string().unwrap();
+    writer.join().unwrap();
+    assert_eq!(s, text);
+}
+
+#[test]
+fn read2_strings_invalid_utf8() {
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+    child_out.write_all("caf\u{e9}".as_bytes()).unwrap();
+    child_err.write_all(b"ok").unwrap();
+    drop((child_out, child_err));
+    let (mut s1, mut s2) = (String::new(), String::new());
+    read2_strings(out, &mut s1, err, &mut s2).unwrap();
+    assert_eq!((&*s1, &*s2), ("caf\u{e9}", "ok"));
+
+    // A stray continuation byte, and a code point cut off by EOF.
+    for bad in [&b"a\x80b"[..], &"\u{e9}".as_bytes()[..1]] {
+        let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+        let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+        child_out.write_all(bad).unwrap();
+        drop((child_out, child_err));
+        let (mut s1, mut s2) = (String::new(), String::new());
+        let e = read2_strings(out, &mut s1, err, &mut s2).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn read2_iocp_matches_read2() {
+    type Read2 = fn(AnonPipe, &mut Vec<u8>, AnonPipe, &mut Vec<u8>) -> io::Result<()>;
+
+    fn run(read2: Read2) -> (Vec<u8>, Vec<u8>) {
+        let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+        let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+        let writer = thread::spawn(move || {
+            for i in 0..100u32 {
+                child_out.write_all(&vec![i as u8; 1000 + i as usize]).unwrap();
+                if i % 10 == 0 {
+                    child_err.write_all(format!("line {i}\n").as_bytes()).unwrap();
+                }
+            }
+        });
+        let (mut v1, mut v2) = (Vec::new(), Vec::new());
+        read2(out, &mut v1, err, &mut v2).unwrap();
+        writer.join().unwrap();
+        (v1, v2)
+    }
+
+    let (out, err) = run(read2);
+    assert_eq!(out.len(), (0..100).map(|i| 1000 + i).sum::<usize>());
+    assert!(err.starts_with(b"line 0\n"));
+    let (iocp_out, iocp_err) = run(read2_iocp);
+    assert!(iocp_out == out);
+    assert_eq!(iocp_err, err);
+    let (apc_out, apc_err) = run(read2_apc);
+    assert!(apc_out == out);
+    assert_eq!(apc_err, err);
+}
+
+#[test]
+fn read_overlapped_ex_calls_back_once_per_read() {
+    // Only this test uses these, and the callback only runs on its thread.
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static READ: AtomicUsize = AtomicUsize::new(0);
+    static ERROR: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "system" fn callback(error: u32, amt: u32, _: *mut c::OVERLAPPED) {
+        ERROR.store(error as usize, SeqCst);
+        READ.store(amt as usize, SeqCst);
+        CALLS.fetch_add(1, SeqCst);
+    }
+
+    let Pipes { ours: reader, theirs: writer } = anon_pipe(true, false).unwrap();
+    let mut buf = [0; 16];
+    let mut overlapped: c::OVERLAPPED = unsafe { mem::zeroed() };
+    for (i, msg) in [&b"first"[..], b"second"].into_iter().enumerate() {
+        writer.write_all(msg).unwrap();
+        let started =
+            unsafe { reader.handle().read_overlapped_ex(&mut buf, &mut overlapped, callback) };
+        assert_eq!(started.unwrap(), None);
+        // The read is likely done already, but nothing's called until the
+        // thread is alertable.
+        assert_eq!(CALLS.load(SeqCst), i);
+        while CALLS.load(SeqCst) == i {
+            unsafe { c::SleepEx(c::INFINITE, c::TRUE) };
+        }
+        assert_eq!(ERROR.load(SeqCst), c::ERROR_SUCCESS as usize);
+        assert_eq!(&buf[..READ.load(SeqCst)], msg);
+    }
+    unsafe { c::SleepEx(0, c::TRUE) };
+    assert_eq!(CALLS.load(SeqCst), 2);
+
+    drop(writer);
+    let eof = unsafe { reader.handle().read_overlapped_ex(&mut buf, &mut overlapped, callback) };
+    assert_eq!(eof.unwrap(), Some(0));
+}
+
+#[test]
+fn write_overlapped_ex_reports_bytes_written() {
+    static WRITTEN: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+    unsafe extern "system" fn callback(error: u32, amt: u32, _: *mut c::OVERLAPPED) {
+        WRITTEN.store(if error == c::ERROR_SUCCESS { amt as usize } else { 0 }, SeqCst);
+    }
+
+    let Pipes { ours: writer, theirs: reader } = anon_pipe(false, false).unwrap();
+    let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    let mut overlapped: c::OVERLAPPED = unsafe { mem::zeroed() };
+    unsafe { writer.handle().write_overlapped_ex(&data, &mut overlapped, callback).unwrap() };
+    while WRITTEN.load(SeqCst) == usize::MAX {
+        unsafe { c::SleepEx(c::INFINITE, c::TRUE) };
+    }
+    assert_eq!(WRITTEN.load(SeqCst), 1000);
+
+    drop(writer);
+    let mut read = Vec::new();
+    reader.read_to_end(&mut read).unwrap();
+    assert!(read == data);
+}
+
+#[test]
+fn write_all_vectored_past_capacity() {
+    // Together the slices are more than the pipe holds, so the writes are
+    // partial until the reader catches up.
+    let slices: Vec<Vec<u8>> =
+        (0..5).map(|i| vec![i as u8; PIPE_BUFFER_CAPACITY as usize / 3 + i]).collect();
+    let expected = slices.concat();
+
+    let Pipes { ours: writer, theirs: reader } = anon_pipe(false, false).unwrap();
+    let reader = thread::spawn(move || {
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        read
+    });
+    let bufs: Vec<IoSlice<'_>> = slices.iter().map(|s| IoSlice::new(s)).collect();
+    writer.write_all_vectored(&bufs).unwrap();
+    drop(writer);
+    assert!(reader.join().unwrap() == expected);
+}
+
+#[test]
+fn read_at_most() {
+    let Pipes { ours: reader, theirs: writer } = anon_pipe(true, false).unwrap();
+    let mut buf = b"kept".to_vec();
+    writer.write_all(b"0123456789").unwrap();
+    assert_eq!(reader.read_at_most(4, &mut buf).unwrap(), 4);
+    assert_eq!(buf, b"kept0123");
+
+    // Asking for more than is waiting only gets what's there.
+    assert_eq!(reader.read_at_most(100, &mut buf).unwrap(), 6);
+    assert_eq!(buf, b"kept0123456789");
+
+    drop(writer);
+    assert_eq!(reader.read_at_most(100, &mut buf).unwrap(), 0);
+    assert_eq!(buf.len(), 14);
+}
+
+#[test]
+fn send_to_process() {
+    use crate::os::windows::io::FromRawHandle;
+
+    let Pipes { ours: reader, theirs: writer } = anon_pipe(true, false).unwrap();
+    // Sending to ourselves is the only way to check the new handle works.
+    let raw = writer.send_to_process(unsafe { c::GetCurrentProcess() }).unwrap();
+    let writer = unsafe { Handle::from_raw_handle(raw) };
+    writer.write(b"moved").unwrap();
+    drop(writer);
+    let mut read = Vec::new();
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, b"moved");
+}
+
+#[test]
+fn metered_pipe_counts() {
+    let Pipes { ours: reader, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (reader, writer) = (MeteredPipe::new(reader), MeteredPipe::new(writer));
+    let metrics = reader.metrics();
+    assert_eq!(metrics.read_latency_percentile(50), None);
+
+    for chunk in [&b"one"[..], b"three", b"fifteen bytes!!"] {
+        writer.write(chunk).unwrap();
+        let mut buf = [0; 32];
+        assert_eq!(reader.read(&mut buf).unwrap(), chunk.len());
+    }
+    assert_eq!((metrics.reads(), metrics.bytes_read()), (3, 23));
+    assert_eq!((metrics.writes(), metrics.bytes_written()), (0, 0));
+    let written = writer.metrics();
+    assert_eq!((written.writes(), written.bytes_written()), (3, 23));
+    assert_eq!(metrics.read_latency_ns().iter().sum::<u64>(), 3);
+    assert!(metrics.read_latency_percentile(100).is_some());
+    assert!(metrics.read_latency_percentile(0) <= metrics.read_latency_percentile(100));
+}
+
+#[test]
+fn read_vectored_fills_each_buffer() {
+    let Pipes { ours: reader, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (mut a, mut b, mut c) = ([0; 10], vec![0; 10], Box::new([0; 10]));
+    writer.write_all(&[b'a'; 10]).unwrap();
+    writer.write_all(&[b'b'; 10]).unwrap();
+    writer.write_all(&[b'c'; 10]).unwrap();
+    // Only asynchronous pipes read into more than the first buffer.
+    assert!(reader.is_read_vectored());
+    assert!(!writer.is_read_vectored());
+    let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b), IoSliceMut::new(&mut *c)];
+    assert_eq!(reader.read_vectored(&mut bufs).unwrap(), 30);
+    assert_eq!((a, &b[..], *c), ([b'a'; 10], &[b'b'; 10][..], [b'c'; 10]));
+
+    // With less waiting than fits, this returns what there is rather than
+    // waiting for more.
+    writer.write_all(&[b'd'; 15]).unwrap();
+    let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b), IoSliceMut::new(&mut *c)];
+    assert_eq!(reader.read_vectored(&mut bufs).unwrap(), 15);
+    assert_eq!(&b[..5], [b'd'; 5]);
+}
+
+#[test]
+fn read2_apc_reads_to_eof() {
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+    child_err.write_all(b"only stderr").unwrap();
+    drop((child_out, child_err));
+    let (mut v1, mut v2) = (Vec::new(), Vec::new());
+    read2_apc(out, &mut v1, err, &mut v2).unwrap();
+    assert!(v1.is_empty());
+    assert_eq!(v2, b"only stderr");
+}
+
+#[test]
+fn read2_select_reports_first_pipe() {
+    fn run(p1_first: bool) -> (SelectResult, Vec<u8>, Vec<u8>) {
+        let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+        let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+        let writer = thread::spawn(move || {
+            let (early, late) =
+                if p1_first { (child_out, child_err) } else { (child_err, child_out) };
+            early.write_all(b"early").unwrap();
+            thread::sleep(Duration::from_millis(200));
+            late.write_all(b"late").unwrap();
+        });
+        let (mut v1, mut v2) = (Vec::new(), Vec::new());
+        let select = read2_select(out, &mut v1, err, &mut v2).unwrap();
+        writer.join().unwrap();
+        (select, v1, v2)
+    }
+
+    assert_eq!(run(true), (SelectResult::First, b"early".to_vec(), b"late".to_vec()));
+    assert_eq!(run(false), (SelectResult::Second, b"late".to_vec(), b"early".to_vec()));
+
+    // Data that's already waiting in both pipes turns up together.
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+    child_out.write_all(b"out").unwrap();
+    child_err.write_all(b"err").unwrap();
+    drop((child_out, child_err));
+    let (mut v1, mut v2) = (Vec::new(), Vec::new());
+    assert_eq!(read2_select(out, &mut v1, err, &mut v2).unwrap(), SelectResult::Both);
+    assert_eq!((&v1[..], &v2[..]), (&b"out"[..], &b"err"[..]));
+
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+    drop((child_out, child_err));
+    let (mut v1, mut v2) = (Vec::new(), Vec::new());
+    assert_eq!(read2_select(out, &mut v1, err, &mut v2).unwrap(), SelectResult::Neither);
+}
+
+#[test]
+fn read2_to_writers_round_trip() {
+    const LEN: usize = 256 * 1024;
+
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let data2 = data.clone();
+    let writer = thread::spawn(move || {
+        child_out.write_all(&data2).unwrap();
+        child_err.write_all(b"some errors").unwrap();
+    });
+
+    let (mut w1, mut w2) = (Cursor::new(Vec::new()), Cursor::new(Vec::new()));
+    read2_to_writers(out, &mut w1, err, &mut w2).unwrap();
+    writer.join().unwrap();
+    assert!(w1.into_inner() == data);
+    assert_eq!(w2.into_inner(), b"some errors");
+}
+
+#[test]
+fn read2_to_writers_sink_error() {
+    struct Fails;
+    impl Write for Fails {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::const_io_error!(io::ErrorKind::Other, "sink failed"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    // The writing end of `err` is never closed, so this would block forever
+    // if the error didn't stop it.
+    let Pipes { ours: err, theirs: _child_err } = anon_pipe(true, false).unwrap();
+    child_out.write(b"hello").unwrap();
+
+    let mut w2 = Vec::new();
+    let e = read2_to_writers(out, &mut Fails, err, &mut w2).unwrap_err();
+    assert_eq!(e.to_string(), "sink failed");
+    assert!(w2.is_empty());
+}
+
+#[test]
+fn read2_with_capacity_avoids_reallocating() {
+    const LEN: usize = 1024 * 1024;
+
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    let Pipes { ours: err, theirs: child_err } = anon_pipe(true, false).unwrap();
+    drop(child_err);
+    let writer = thread::spawn(move || child_out.write_all(&vec![1; LEN]).unwrap());
+
+    let (mut v1, mut v2) = (Vec::new(), Vec::new());
+    read2_with_capacity(out, &mut v1, 2 * LEN, err, &mut v2, 0).unwrap();
+    writer.join().unwrap();
+    assert_eq!(v1, vec![1; LEN]);
+    // The vector never filled up, so it was never grown past what was
+    // reserved up front.
+    assert_eq!(v1.capacity(), 2 * LEN);
+    assert!(v2.is_empty());
+}
+
+#[test]
+fn read_all_pipes_three() {
+    let mut readers = Vec::new();
+    let mut writers = Vec::new();
+    for i in 0..3u8 {
+        let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+        readers.push(ours);
+        writers.push(thread::spawn(move || {
+            // Make the pipes finish at different times.
+            for _ in 0..=i {
+                thread::sleep(Duration::from_millis(10));
+                theirs.write(&[i; 100]).unwrap();
+            }
+        }));
+    }
+
+    let mut outputs = [Vec::new(), Vec::new(), Vec::new()];
+    read_all_pipes(readers.into_iter().zip(outputs.iter_mut()).collect()).unwrap();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    for (i, output) in outputs.iter().enumerate() {
+        assert_eq!(*output, vec![i as u8; 100 * (i + 1)]);
+    }
+}
+
+#[test]
+fn read2_times_out() {
+    use crate::time::Instant;
+
+    let Pipes { ours: out, theirs: child_out } = anon_pipe(true, false).unwrap();
+    let Pipes { ours: err, theirs: _child_err } = anon_pipe(true, false).unwrap();
+    child_out.write(b"partial").unwrap();
+
+    // Neither pipe is ever closed on the writing end.
+    let (mut v1, mut v2) = (Vec::new(), Vec::new());
+    let start = Instant::now();
+    let e = read2_timeout(out, &mut v1, err, &mut v2, Duration::from_millis(200)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    // Timer resolution means the wait may end a little early.
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert_eq!(v1, b"partial");
+    assert!(v2.is_empty());
+
+    // Our end of the pipe has been closed.
+    assert_eq!(child_out.write(b"more").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn async_pipe_drop_cancels_only_its_read() {
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+    let clone = ours.try_clone().unwrap();
+
+    let (mut v1, mut v2) = (Vec::new(), Vec::new());
+    let mut p1 = AsyncPipe::new(ours.into_handle(), &mut v1, usize::MAX).unwrap();
+    let mut p2 = AsyncPipe::new(clone.into_handle(), &mut v2, usize::MAX).unwrap();
+    assert!(p1.schedule_read().unwrap());
+    assert!(p2.schedule_read().unwrap());
+
+    // Cancelling the first read leaves the second one pending.
+    drop(p1);
+    theirs.write(b"hello").unwrap();
+    assert!(p2.result().unwrap());
+    drop(p2);
+    assert_eq!(v2, b"hello");
+}
+
+#[test]
+fn cancel_io_ex_null_cancels_everything() {
+    use crate::mem;
+    use crate::ptr;
+
+    let Pipes { ours, theirs: _theirs } = anon_pipe(true, false).unwrap();
+    let handle = ours.handle();
+    let events = [Handle::new_event(true, false).unwrap(), Handle::new_event(true, false).unwrap()];
+    let mut overlapped: [c::OVERLAPPED; 2] = unsafe { mem::zeroed() };
+    let mut bufs = [[0; 16]; 2];
+    for i in 0..2 {
+        overlapped[i].hEvent = events[i].as_raw_handle();
+        let res = unsafe { handle.read_overlapped(&mut bufs[i], &mut overlapped[i]) };
+        assert_eq!(res.unwrap(), None);
+    }
+
+    handle.cancel_io_ex(ptr::null_mut()).unwrap();
+    for overlapped in &mut overlapped {
+        let err = handle.overlapped_result(overlapped, true).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(c::ERROR_OPERATION_ABORTED as i32));
+    }
+}
+
+#[test]
+fn read_overlapped_timeout() {
+    use crate::mem;
+    use crate::time::Instant;
+
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+    let handle = ours.handle();
+    let mut overlapped: c::OVERLAPPED = unsafe { mem::zeroed() };
+    let mut buf = [0; 16];
+
+    let start = Instant::now();
+    let res = handle.read_overlapped_timeout(&mut buf, &mut overlapped, Duration::from_millis(100));
+    assert_eq!(res.unwrap(), None);
+    // Timer resolution means the wait may end a little early.
+    assert!(start.elapsed() >= Duration::from_millis(75));
+
+    theirs.write(b"hello").unwrap();
+    let res = handle.read_overlapped_timeout(&mut buf, &mut overlapped, Duration::from_secs(10));
+    assert_eq!(res.unwrap(), Some(5));
+    assert_eq!(&buf[..5], b"hello");
+}
+
+#[test]
+fn write_vectored() {
+    let Pipes { ours: writer, theirs: reader } = anon_pipe(false, false).unwrap();
+    assert!(writer.is_write_vectored());
+    let bufs = [IoSlice::new(b"hello "), IoSlice::new(b"vectored "), IoSlice::new(b"world")];
+    assert_eq!(writer.write_vectored(&bufs).unwrap(), 20);
+    let mut buf = [0; 20];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello vectored world");
+}
+
+#[test]
+fn write_vectored_large() {
+    const CAP: usize = PIPE_BUFFER_CAPACITY as usize;
+
+    let Pipes { ours: writer, theirs: reader } = anon_pipe(false, false).unwrap();
+    let reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        buf
+    });
+    // At most one pipe buffer's worth is copied and written at a time, and a
+    // large first buffer is written as it is.
+    let (small, large) = (vec![1; 10], vec![2; 2 * CAP]);
+    let n1 = writer.write_vectored(&[IoSlice::new(&small), IoSlice::new(&large)]).unwrap();
+    assert!(n1 > small.len() && n1 <= CAP, "{n1}");
+    let n2 = writer.write_vectored(&[IoSlice::new(&large), IoSlice::new(&small)]).unwrap();
+    assert!(n2 <= large.len(), "{n2}");
+    drop(writer);
+
+    let mut expected = small.clone();
+    expected.extend_from_slice(&large[..n1 - small.len()]);
+    expected.extend_from_slice(&large[..n2]);
+    assert!(reader.join().unwrap() == expected);
+}
+
+#[test]
+fn write_vectored_page_aligned() {
+    let page_size = page_size();
+    let layout = Layout::from_size_align(page_size * 2, page_size).unwrap();
+    unsafe {
+        let ptr = alloc::alloc(layout);
+        assert!(!ptr.is_null());
+        let pages = crate::slice::from_raw_parts_mut(ptr, page_size * 2);
+        pages[..page_size].fill(b'a');
+        pages[page_size..].fill(b'b');
+
+        // Whether or not the pipe supports `WriteFileGather`, both pages
+        // arrive in order.
+        let Pipes { ours: writer, theirs: reader } = anon_pipe(false, false).unwrap();
+        let (first, second) = pages.split_at(page_size);
+        let bufs = [IoSlice::new(second), IoSlice::new(first)];
+        let t = thread::spawn(move || {
+            let mut buf = vec![0; page_size * 2];
+            reader.read_exact(&mut buf).unwrap();
+            buf
+        });
+        assert_eq!(writer.write_vectored(&bufs).unwrap(), page_size * 2);
+        let buf = t.join().unwrap();
+        assert!(buf[..page_size].iter().all(|&b| b == b'b'));
+        assert!(buf[page_size..].iter().all(|&b| b == b'a'));
+
+        alloc::dealloc(ptr, layout);
+    }
+}
+
+#[test]
+fn relay_buffer_size_out_of_range() {
+    let Pipes { ours, theirs: _theirs } = anon_pipe(true, false).unwrap();
+    for size in [0, 64 * 1024 * 1024 + 1] {
+        let err = spawn_pipe_relay_with_buf(ours.handle(), false, false, size).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn relay_with_name() {
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, relay) =
+        spawn_pipe_relay_with_name(source.handle(), false, false, "stdout-relay").unwrap();
+    drop(source);
+    assert_eq!(relay.thread().name(), Some("stdout-relay"));
+
+    writer.write_all(b"named").unwrap();
+    drop(writer);
+    let mut read = Vec::new();
+    out.read_to_end(&mut read).unwrap();
+    assert_eq!(read, b"named");
+    relay.join().unwrap().unwrap();
+
+    let err = spawn_pipe_relay_with_name(out.handle(), true, false, "a\0b").err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn relay_with_priority() {
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, relay) =
+        spawn_pipe_relay_with_priority(source.handle(), false, false, ThreadPriority::ABOVE_NORMAL)
+            .unwrap();
+    drop(source);
+
+    // Once data has made it through, the relay has set its priority.
+    writer.write_all(b"x").unwrap();
+    let mut buf = [0; 1];
+    out.read_exact(&mut buf).unwrap();
+    let priority = unsafe { c::GetThreadPriority(relay.as_raw_handle()) };
+    assert_eq!(priority, ThreadPriority::ABOVE_NORMAL.as_raw());
+
+    drop(writer);
+    assert_eq!(out.read(&mut buf).unwrap(), 0);
+    relay.join().unwrap().unwrap();
+}
+
+#[test]
+fn relay_transform() {
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, relay) = spawn_pipe_relay_transform(source.handle(), false, false, |buf| {
+        if !buf.contains(&b'\n') {
+            return Cow::Borrowed(buf);
+        }
+        let mut converted = Vec::with_capacity(buf.len() * 2);
+        for &b in buf {
+            if b == b'\n' {
+                converted.push(b'\r');
+            }
+            converted.push(b);
+        }
+        Cow::Owned(converted)
+    })
+    .unwrap();
+    drop(source);
+
+    writer.write_all(b"one\ntwo\n\nthree").unwrap();
+    drop(writer);
+    let mut read = Vec::new();
+    out.read_to_end(&mut read).unwrap();
+    assert_eq!(read, b"one\r\ntwo\r\n\r\nthree");
+    relay.join().unwrap().unwrap();
+}
+
+#[test]
+fn relay_pipe_to_file_copies_everything() {
+    const LEN: usize = 10 * 1024 * 1024;
+
+    let tmp = tmpdir();
+    let path = tmp.join("relayed");
+    let file = File::create(&path).unwrap();
+    let Pipes { ours: reader, theirs: writer } = anon_pipe(true, false).unwrap();
+    let relay = relay_pipe_to_file(reader, file.as_inner()).unwrap();
+    drop(file);
+
+    let pattern = |i: usize| (i % 251) as u8;
+    let data: Vec<u8> = (0..LEN).map(pattern).collect();
+    writer.write_all(&data).unwrap();
+    drop(writer);
+    assert_eq!(relay.join().unwrap().unwrap(), LEN as u64);
+
+    let written = crate::fs::read(&path).unwrap();
+    assert_eq!(written.len(), LEN);
+    assert!(written.iter().enumerate().all(|(i, &b)| b == pattern(i)));
+}
+
+#[test]
+fn relay_with_max_bytes() {
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, relay) = spawn_pipe_relay_with_max_bytes(source.handle(), false, false, 100).unwrap();
+    drop(source);
+
+    // All of this is waiting in the pipe before the relay reads any of it,
+    // so the relay's read has to stop at the limit.
+    let data: Vec<u8> = (0..150).collect();
+    writer.write_all(&data).unwrap();
+    let mut read = Vec::new();
+    out.read_to_end(&mut read).unwrap();
+    assert_eq!(read, &data[..100]);
+    relay.join().unwrap().unwrap();
+}
+
+#[test]
+fn relay_counts_bytes() {
+    const LEN: usize = 1024 * 1024;
+
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, relay, written) =
+        spawn_pipe_relay_with_buf_counted(source.handle(), false, false, 64 * 1024).unwrap();
+    drop(source);
+
+    let producer = thread::spawn(move || writer.write_all(&vec![7; LEN]).unwrap());
+    let mut read = Vec::new();
+    out.read_to_end(&mut read).unwrap();
+    producer.join().unwrap();
+    relay.join().unwrap().unwrap();
+    assert_eq!(read.len(), LEN);
+    assert_eq!(written.load(SeqCst), 1_048_576);
+
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, relay, written) = spawn_pipe_relay_counted(source.handle(), false, false).unwrap();
+    drop(source);
+    writer.write_all(b"counted").unwrap();
+    drop(writer);
+    out.read_to_end(&mut Vec::new()).unwrap();
+    relay.join().unwrap().unwrap();
+    assert_eq!(written.load(SeqCst), 7);
+}
+
+#[test]
+fn relay_reports_broken_pipe() {
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, relay) = spawn_pipe_relay(source.handle(), false, false).unwrap();
+    drop(source);
+    assert_eq!(relay.thread().name(), Some("pipe-relay"));
+
+    // With nothing left to read from the relay, passing this on has to fail.
+    drop(out);
+    writer.write(b"hello").unwrap();
+    drop(writer);
+    let err = relay.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn relay_queue_drains_source() {
+    const CHUNK: usize = 4096;
+    const QUEUE_LEN: usize = 32;
+    // More than the two pipes' buffers and the relay's own buffer hold, but
+    // not more than that plus the queue.
+    const LEN: usize = 3 * PIPE_BUFFER_CAPACITY as usize;
+
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, relay) =
+        spawn_pipe_relay_with_queue(source.handle(), false, false, CHUNK, QUEUE_LEN).unwrap();
+    drop(source);
+
+    // Nothing reads from `out` until everything has been written.
+    let data: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+    let expected = data.clone();
+    let producer = thread::spawn(move || writer.write_all(&data));
+    let start = crate::time::Instant::now();
+    while !producer.is_finished() {
+        assert!(start.elapsed() < Duration::from_secs(10), "the producer is stuck");
+        thread::sleep(Duration::from_millis(10));
+    }
+    producer.join().unwrap().unwrap();
+
+    let mut read = Vec::new();
+    out.read_to_end(&mut read).unwrap();
+    assert!(read == expected);
+    relay.join().unwrap().unwrap();
+}
+
+fn relay_destination_closed(queued: bool) {
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, relay) = if queued {
+        spawn_pipe_relay_with_queue(source.handle(), false, false, 4096, 16).unwrap()
+    } else {
+        spawn_pipe_relay(source.handle(), false, false).unwrap()
+    };
+    drop(source);
+
+    writer.write_all(b"first").unwrap();
+    let mut buf = [0; 5];
+    out.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"first");
+
+    // This chunk is read, but can't be written anywhere.
+    drop(out);
+    writer.write_all(b"second").unwrap();
+    let err = relay.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+    // Nothing is left reading the source, so nothing more is swallowed.
+    let err = writer.write(b"third").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn relay_destination_closed_direct() {
+    relay_destination_closed(false);
+}
+
+#[test]
+fn relay_destination_closed_queued() {
+    relay_destination_closed(true);
+}
+
+fn bench_relay(b: &mut test::Bencher, buf_size: usize) {
+    const BYTES: usize = 1024 * 1024;
+
+    // Data written to `writer` goes through the relay thread to `out`.
+    let Pipes { ours: source, theirs: writer } = anon_pipe(true, false).unwrap();
+    let (out, _relay) = spawn_pipe_relay_with_buf(source.handle(), false, false, buf_size).unwrap();
+    drop(source);
+    thread::spawn(move || {
+        let data = vec![0u8; 64 * 1024];
+        // This stops once the relay is gone, after `out` is dropped below.
+        while writer.write(&data).is_ok() {}
+    });
+
+    let mut buf = vec![0u8; BYTES];
+    b.bytes = BYTES as u64;
+    b.iter(|| out.read_exact(&mut buf).unwrap());
+}
+
+#[bench]
+fn bench_relay_4k(b: &mut test::Bencher) {
+    bench_relay(b, 4 * 1024);
+}
+
+#[bench]
+fn bench_relay_64k(b: &mut test::Bencher) {
+    bench_relay(b, 64 * 1024);
+}
+
+#[bench]
+fn bench_relay_1m(b: &mut test::Bencher) {
+    bench_relay(b, 1024 * 1024);
+}
+
+#[test]
+fn bidirectional_relay() {
+    const LEN: usize = 32 * 1024;
+
+    // Data goes from `theirs1` through the relay into `theirs2`, and back.
+    let Pipes { ours: ours1, theirs: theirs1 } = anon_pipe_duplex(false).unwrap();
+    let Pipes { ours: ours2, theirs: theirs2 } = anon_pipe_duplex(false).unwrap();
+    let (a_to_b, b_to_a) = spawn_bidirectional_relay(ours1.handle(), ours2.handle()).unwrap();
+    drop((ours1, ours2));
+
+    let forward: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+    let backward: Vec<u8> = (0..LEN).map(|i| (i / 7) as u8).collect();
+
+    let (forward2, backward2) = (forward.clone(), backward.clone());
+    let t = thread::spawn(move || {
+        assert_eq!(theirs1.write(&forward2).unwrap(), LEN);
+        let mut buf = vec![0; LEN];
+        theirs1.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, backward2);
+    });
+    assert_eq!(theirs2.write(&backward).unwrap(), LEN);
+    let mut buf = vec![0; LEN];
+    theirs2.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, forward);
+    t.join().unwrap();
+
+    // Closing both ends stops both relay threads.
+    drop(theirs2);
+    a_to_b.join().unwrap().unwrap();
+    b_to_a.join().unwrap().unwrap();
+}
+
+#[test]
+fn bidirectional_relay_eof() {
+    let Pipes { ours: ours1, theirs: theirs1 } = anon_pipe_duplex(false).unwrap();
+    let Pipes { ours: ours2, theirs: theirs2 } = anon_pipe_duplex(false).unwrap();
+    let (a_to_b, b_to_a) = spawn_bidirectional_relay(ours1.handle(), ours2.handle()).unwrap();
+    drop((ours1, ours2));
+
+    // `theirs2` waits for EOF before closing its end, so this only finishes
+    // if `theirs1` closing shuts down the `b_to_a` thread too.
+    theirs1.write_all(b"ping").unwrap();
+    drop(theirs1);
+    let mut buf = Vec::new();
+    theirs2.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"ping");
+    a_to_b.join().unwrap().unwrap();
+    b_to_a.join().unwrap().unwrap();
+}
+
+#[test]
+fn read_exact() {
+    let Pipes { ours: reader, theirs: writer } = anon_pipe(true, false).unwrap();
+    let t = thread::spawn(move || {
+        writer.write(b"hello").unwrap();
+        // Give the reader a chance to see the first half on its own.
+        thread::sleep(Duration::from_millis(50));
+        writer.write(b"world").unwrap();
+    });
+    let mut buf = [0; 10];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"helloworld");
+    t.join().unwrap();
+
+    // The writer is gone now, so there's nothing more to read.
+    let err = reader.read_exact(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn write_all() {
+    const LEN: usize = 4 * PIPE_BUFFER_CAPACITY as usize;
+
+    let Pipes { ours: writer, theirs: reader } = anon_pipe(false, false).unwrap();
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let t = thread::spawn(move || {
+        let mut buf = vec![0; LEN];
+        reader.read_exact(&mut buf).unwrap();
+        buf
+    });
+    writer.write_all(&data).unwrap();
+    assert_eq!(t.join().unwrap(), data);
+}
+
+/// Counts how often a `Vec` is reallocated to grow it.
+#[derive(Default)]
+struct CountGrows(Cell<usize>);
+
+unsafe impl Allocator for CountGrows {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        Global.deallocate(ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.set(self.0.get() + 1);
+        Global.grow(ptr, old_layout, new_layout)
+    }
+}
+
+#[test]
+fn read_to_end() {
+    const LEN: usize = 512 * 1024;
+
+    let Pipes { ours: reader, theirs: writer } = anon_pipe(true, false).unwrap();
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let data2 = data.clone();
+    let t = thread::spawn(move || writer.write_all(&data2).unwrap());
+
+    let grows = CountGrows::default();
+    let mut buf = Vec::new_in(&grows);
+    assert_eq!(reader.read_to_end(&mut buf).unwrap(), LEN);
+    assert!(buf == data);
+    t.join().unwrap();
+
+    // Doubling from 16 bytes up to `LEN`.
+    assert!(grows.0.get() <= (LEN / 16).trailing_zeros() as usize, "{} grows", grows.0.get());
+}
+
+#[test]
+fn slice_to_end_grows_geometrically() {
+    const LEN: usize = 10 * 1024 * 1024;
+
+    let grows = CountGrows::default();
+    let mut v = Vec::new_in(&grows);
+    while v.len() < LEN {
+        let len = v.len();
+        let amt = unsafe { slice_to_end(&mut v).len() };
+        assert!(amt > 0);
+        unsafe { v.set_len(len + amt) };
+    }
+
+    // Doubling from 8 KiB up to `LEN`.
+    let max = (LEN / 8192).next_power_of_two().trailing_zeros() as usize;
+    assert!(grows.0.get() <= max, "{} grows", grows.0.get());
+}
+
+#[test]
+fn copy_to() {
+    const LEN: usize = 256 * 1024;
+
+    for (src_async, dst_async) in [(true, true), (true, false), (false, true)] {
+        let (src, producer) = pipe_pair(true, src_async);
+        let (dst, consumer) = pipe_pair(false, dst_async);
+        let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+        let data2 = data.clone();
+        let p = thread::spawn(move || producer.write_all(&data2).unwrap());
+        let c = thread::spawn(move || {
+            let mut buf = Vec::new();
+            consumer.read_to_end(&mut buf).unwrap();
+            buf
+        });
+        assert_eq!(src.copy_to(&dst).unwrap(), LEN as u64);
+        drop(dst);
+        p.join().unwrap();
+        assert!(c.join().unwrap() == data);
+    }
+}
+
+/// Creates a pipe, returning `(ours, theirs)`.
+///
+/// `ours` is asynchronous if `ours_async` is set, and `theirs` is always
+/// synchronous.
+fn pipe_pair(ours_readable: bool, ours_async: bool) -> (AnonPipe, AnonPipe) {
+    let Pipes { ours, theirs } =
+        PipeBuilder::new().ours_readable(ours_readable).synchronous(!ours_async).build().unwrap();
+    (ours, theirs)
+}
+
+fn bench_copy(b: &mut test::Bencher, double_buffered: bool) {
+    const LEN: usize = 10 * 1024 * 1024;
+    const CHUNK: usize = 64 * 1024;
+
+    b.bytes = LEN as u64;
+    b.iter(|| {
+        let (src, producer) = pipe_pair(true, true);
+        let (dst, consumer) = pipe_pair(false, true);
+        let p = thread::spawn(move || {
+            let chunk = vec![0; CHUNK];
+            for _ in 0..LEN / CHUNK {
+                producer.write_all(&chunk).unwrap();
+            }
+        });
+        let c = thread::spawn(move || {
+            let mut buf = vec![0; CHUNK];
+            while consumer.read(&mut buf).unwrap() != 0 {}
+        });
+        let copied = if double_buffered {
+            src.copy_to(&dst).unwrap()
+        } else {
+            relay(&src, &dst, &mut vec![0; CHUNK]).unwrap()
+        };
+        assert_eq!(copied, LEN as u64);
+        drop(dst);
+        p.join().unwrap();
+        c.join().unwrap();
+    });
+}
+
+#[bench]
+fn bench_copy_single_buffer(b: &mut test::Bencher) {
+    bench_copy(b, false);
+}
+
+#[bench]
+fn bench_copy_double_buffer(b: &mut test::Bencher) {
+    bench_copy(b, true);
+}
+
+#[test]
+fn set_non_blocking() {
+    let Pipes { ours: reader, theirs: writer } = Pipes::new_synchronous(true, false).unwrap();
+    reader.set_non_blocking(true).unwrap();
+    let mut buf = [0; 5];
+    let err = reader.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    writer.write(b"hello").unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Back in blocking mode, a closed pipe is reported as EOF again.
+    reader.set_non_blocking(false).unwrap();
+    drop(writer);
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn set_non_blocking_read_only_client() {
+    // `theirs` is the client end, opened for reading only.
+    let Pipes { ours: writer, theirs: reader } = Pipes::new_synchronous(false, false).unwrap();
+    reader.set_non_blocking(true).unwrap();
+    let mut buf = [0; 5];
+    let err = reader.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    writer.write(b"hello").unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    reader.set_non_blocking(false).unwrap();
+}
+
+#[test]
+fn poll_read() {
+    let Pipes { ours: reader, theirs: writer } = anon_pipe(true, false).unwrap();
+    assert_eq!(reader.poll_read().unwrap(), 0);
+    writer.write_all(&[1; 100]).unwrap();
+    assert_eq!(reader.poll_read().unwrap(), 100);
+    let mut buf = [0; 100];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(reader.poll_read().unwrap(), 0);
+}
+
+#[test]
+fn anonymous_pipe_names_are_unique() {
+    const COUNT: usize = 10_000;
+
+    let names: Vec<String> =
+        (0..COUNT).map(|_| anonymous_pipe_name("__rust_anonymous_pipe1__").unwrap()).collect();
+    let unique: HashSet<&String> = names.iter().collect();
+    assert_eq!(unique.len(), COUNT);
+
+    // With a zero seed the counter would start right at the beginning.
+    let zero_seeded = format!(r"\\.\pipe\__rust_anonymous_pipe1__.{}.", crate::process::id());
+    for name in &names {
+        let n: usize = name.strip_prefix(&zero_seeded).unwrap().parse().unwrap();
+        assert!(n > COUNT * 2, "{name} looks like it came from a zero seed");
+    }
+}
+
+#[test]
+fn random_numbers_unique_across_threads() {
+    let threads: Vec<_> = (0..16)
+        .map(|_| {
+            thread::spawn(|| (0..10_000).map(|_| random_number().unwrap()).collect::<Vec<_>>())
+        })
+        .collect();
+    let mut seen = HashSet::new();
+    for t in threads {
+        for n in t.join().unwrap() {
+            assert!(seen.insert(n), "{n} was returned twice");
+        }
+    }
+}
+
+#[bench]
+fn bench_random_number_16_threads(b: &mut test::Bencher) {
+    b.iter(|| {
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..1_000_000 {
+                        crate::hint::black_box(random_number().unwrap());
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+    });
+}
+
+#[test]
+fn many_threads_create_pipes() {
+    let threads: Vec<_> = (0..64)
+        .map(|_| thread::spawn(|| (0..16).map(|_| anon_pipe(true, false)).collect::<Vec<_>>()))
+        .collect();
+    for t in threads {
+        for pipes in t.join().unwrap() {
+            pipes.unwrap();
+        }
+    }
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn debug_name() {
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+    let name = ours.debug_name().unwrap();
+    let prefix = format!(r"\\.\pipe\__rust_anonymous_pipe1__.{}.", crate::process::id());
+    let n = name.strip_prefix(&prefix).unwrap();
+    assert!(n.parse::<usize>().is_ok(), "{name}");
+    assert_eq!(theirs.debug_name(), Some(name));
+
+    let Pipes { ours, .. } = Pipes::new_synchronous(true, false).unwrap();
+    assert_eq!(ours.debug_name(), None);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn name_prefix() {
+    let Pipes { ours, theirs } = anon_pipe_with_prefix("__rust_test_prefix", true, false).unwrap();
+    let prefix = format!(r"\\.\pipe\__rust_test_prefix.{}.", crate::process::id());
+    let name = ours.debug_name().unwrap();
+    assert!(name.starts_with(&prefix), "{name}");
+    theirs.write(b"hello").unwrap();
+    let mut buf = [0; 5];
+    ours.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let err = anon_pipe_with_prefix(r"a\b", true, false).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn wide_pipe_name_limit() {
+    let name = format!(r"\\.\pipe\{}", "é".repeat(247));
+    let wide = wide_pipe_name(&name).unwrap();
+    assert!(wide[..256].iter().copied().eq(name.encode_utf16()));
+    assert_eq!(wide[256], 0);
+
+    // The prefix pushes the whole name over the limit.
+    let err = anon_pipe_with_prefix(&"x".repeat(256), true, false).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let err = wide_pipe_name("a\0b").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[bench]
+fn bench_anon_pipe(b: &mut test::Bencher) {
+    b.iter(|| anon_pipe(true, false).unwrap());
+}
+
+#[test]
+fn from_raw_handles() {
+    let mut read = crate::ptr::null_mut();
+    let mut write = crate::ptr::null_mut();
+    assert_ne!(unsafe { c::CreatePipe(&mut read, &mut write, crate::ptr::null(), 0) }, 0);
+    let Pipes { ours, theirs } = unsafe { Pipes::from_raw_handles(write, read, false) };
+    assert!(!ours.is_write_vectored());
+
+    ours.write_all(b"round trip").unwrap();
+    let mut buf = [0; 10];
+    theirs.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"round trip");
+}
+
+/// Answers each message on `server` with the same message until the client
+/// goes away.
+fn spawn_echo_server(server: AnonPipe) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            let mut message = Vec::new();
+            match server.read_message(&mut message).unwrap() {
+                0 => return,
+                _ => assert_eq!(server.write(&message).unwrap(), message.len()),
+            }
+        }
+    })
+}
+
+#[test]
+fn transact() {
+    let Pipes { ours, theirs } =
+        PipeBuilder::new().duplex(true).message_mode(true).build().unwrap();
+    let server = spawn_echo_server(ours);
+
+    let mut response = Vec::new();
+    assert_eq!(theirs.transact(b"ping", &mut response).unwrap(), 4);
+    assert_eq!(response, b"ping");
+
+    // Much bigger than what `response` has room for at first.
+    let request: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let mut response = Vec::with_capacity(16);
+    assert_eq!(theirs.transact(&request, &mut response).unwrap(), request.len());
+    assert!(response == request);
+
+    drop(theirs);
+    server.join().unwrap();
+}
+
+#[test]
+fn transact_byte_mode() {
+    let Pipes { ours: _ours, theirs } = anon_pipe_duplex(false).unwrap();
+    let err = theirs.transact(b"ping", &mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn call_named_pipe() {
+    use crate::os::windows::ffi::OsStrExt;
+    use crate::os::windows::io::FromRawHandle;
+    use crate::ptr;
+
+    let name = format!(r"\\.\pipe\__rust_test_call_named_pipe.{}", crate::process::id());
+    let wide_name: Vec<u16> = OsStr::new(&name).encode_wide().chain(Some(0)).collect();
+    let server = unsafe {
+        let handle = c::CreateNamedPipeW(
+            wide_name.as_ptr(),
+            c::PIPE_ACCESS_DUPLEX,
+            c::PIPE_TYPE_MESSAGE | c::PIPE_READMODE_MESSAGE | c::PIPE_WAIT,
+            1,
+            0,
+            0,
+            0,
+            ptr::null_mut(),
+        );
+        assert_ne!(handle, c::INVALID_HANDLE_VALUE, "{}", io::Error::last_os_error());
+        Handle::from_raw_handle(handle)
+    };
+
+    // Answers "big" with 10,000 bytes and anything else with the request
+    // itself, and stops after "quit".
+    let server = thread::spawn(move || {
+        loop {
+            let res = unsafe { c::ConnectNamedPipe(server.as_raw_handle(), ptr::null_mut()) };
+            if res == 0 {
+                let err = io::Error::last_os_error();
+                assert_eq!(err.raw_os_error(), Some(c::ERROR_PIPE_CONNECTED as i32), "{err}");
+            }
+            let mut buf = [0; 64];
+            let n = server.read(&mut buf).unwrap();
+            let reply = if &buf[..n] == b"big" { vec![7; 10_000] } else { buf[..n].to_vec() };
+            // The client may already be gone if the reply was too big for it.
+            let _ = server.write(&reply);
+            unsafe { c::FlushFileBuffers(server.as_raw_handle()) };
+            unsafe { c::DisconnectNamedPipe(server.as_raw_handle()) };
+            if &buf[..n] == b"quit" {
+                return;
+            }
+        }
+    });
+
+    let name = OsStr::new(&name);
+    assert_eq!(call_named_pipe(name, b"hello", None).unwrap(), b"hello");
+    assert_eq!(call_named_pipe(name, b"big", Some(5000)).unwrap(), vec![7; 10_000]);
+    assert_eq!(call_named_pipe(name, b"quit", None).unwrap(), b"quit");
+    server.join().unwrap();
+}
+
+#[test]
+fn security_attributes() {
+    // S-1-1-0, the "Everyone" group.
+    #[repr(C)]
+    struct WorldSid {
+        revision: u8,
+        sub_authority_count: u8,
+        identifier_authority: [u8; 6],
+        sub_authority: [u32; 1],
+    }
+    let world = WorldSid {
+        revision: 1,
+        sub_authority_count: 1,
+        identifier_authority: [0, 0, 0, 0, 0, 1],
+        sub_authority: [0],
+    };
+    let world = unsafe { &*(&world as *const WorldSid as *const c::SID) };
+
+    // Opening `theirs` needs access to the pipe, so denying it to everyone
+    // makes building the pipe fail.
+    let mut denied = PipeSecurityAttributes::new();
+    unsafe { denied.deny_sid(world, c::GENERIC_READ | c::GENERIC_WRITE) };
+    let err = PipeBuilder::new().security(&mut denied).build().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+    let mut allowed = PipeSecurityAttributes::new();
+    unsafe { allowed.allow_sid(world, c::GENERIC_READ | c::GENERIC_WRITE) };
+    for synchronous in [false, true] {
+        let Pipes { ours, theirs } =
+            PipeBuilder::new().synchronous(synchronous).security(&mut allowed).build().unwrap();
+        theirs.write(b"hello").unwrap();
+        let mut buf = [0; 5];
+        ours.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
+
+#[test]
+fn into_reader_and_writer() {
+    for synchronous in [false, true] {
+        let Pipes { ours, theirs } =
+            PipeBuilder::new().ours_readable(true).synchronous(synchronous).build().unwrap();
+
+        let err = theirs.try_clone().unwrap().into_reader().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = ours.try_clone().unwrap().into_writer().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut reader = ours.into_reader().unwrap();
+        let mut writer = theirs.into_writer().unwrap();
+        writer.write_all(b"hello").unwrap();
+        drop(writer);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+        assert_eq!(reader.into_anon_pipe().is_async(), !synchronous);
+    }
+
+    let Pipes { ours, theirs: _theirs } = anon_pipe_duplex(false).unwrap();
+    let reader = ours.into_reader().unwrap().into_anon_pipe();
+    reader.into_writer().unwrap();
+}
+
+#[test]
+fn swap_pipes() {
+    let mut pipes = anon_pipe(true, false).unwrap();
+    pipes.swap();
+    assert!(pipes.ours.is_sync());
+    assert!(pipes.theirs.is_async());
+    pipes.ours.write(b"hello").unwrap();
+    let mut buf = [0; 5];
+    pipes.theirs.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let Pipes { ours, theirs } = pipes.reversed();
+    theirs.write(b"again").unwrap();
+    ours.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"again");
+}
+
+#[test]
+fn is_async() {
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+    assert!(ours.is_async() && !ours.is_sync());
+    assert!(theirs.is_sync() && !theirs.is_async());
+
+    let Pipes { ours, theirs } = Pipes::new_synchronous(true, false).unwrap();
+    assert!(ours.is_sync());
+    assert!(theirs.is_sync());
+}
+
+#[test]
+fn upgrade_to_async() {
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+    let ours = ours.upgrade_to_async().unwrap();
+    assert!(ours.is_async());
+
+    // Writes on an asynchronous pipe go through `alertable_io_internal`.
+    let theirs = theirs.upgrade_to_async().unwrap();
+    assert!(theirs.is_async());
+    theirs.write(b"hello").unwrap();
+    let mut buf = [0; 5];
+    ours.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn downgrade_to_sync() {
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+    let theirs = theirs.downgrade_to_sync().unwrap();
+    assert!(theirs.is_sync());
+    let ours = ours.downgrade_to_sync().unwrap();
+    assert!(ours.is_sync());
+
+    // A plain blocking `ReadFile`, with no `OVERLAPPED` involved.
+    theirs.write(b"hello").unwrap();
+    let mut buf = [0; 5];
+    assert_eq!(ours.handle().read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn read_timeout() {
+    use crate::time::Instant;
+
+    for ours_async in [true, false] {
+        let (ours, _theirs) = pipe_pair(true, ours_async);
+        assert_eq!(ours.read_timeout(), None);
+        ours.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert_eq!(ours.read_timeout(), Some(Duration::from_millis(100)));
+
+        let start = Instant::now();
+        let err = ours.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(75), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(200), "{elapsed:?}");
+
+        ours.set_read_timeout(None).unwrap();
+        assert_eq!(ours.read_timeout(), None);
+    }
+}
+
+#[test]
+fn read_timeout_with_data() {
+    for ours_async in [true, false] {
+        let (ours, theirs) = pipe_pair(true, ours_async);
+        ours.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        theirs.write(b"hello").unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(ours.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        // EOF isn't a timeout.
+        drop(theirs);
+        assert_eq!(ours.read(&mut buf).unwrap(), 0);
+    }
+}
+
+#[test]
+fn write_timeout_partly_read() {
+    const LEN: usize = 4 * PIPE_BUFFER_CAPACITY as usize;
+
+    let (ours, theirs) = pipe_pair(false, true);
+    ours.set_write_timeout(Some(Duration::from_millis(300))).unwrap();
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let reader = thread::spawn(move || {
+        // Take part of the write while it's still in progress, then stop.
+        thread::sleep(Duration::from_millis(50));
+        let mut buf = vec![0; 1000];
+        theirs.read_exact(&mut buf).unwrap();
+        (theirs, buf)
+    });
+    // The write is cut short by the timeout, but what was taken already
+    // counts as written rather than as a timeout.
+    let written = ours.write(&data).unwrap();
+    let (theirs, mut read) = reader.join().unwrap();
+    drop(ours);
+    theirs.read_to_end(&mut read).unwrap();
+    assert_eq!(read.len(), written);
+    assert!(read == data[..written]);
+}
+
+#[test]
+fn write_timeout() {
+    let (ours, _theirs) = pipe_pair(false, true);
+    ours.set_write_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert_eq!(ours.write_timeout(), Some(Duration::from_millis(100)));
+    // Nothing reads from the pipe, so this can't all fit.
+    let err = ours.write(&vec![0; 4 * PIPE_BUFFER_CAPACITY as usize]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    let (ours, _theirs) = pipe_pair(false, false);
+    let err = ours.set_write_timeout(Some(Duration::from_millis(100))).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    ours.set_write_timeout(None).unwrap();
+
+    let err = ours.set_read_timeout(Some(Duration::ZERO)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+fn is_inheritable(pipe: &AnonPipe) -> bool {
+    let mut flags = 0;
+    let res = unsafe { c::GetHandleInformation(pipe.handle().as_raw_handle(), &mut flags) };
+    assert_ne!(res, 0, "{}", io::Error::last_os_error());
+    flags & c::HANDLE_FLAG_INHERIT != 0
+}
+
+#[test]
+fn new_synchronous_inheritable() {
+    for inheritable in [false, true] {
+        let Pipes { ours, theirs } = Pipes::new_synchronous(true, inheritable).unwrap();
+        assert!(!is_inheritable(&ours));
+        assert_eq!(is_inheritable(&theirs), inheritable);
+
+        theirs.write(b"hello").unwrap();
+        let mut buf = [0; 5];
+        ours.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
+
+#[test]
+fn named_event() {
+    let name = format!(r"Local\__rust_test_named_event.{}", crate::process::id());
+    let name = OsStr::new(&name);
+    let event = Handle::new_named_event(name, true, false).unwrap();
+
+    let name2 = name.to_os_string();
+    let t = thread::spawn(move || {
+        let event = Handle::open_named_event(&name2, c::EVENT_MODIFY_STATE, false).unwrap();
+        assert_ne!(unsafe { c::SetEvent(event.as_raw_handle()) }, 0);
+    });
+    let res = unsafe { c::WaitForSingleObject(event.as_raw_handle(), 10_000) };
+    assert_eq!(res, c::WAIT_OBJECT_0);
+    t.join().unwrap();
+
+    let missing = OsStr::new(r"Local\__rust_test_named_event.missing");
+    let err = Handle::open_named_event(missing, c::SYNCHRONIZE, false).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn alertable_io_completes_synchronously() {
+    // The data is already in the pipe, so the `ReadFileEx` completes
+    // straight away, and the small write fits in the pipe's buffer. Both
+    // still have to wait for their completion routine.
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap();
+    let theirs = theirs.upgrade_to_async().unwrap();
+    for _ in 0..100 {
+        assert_eq!(theirs.write(b"hello").unwrap(), 5);
+        let mut buf = [0; 5];
+        assert_eq!(ours.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+    }
+}
+
+#[test]
+fn try_read() {
+    for ours_async in [true, false] {
+        let (ours, theirs) = pipe_pair(true, ours_async);
+        let mut buf = [0; 16];
+        let err = ours.try_read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        theirs.write(b"hello").unwrap();
+        assert_eq!(ours.try_read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(ours.try_read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        drop(theirs);
+        assert_eq!(ours.try_read(&mut buf).unwrap(), 0);
+    }
+}
+
+#[test]
+fn try_write() {
+    for ours_async in [true, false] {
+        let (ours, theirs) = pipe_pair(false, ours_async);
+        assert_eq!(ours.try_write(b"hello").unwrap(), 5);
+
+        // Fill the pipe's buffer, then there's no space left.
+        let chunk = vec![0; 4096];
+        loop {
+            match ours.try_write(&chunk) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("unexpected error: {e}"),
+            }
+        }
+        let err = ours.try_write(&chunk).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // Ordinary blocking writes still block once it's done.
+        if !ours_async {
+            assert_eq!(ours.handle().pipe_handle_state().unwrap().wait_mode, PipeWaitMode::Wait);
+        }
+
+        let mut buf = [0; 5];
+        theirs.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
+
+#[test]
+fn inherit_all() {
+    for ours_async in [true, false] {
+        let (ours, theirs) = pipe_pair(true, ours_async);
+        let Pipes { ours, theirs } = Pipes { ours, theirs }.inherit_all().unwrap();
+        assert!(ours.is_sync());
+        assert!(is_inheritable(&ours));
+        assert!(is_inheritable(&theirs));
+
+        theirs.write(b"hello").unwrap();
+        drop(theirs);
+        let mut buf = Vec::new();
+        ours.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+    }
+
+    // A child that inherits both ends and exits without using them doesn't
+    // get in the way once it has gone.
+    let Pipes { ours, theirs } = anon_pipe(true, false).unwrap().inherit_all().unwrap();
+    let status = crate::process::Command::new("cmd").args(["/C", "exit"]).status().unwrap();
+    assert!(status.success());
+    theirs.write(b"hello").unwrap();
+    drop(theirs);
+    let mut buf = Vec::new();
+    ours.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"hello");
+}
+
+#[test]
+fn stdio_for_child() {
+    use crate::process::{self, Command};
+    use crate::sys_common::FromInner;
+
+    let (stdout, ours) = anon_pipe(true, false).unwrap().stdio_for_child();
+    let mut cmd = Command::new("cmd");
+    cmd.args(["/C", "echo hello"]).stdout(process::Stdio::from_inner(stdout));
+    let mut child = cmd.spawn().unwrap();
+    // `cmd` still holds our copy of the writing end.
+    drop(cmd);
+    let mut buf = Vec::new();
+    ours.read_to_end(&mut buf).unwrap();
+    assert!(child.wait().unwrap().success());
+    assert_eq!(buf, b"hello\r\n");
+}
+
+#[test]
+fn anon_pipe_io_traits() {
+    use crate::io::{BufRead, BufReader};
+
+    for ours_async in [true, false] {
+        let (ours, mut theirs) = pipe_pair(true, ours_async);
+        let writer = thread::spawn(move || {
+            // Through `io::Write`, rather than the inherent methods.
+            let w: &mut dyn Write = &mut theirs;
+            w.write_all(b"one\ntwo\n").unwrap();
+            w.write_all_vectored(&mut [IoSlice::new(b"thr"), IoSlice::new(b"ee")]).unwrap();
+            w.flush().unwrap();
+        });
+
+        let lines = BufReader::new(ours).lines().collect::<io::Result<Vec<_>>>().unwrap();
+        writer.join().unwrap();
+        assert_eq!(lines, ["one", "two", "three"]);
+    }
+}
+
+#[test]
+fn debug_and_display() {
+    for ours_async in [true, false] {
+        let (ours, theirs) = pipe_pair(true, ours_async);
+        let handle = format!("{:?}", ours.as_raw_handle());
+        let variant = if ours_async { "Async" } else { "Sync" };
+        assert_eq!(format!("{ours:?}"), format!("AnonPipe::{variant}({handle})"));
+        let mode = variant.to_lowercase();
+        assert_eq!(ours.to_string(), format!("anonymous pipe ({mode}, handle={handle})"));
+
+        let pipes = format!("{:?}", Pipes { ours, theirs });
+        assert!(pipes.contains(&handle), "{pipes}");
+    }
+
+    let (ours, _theirs) = pipe_pair(true, true);
+    let handle = format!("{:?}", ours.as_raw_handle());
+    let mut dst = b"abc".to_vec();
+    let pipe = AsyncPipe::new(ours.into_handle(), &mut dst, usize::MAX).unwrap();
+    let debug = format!("{pipe:?}");
+    assert!(debug.contains(&handle) && debug.contains("buf_len: 3"), "{debug}");
+}
+
+#[test]
+fn anon_pipe_eq() {
+    let (ours, theirs) = pipe_pair(true, true);
+    assert_eq!(ours, ours);
+    // The two ends are different file objects.
+    assert_ne!(ours, theirs);
+
+    let clone = ours.try_clone().unwrap();
+    // Without `CompareObjectHandles` there's no telling that they're the
+    // same object.
+    if c::CompareObjectHandles::option().is_some() {
+        assert_eq!(clone, ours);
+        assert_ne!(clone, theirs);
+    }
+}
+
+#[test]
+fn take_one_end() {
+    // With the other end closed, reading sees EOF instead of blocking.
+    let ours = anon_pipe(true, false).unwrap().take_ours();
+    let mut buf = Vec::new();
+    assert_eq!(ours.read_to_end(&mut buf).unwrap(), 0);
+
+    let theirs = anon_pipe(false, false).unwrap().take_theirs();
+    assert_eq!(theirs.read_to_end(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn duplicate() {
+    for ours_async in [true, false] {
+        let (ours, theirs) = pipe_pair(true, ours_async);
+        let dup = ours.duplicate(0, false).unwrap();
+        assert_eq!(dup.is_async(), ours_async);
+        assert!(!is_inheritable(&dup));
+        assert!(is_inheritable(&ours.duplicate(0, true).unwrap()));
+
+        // Each read takes its own data, whichever handle it's through.
+        theirs.write(b"hello").unwrap();
+        let mut buf = [0; 3];
+        dup.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hel");
+        drop(dup);
+        theirs.write(b"!").unwrap();
+        drop(theirs);
+        let mut buf = Vec::new();
+        ours.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"lo!");
+    }
+}
+
+#[test]
+fn pipe_error_kinds() {
+    // Creating the first instance of a pipe that already exists is denied.
+    let name = format!(r"\\.\pipe\__rust_test_pipe_error_kinds.{}", crate::process::id());
+    let _server = NamedPipeServer::bind(OsStr::new(&name), 1).unwrap();
+    let err = PipeError::from(NamedPipeServer::bind(OsStr::new(&name), 1).unwrap_err());
+    assert!(matches!(err.kind(), PipeErrorKind::AccessDenied));
+
+    let (ours, theirs) = pipe_pair(false, false);
+    drop(theirs);
+    let err = PipeError::from(ours.write(b"hello").unwrap_err());
+    assert!(matches!(err.kind(), PipeErrorKind::PipeDisconnected));
+    // Converting back gives the original error.
+    assert_eq!(io::Error::from(err).raw_os_error(), Some(c::ERROR_NO_DATA as i32));
+
+    let err = PipeError::from(io::Error::from_raw_os_error(c::ERROR_NOT_ENOUGH_QUOTA as i32));
+    assert!(matches!(err.kind(), PipeErrorKind::BufferFull));
+
+    // Callers of `build` see the same kind of error as `ERROR_ACCESS_DENIED`,
+    // along with what was tried.
+    let name = r"\\.\pipe\__rust_anonymous_pipe1__.1.2";
+    let err = io::Error::from(PipeError::name_collision(MAX_NAME_TRIES, name.to_string()));
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    let msg = err.to_string();
+    assert!(msg.contains("after 64 attempts"), "{msg}");
+    assert!(msg.contains(name) && msg.contains("os error 5"), "{msg}");
+
+    let err = PipeBuilder::new().synchronous(true).duplex(true).try_build().unwrap_err();
+    match err.kind() {
+        PipeErrorKind::Other(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        kind => panic!("unexpected error kind: {kind:?}"),
+    }
+}
+
+#[test]
+fn is_connected() {
+    for ours_async in [true, false] {
+        let (ours, theirs) = pipe_pair(true, ours_async);
+        assert!(ours.is_connected());
+        theirs.write(b"hello").unwrap();
+        drop(theirs);
+        // The pipe only counts as disconnected once it has been drained.
+        assert!(ours.is_connected());
+        let mut buf = Vec::new();
+        ours.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+        assert!(!ours.is_connected());
+    }
+}
+
+#[test]
+fn available_bytes() {
+    for ours_async in [true, false] {
+        let (ours, theirs) = pipe_pair(true, ours_async);
+        assert_eq!(ours.available_bytes().unwrap(), 0);
+        theirs.write_all(&[1; 100]).unwrap();
+        theirs.write_all(&[2; 50]).unwrap();
+        assert_eq!(ours.available_bytes().unwrap(), 150);
+
+        let mut buf = [0; 120];
+        ours.read_exact(&mut buf).unwrap();
+        assert_eq!(ours.available_bytes().unwrap(), 30);
+        assert_eq!(ours.available_bytes().unwrap() as usize, ours.poll_read().unwrap());
+    }
+}
+
+#[test]
+fn next_message_size() {
+    let Pipes { ours: reader, theirs: writer } =
+        PipeBuilder::new().ours_readable(true).message_mode(true).build().unwrap();
+    assert_eq!(reader.next_message_size().unwrap(), None);
+
+    writer.write(&[7; 1000]).unwrap();
+    writer.write(b"hello").unwrap();
+    let size = reader.next_message_size().unwrap().unwrap();
+    assert_eq!(size, 1000);
+    let mut buf = vec![0; size as usize];
+    assert_eq!(reader.read(&mut buf).unwrap(), 1000);
+    assert_eq!(reader.next_message_size().unwrap(), Some(5));
+    assert_eq!(reader.read(&mut buf).unwrap(), 5);
+    assert_eq!(reader.next_message_size().unwrap(), None);
+
+    let (ours, theirs) = pipe_pair(true, true);
+    theirs.write(b"hello").unwrap();
+    assert_eq!(ours.next_message_size().unwrap(), None);
+}
+
+#[test]
+fn named_pipe_listener() {
+    let name = format!(r"\\.\pipe\__rust_test_named_pipe_listener.{}", crate::process::id());
+    let mut listener = NamedPipeListener::bind(OsStr::new(&name)).unwrap();
+
+    let server = thread::spawn(move || {
+        let mut served = 0;
+        for stream in listener.by_ref().take(3) {
+            let mut stream = stream.unwrap();
+            let mut buf = [0; 1];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+            stream.flush().unwrap();
+            served += 1;
+        }
+        listener.close();
+        assert!(listener.accept().is_err());
+        assert!(listener.next().is_none());
+        served
+    });
+
+    for byte in [1, 2, 3] {
+        let mut client = connect_named_pipe(OsStr::new(&name));
+        client.write_all(&[byte]).unwrap();
+        let mut buf = [0; 1];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [byte]);
+    }
+    assert_eq!(server.join().unwrap(), 3);
+}
+
+#[test]
+fn named_pipe_stream_connect() {
+    let name = format!(r"\\.\pipe\__rust_test_named_pipe_stream.{}", crate::process::id());
+    let listener = NamedPipeListener::bind(OsStr::new(&name)).unwrap();
+    let server = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        stream.flush().unwrap();
+    });
+
+    let mut client = NamedPipeStream::connect(OsStr::new(&name)).unwrap();
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+    server.join().unwrap();
+
+    let missing = OsStr::new(r"\\.\pipe\__rust_test_named_pipe_stream.missing");
+    let err = NamedPipeStream::connect(missing).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn named_pipe_stream_connect_timeout() {
+    let name = format!(r"\\.\pipe\__rust_test_connect_timeout.{}", crate::process::id());
+    let name = OsStr::new(&name);
+    let listener = NamedPipeListener::bind(name).unwrap();
+
+    // The first client takes the only instance there is, and no more are
+    // created until it's accepted.
+    let _first = NamedPipeStream::connect_timeout(name, Duration::from_secs(10)).unwrap();
+    let err = NamedPipeStream::connect_timeout(name, Duration::from_millis(100)).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    let server = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        let stream = listener.accept().unwrap();
+        (listener, stream)
+    });
+    let _second = NamedPipeStream::connect_timeout(name, Duration::from_secs(10)).unwrap();
+    drop(server.join().unwrap());
+}
+
+#[test]
+fn named_pipe_stream_message_mode() {
+    use crate::os::windows::io::FromRawHandle;
+
+    let name = format!(r"\\.\pipe\__rust_test_stream_message_mode.{}", crate::process::id());
+    let wide_name: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+    let server = unsafe {
+        let handle = c::CreateNamedPipeW(
+            wide_name.as_ptr(),
+            c::PIPE_ACCESS_DUPLEX,
+            c::PIPE_TYPE_MESSAGE | c::PIPE_READMODE_MESSAGE | c::PIPE_WAIT,
+            1,
+            0,
+            0,
+            0,
+            crate::ptr::null_mut(),
+        );
+        assert_ne!(handle, c::INVALID_HANDLE_VALUE, "{}", io::Error::last_os_error());
+        Handle::from_raw_handle(handle)
+    };
+
+    let mut client = NamedPipeStream::connect(OsStr::new(&name)).unwrap();
+    assert!(!client.is_message_mode().unwrap());
+    client.set_message_mode(true).unwrap();
+    assert!(client.is_message_mode().unwrap());
+
+    server.write(b"hello").unwrap();
+    server.write(b"world").unwrap();
+    let mut buf = [0; 64];
+    assert_eq!(client.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(client.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"world");
+
+    client.set_message_mode(false).unwrap();
+    assert!(!client.is_message_mode().unwrap());
+}
+
+#[test]
+fn named_pipe_stream_peer_process_id() {
+    let name = format!(r"\\.\pipe\__rust_test_peer_process_id.{}", crate::process::id());
+    let listener = NamedPipeListener::bind(OsStr::new(&name)).unwrap();
+    let client = NamedPipeStream::connect(OsStr::new(&name)).unwrap();
+    let server = listener.accept().unwrap();
+    assert_eq!(server.peer_process_id().unwrap(), crate::process::id());
+    assert_eq!(client.peer_process_id().unwrap(), crate::process::id());
+}
+
+#[test]
+fn named_pipe_stream_impersonate() {
+    let name = format!(r"\\.\pipe\__rust_test_stream_impersonate.{}", crate::process::id());
+    let listener = NamedPipeListener::bind(OsStr::new(&name)).unwrap();
+    let mut client = NamedPipeStream::connect(OsStr::new(&name)).unwrap();
+    let mut server = listener.accept().unwrap();
+
+    // Nothing has been read from the client yet.
+    let err = server.impersonate().err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+    client.write_all(b"a").unwrap();
+    let mut buf = [0; 1];
+    server.read_exact(&mut buf).unwrap();
+    let guard = server.impersonate().unwrap();
+    thread_token().unwrap();
+    drop(guard);
+    let err = thread_token().unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(ERROR_NO_TOKEN));
+}
+
+#[test]
+fn named_pipe_builder() {
+    const PIPE_SERVER_END: u32 = 0x00000001;
+
+    let name = format!(r"\\.\pipe\__rust_test_named_pipe_builder.{}", crate::process::id());
+    let name = OsStr::new(&name);
+    let mut builder = NamedPipeBuilder::new();
+    builder
+        .write_through(true)
+        .message_mode(true)
+        .max_instances(4)
+        .out_buffer_size(8192)
+        .in_buffer_size(4096);
+    let server = builder.first_instance(true).build_server(name).unwrap();
+    let client = builder.build_client(name).unwrap();
+
+    let info = server.pipe_info().unwrap();
+    assert_eq!(info.flags, PIPE_SERVER_END | c::PIPE_TYPE_MESSAGE);
+    assert_eq!(info.max_instances, 4);
+    assert_eq!(info.out_buffer_size, 8192);
+    assert_eq!(info.in_buffer_size, 4096);
+    let info = client.pipe_info().unwrap();
+    assert_eq!(info.flags, c::PIPE_TYPE_MESSAGE);
+
+    // Both ways, whole messages.
+    server.write(b"hello").unwrap();
+    server.write(b"world").unwrap();
+    let mut buf = [0; 64];
+    assert_eq!(client.read(&mut buf).unwrap(), 5);
+    client.write(b"hi").unwrap();
+    assert_eq!(server.read(&mut buf).unwrap(), 2);
+
+    // Another instance can't be created as the first one.
+    let err = builder.build_server(name).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    builder.first_instance(false).build_server(name).unwrap();
+
+    let err = NamedPipeBuilder::new().inbound(false).outbound(false).build_server(name).err();
+    assert_eq!(err.unwrap().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn named_pipe_builder_read_only_message_client() {
+    let name = format!(r"\\.\pipe\__rust_test_read_only_message.{}", crate::process::id());
+    let name = OsStr::new(&name);
+    let mut builder = NamedPipeBuilder::new();
+    builder.inbound(false).message_mode(true);
+    // The client only reads, but can still be switched to message mode.
+    let server = builder.build_server(name).unwrap();
+    let client = builder.build_client(name).unwrap();
+
+    server.write(b"hello").unwrap();
+    server.write(b"world").unwrap();
+    let mut buf = [0; 64];
+    assert_eq!(client.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(client.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"world");
+}
+
+#[test]
+fn named_pipe_listener_max_instances() {
+    let name = format!(r"\\.\pipe\__rust_test_listener_max_instances.{}", crate::process::id());
+    let listener = NamedPipeListener::with_max_instances(OsStr::new(&name), 2).unwrap();
+    let _a = NamedPipeStream::connect(OsStr::new(&name)).unwrap();
+    let server_a = listener.accept().unwrap();
+    let _b = NamedPipeStream::connect(OsStr::new(&name)).unwrap();
+    let _server_b = listener.accept().unwrap();
+    assert_eq!(listener.instance_count(), 2);
+
+    let name2 = name.clone();
+    let client = thread::spawn(move || NamedPipeStream::connect(OsStr::new(&name2)).map(drop));
+    let server = thread::spawn(move || {
+        let stream = listener.accept().unwrap();
+        let count = listener.instance_count();
+        drop(stream);
+        count
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert!(!server.is_finished());
+    assert!(!client.is_finished());
+
+    drop(server_a);
+    client.join().unwrap().unwrap();
+    assert_eq!(server.join().unwrap(), 2);
+
+    for max_instances in [0, 256] {
+        let err = NamedPipeListener::with_max_instances(OsStr::new(&name), max_instances).err();
+        assert_eq!(err.unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn named_pipe_server_accept_overlapped() {
+    let name = format!(r"\\.\pipe\__rust_test_accept_overlapped.{}", crate::process::id());
+    let name = OsStr::new(&name);
+    let server = NamedPipeServer::bind_overlapped(name, 1).unwrap();
+    let event = Handle::new_event(true, false).unwrap();
+
+    // Nobody has connected yet.
+    let mut overlapped: c::OVERLAPPED = unsafe { crate::mem::zeroed() };
+    assert!(!unsafe { server.accept_overlapped(&event, &mut overlapped) }.unwrap());
+    let mut client = connect_named_pipe(name);
+    let res = unsafe { c::WaitForSingleObject(event.as_raw_handle(), 10_000) };
+    assert_eq!(res, c::WAIT_OBJECT_0);
+    server.accept_overlapped_result(&mut overlapped).unwrap();
+
+    let mut conn = server.accept().unwrap();
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    conn.read_exact(&mut buf).unwrap();
+    conn.write_all(&buf).unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+    drop((client, conn));
+    server.disconnect().unwrap();
+
+    // The client is already there.
+    let _client = connect_named_pipe(name);
+    let mut overlapped: c::OVERLAPPED = unsafe { crate::mem::zeroed() };
+    assert!(unsafe { server.accept_overlapped(&event, &mut overlapped) }.unwrap());
+    server.accept().unwrap();
+
+    let name = format!(r"\\.\pipe\__rust_test_accept_sync.{}", crate::process::id());
+    let server = NamedPipeServer::bind(OsStr::new(&name), 1).unwrap();
+    let err = unsafe { server.accept_overlapped(&event, &mut overlapped) }.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn set_inheritable() {
+    let (ours, theirs) = pipe_pair(true, true);
+    assert!(!ours.is_inheritable().unwrap());
+    ours.set_inheritable(true).unwrap();
+    assert!(is_inheritable(&ours));
+    assert!(ours.is_inheritable().unwrap());
+    ours.set_inheritable(false).unwrap();
+    assert!(!is_inheritable(&ours));
+    assert!(!ours.is_inheritable().unwrap());
+    assert!(!theirs.is_inheritable().unwrap());
+}
+
+#[test]
+fn set_protected() {
+    let (ours, _theirs) = pipe_pair(true, false);
+    assert!(!ours.is_protected().unwrap());
+    ours.set_protected(true).unwrap();
+    assert!(ours.is_protected().unwrap());
+    ours.set_inheritable(true).unwrap();
+    assert_eq!(
+        ours.windows_handle_flags().unwrap(),
+        c::HANDLE_FLAG_INHERIT | c::HANDLE_FLAG_PROTECT_FROM_CLOSE
+    );
+    // Unprotect before dropping, or the handle would leak.
+    ours.set_protected(false).unwrap();
+    assert!(!ours.is_protected().unwrap());
+    assert!(ours.is_inheritable().unwrap());
+}
+
+#[test]
+fn pipe_error_codes() {
+    let kind = |code| io::Error::from_raw_os_error(code as i32).kind();
+    assert_eq!(kind(c::ERROR_PIPE_NOT_CONNECTED), io::ErrorKind::NotConnected);
+    assert_eq!(kind(c::ERROR_BAD_PIPE), io::ErrorKind::InvalidInput);
+    // A write to a pipe whose reader has gone is still a broken pipe.
+    assert_eq!(kind(c::ERROR_NO_DATA), io::ErrorKind::BrokenPipe);
+
+    // Once the server has disconnected, its end is no longer connected to
+    // anything.
+    let name = format!(r"\\.\pipe\__rust_test_pipe_error_codes.{}", crate::process::id());
+    let server = NamedPipeServer::bind(OsStr::new(&name), 1).unwrap();
+    let client = connect_named_pipe(OsStr::new(&name));
+    let mut conn = server.accept().unwrap();
+    drop(client);
+    server.disconnect().unwrap();
+    let err = conn.write(b"hello").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(c::ERROR_PIPE_NOT_CONNECTED as i32));
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+}
@@ -555,7 +555,9 @@ fn to_handle(&self, stdio_id: c::DWORD, pipe: &mut Option<AnonPipe>) -> io::Resu
             Stdio::AsyncPipe(ref source) => {
                 // We need to synchronize asynchronous pipes by using a pipe relay.
                 let ours_readable = stdio_id != c::STD_INPUT_HANDLE;
-                pipe::spawn_pipe_relay(source, ours_readable, true).map(AnonPipe::into_handle)
+                // Errors in the relay just end it early, so the thread is detached.
+                let (theirs, _relay) = pipe::spawn_pipe_relay(source, ours_readable, true)?;
+                Ok(theirs.into_handle())
             }
 
             Stdio::Handle(ref handle) => handle.duplicate(0, true, c::DUPLICATE_SAME_ACCESS),
@@ -586,8 +588,8 @@ fn from(pipe: AnonPipe) -> Stdio {
         // Therefore if the pipe is asynchronous we must have a way to turn it synchronous.
         // See #95759.
         match pipe {
-            AnonPipe::Sync(handle) => Stdio::Handle(handle),
-            AnonPipe::Async(handle) => Stdio::AsyncPipe(handle),
+            AnonPipe::Sync(handle, _) => Stdio::Handle(handle),
+            AnonPipe::Async(handle, _) => Stdio::AsyncPipe(handle),
         }
     }
 }